@@ -0,0 +1,83 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{request::Parts, StatusCode},
+};
+use std::sync::OnceLock;
+use tower_cookies::{cookie::CookieJar, Cookie, Cookies, Key};
+
+use crate::config::config;
+
+static COOKIE_KEY: OnceLock<Option<Key>> = OnceLock::new();
+
+/// `auth.cookie_key`, checked by `Config::validate` at start
+fn cookie_key() -> Option<&'static Key> {
+    COOKIE_KEY.get_or_init(|| config().auth.cookie_key().ok().flatten()).as_ref()
+}
+
+/// value of cookie encrypted by KPHIS, cookie name is authenticated too,
+/// so a value cannot be moved from user cookie to role cookie
+fn decrypt(key: &Key, name: &str, value: &str) -> Option<String> {
+    let mut jar = CookieJar::new();
+    jar.add_original(Cookie::new(name.to_owned(), value.to_owned()));
+    jar.private(key).get(name).map(|cookie| cookie.value().to_owned())
+}
+
+/// current user from encrypted KPHIS cookies,
+/// request without valid cookies or without `auth.cookie_key` is default user and role
+pub struct User {
+    pub name: String,
+    pub role: String,
+}
+
+impl User {
+    pub fn is_privileged(&self) -> bool {
+        config().auth.privileged_roles.contains(&self.role)
+    }
+}
+
+impl<S> FromRequestParts<S> for User
+where
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let cookies = Cookies::from_request_parts(parts, state).await?;
+        let auth = &config().auth;
+        // plain cookies can be set by any client
+        let verified = |name: &str| {
+            let key = cookie_key()?;
+            decrypt(key, name, cookies.get(name)?.value())
+        };
+        let name = verified(&auth.user_cookie).unwrap_or_else(|| auth.default_user.clone());
+        let role = verified(&auth.role_cookie).unwrap_or_else(|| auth.default_role.clone());
+        Ok(Self { name, role })
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use tower_cookies::{cookie::CookieJar, Cookie, Key};
+
+    use super::decrypt;
+
+    fn encrypt(key: &Key, name: &str, value: &str) -> String {
+        let mut jar = CookieJar::new();
+        jar.private_mut(key).add(Cookie::new(name.to_owned(), value.to_owned()));
+        jar.get(name).unwrap().value().to_owned()
+    }
+
+    #[test]
+    pub fn test_decrypt() {
+        let key = Key::generate();
+        let role = encrypt(&key, "kphis_role", "admin");
+        assert_eq!(decrypt(&key, "kphis_role", &role).as_deref(), Some("admin"));
+        // client set plain value
+        assert_eq!(decrypt(&key, "kphis_role", "admin"), None);
+        // encrypted by other key
+        assert_eq!(decrypt(&Key::generate(), "kphis_role", &role), None);
+        // user named `admin` cannot use own user cookie as role cookie
+        let user = encrypt(&key, "kphis_user", "admin");
+        assert_eq!(decrypt(&key, "kphis_role", &user), None);
+    }
+}
@@ -0,0 +1,73 @@
+use image::ImageFormat;
+use imaging::Profile;
+use std::io;
+use tokio::sync::Semaphore;
+use tracing::warn;
+
+use crate::{
+    blob::{blob_key, blobs},
+    config::config,
+    handlers::restricted_prefix,
+    image_parser::avif_path,
+    shutdown,
+};
+
+// AVIF encoding takes a core for seconds, uploads of a whole ward must not starve requests
+static ENCODERS: Semaphore = Semaphore::const_new(2);
+
+/// write AVIF copy of webp rendition `{prefix}/{path}` at the same Ulid path, keeping its size
+pub async fn write_avif(prefix: &str, path: &str, webp: Vec<u8>) -> io::Result<()> {
+    let Some(avif_path) = avif_path(path) else {
+        return Ok(());
+    };
+    // queued encodings are dropped on shutdown, webp is served instead
+    let token = shutdown::token();
+    if token.is_cancelled() {
+        return Ok(());
+    }
+    let _permit = tokio::select! {
+        permit = ENCODERS.acquire() => permit.map_err(io::Error::other)?,
+        _ = token.cancelled() => return Ok(()),
+    };
+    let avif = tokio::task::spawn_blocking(move || {
+        image::load_from_memory(&webp)
+            .and_then(|image| Profile::IMAGE.with_format(ImageFormat::Avif).encode(&image))
+    })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)?;
+    // webp was moved to `restricted/` by redaction while encoding
+    if blobs().stat(&blob_key(prefix, path)).await?.is_none() {
+        return Ok(());
+    }
+    let key = blob_key(prefix, &avif_path);
+    blobs().put(&key, avif).await?;
+    // or while writing, after redaction looked for AVIF copy to move
+    if blobs().stat(&blob_key(prefix, path)).await?.is_none() {
+        match blobs().rename(&key, &blob_key(&restricted_prefix(prefix), &avif_path)).await {
+            // moved by redaction meanwhile
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// `write_avif` when `renditions.avif`, failure is logged only since a missing AVIF is served as webp
+pub async fn update_avif(prefix: &str, path: &str, webp: Vec<u8>) {
+    if !config().renditions.avif {
+        return;
+    }
+    if let Err(e) = write_avif(prefix, path, webp).await {
+        warn!("Cannot write AVIF of {}/{}: {}", prefix, path, e);
+    }
+}
+
+/// `update_avif` in background, upload does not wait for encoding
+pub fn spawn_avif(prefix: &str, path: &str, webp: &[u8]) {
+    if !config().renditions.avif {
+        return;
+    }
+    let (prefix, path, webp) = (prefix.to_owned(), path.to_owned(), webp.to_vec());
+    shutdown::spawn(async move { update_avif(&prefix, &path, webp).await });
+}
@@ -0,0 +1,290 @@
+use async_trait::async_trait;
+use futures_util::TryStreamExt;
+use object_store::{aws::AmazonS3Builder, path::Path as ObjectPath, ObjectStore, PutPayload};
+use sha2::{Digest, Sha256};
+use std::{
+    io,
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+use tokio::io::AsyncWriteExt;
+
+use crate::{
+    config::{config, BlobBackend, Config, S3},
+    store::{list_files, temp_path, TEMP_SUFFIX},
+};
+
+static BLOBS: OnceLock<Box<dyn BlobStore>> = OnceLock::new();
+
+/// blob store of running process, local volume when `init` was not called, ex. in tests
+pub fn blobs() -> &'static dyn BlobStore {
+    BLOBS.get_or_init(|| Box::new(LocalStore::new(config().storage.volume.clone()))).as_ref()
+}
+
+pub fn init(store: Box<dyn BlobStore>) {
+    let _ = BLOBS.set(store);
+}
+
+/// store of `storage.blob_store`, S3 client is only built here so bad settings stop startup
+pub fn from_config(config: &Config) -> Result<Box<dyn BlobStore>, String> {
+    match config.storage.blob_store {
+        BlobBackend::Local => Ok(Box::new(LocalStore::new(config.storage.volume.clone()))),
+        BlobBackend::S3 => S3Store::new(&config.s3).map(|store| Box::new(store) as Box<dyn BlobStore>),
+    }
+}
+
+/// `{prefix}/{path}`, ex. `restricted/images/01J/G0/M004KYHATX7J2W7MB28X4.webp`
+pub fn blob_key(prefix: &str, path: &str) -> String {
+    [prefix, "/", path].concat()
+}
+
+/// lowercase hex SHA-256 of file content
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlobMeta {
+    pub key: String,
+    pub size: u64,
+}
+
+/// images, thumbnails and restricted files, keys are `/` separated like `images/01J/G0/M004KYHATX7J2W7MB28X4.webp`,
+/// tables, fonts and PWA files stay in local volume
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// replace whole blob, readers never see half-written data
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()>;
+    /// `NotFound` error when missing
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    /// missing blob is not an error
+    async fn delete(&self, key: &str) -> io::Result<()>;
+    /// all blobs under directory `prefix`, ex. `images`, sorted by key
+    async fn list(&self, prefix: &str) -> io::Result<Vec<BlobMeta>>;
+    /// `None` when missing
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobMeta>>;
+
+    /// move blob, ex. to restricted storage
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let data = self.get(from).await?;
+        self.put(to, data).await?;
+        self.delete(from).await
+    }
+}
+
+/// only `01J/G0/M004KYHATX7J2W7MB28X4.webp` like keys, never outside of root
+fn check_key(key: &str) -> io::Result<()> {
+    if key.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid blob key '{}'", key)))
+    } else {
+        Ok(())
+    }
+}
+
+/// files under volume directory
+pub struct LocalStore {
+    root: PathBuf,
+}
+
+impl LocalStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        check_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // interrupted write leaves only temporary file, removed at next start
+        let tmp_path = temp_path(&path);
+        let mut f = tokio::fs::File::create(&tmp_path).await?;
+        f.write_all(&data).await?;
+        f.sync_all().await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        tokio::fs::read(self.path(key)?).await
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<BlobMeta>> {
+        let dir = self.path(prefix)?;
+        let prefix = prefix.to_owned();
+        tokio::task::spawn_blocking(move || {
+            list_files(&dir)?.into_iter()
+                .filter(|path| !path.ends_with(TEMP_SUFFIX))
+                .map(|path| {
+                    let size = std::fs::metadata(dir.join(&path))?.len();
+                    Ok(BlobMeta { key: blob_key(&prefix, &path), size })
+                })
+                .collect()
+        }).await?
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobMeta>> {
+        match tokio::fs::metadata(self.path(key)?).await {
+            Ok(metadata) if metadata.is_file() => Ok(Some(BlobMeta { key: key.to_owned(), size: metadata.len() })),
+            Ok(_) => Ok(None),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = self.path(to)?;
+        if let Some(parent) = to.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(self.path(from)?, to).await
+    }
+}
+
+/// S3 or S3-compatible service like MinIO, keys are under `s3.prefix` of bucket
+pub struct S3Store {
+    store: Arc<dyn ObjectStore>,
+    prefix: String,
+}
+
+impl S3Store {
+    /// credentials not in config are taken from `AWS_*` env
+    pub fn new(s3: &S3) -> Result<Self, String> {
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(&s3.bucket)
+            .with_region(&s3.region);
+        if let Some(endpoint) = &s3.endpoint {
+            // MinIO on LAN is often plain HTTP
+            builder = builder.with_endpoint(endpoint).with_allow_http(endpoint.starts_with("http://"));
+        }
+        if !s3.access_key_id.is_empty() {
+            builder = builder.with_access_key_id(&s3.access_key_id).with_secret_access_key(&s3.secret_access_key);
+        }
+        let store = builder.build().map_err(|e| format!("Invalid S3 config: {}", e))?;
+        Ok(Self::with_store(Arc::new(store), &s3.prefix))
+    }
+
+    /// any object store, ex. `InMemory` in place of S3 in tests
+    pub fn with_store(store: Arc<dyn ObjectStore>, prefix: &str) -> Self {
+        Self { store, prefix: prefix.trim_matches('/').to_owned() }
+    }
+
+    fn location(&self, key: &str) -> io::Result<ObjectPath> {
+        check_key(key)?;
+        let key = if self.prefix.is_empty() { key.to_owned() } else { blob_key(&self.prefix, key) };
+        ObjectPath::parse(&key).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    /// key without `s3.prefix`
+    fn key(&self, location: &ObjectPath) -> String {
+        let location = location.as_ref();
+        if self.prefix.is_empty() {
+            location.to_owned()
+        } else {
+            location.strip_prefix(&self.prefix).unwrap_or(location).trim_start_matches('/').to_owned()
+        }
+    }
+}
+
+fn s3_error(e: object_store::Error) -> io::Error {
+    match e {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, e),
+        _ => io::Error::other(e),
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        self.store.put(&self.location(key)?, PutPayload::from(data)).await.map_err(s3_error)?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let result = self.store.get(&self.location(key)?).await.map_err(s3_error)?;
+        Ok(result.bytes().await.map_err(s3_error)?.to_vec())
+    }
+
+    async fn delete(&self, key: &str) -> io::Result<()> {
+        match self.store.delete(&self.location(key)?).await {
+            Err(e) if !matches!(e, object_store::Error::NotFound { .. }) => Err(s3_error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> io::Result<Vec<BlobMeta>> {
+        let location = self.location(prefix)?;
+        let mut blobs = self.store.list(Some(&location))
+            .map_ok(|meta| BlobMeta { key: self.key(&meta.location), size: meta.size })
+            .try_collect::<Vec<BlobMeta>>().await
+            .map_err(s3_error)?;
+        blobs.sort_by(|a, b| a.key.cmp(&b.key));
+        Ok(blobs)
+    }
+
+    async fn stat(&self, key: &str) -> io::Result<Option<BlobMeta>> {
+        match self.store.head(&self.location(key)?).await {
+            Ok(meta) => Ok(Some(BlobMeta { key: key.to_owned(), size: meta.size })),
+            Err(object_store::Error::NotFound { .. }) => Ok(None),
+            Err(e) => Err(s3_error(e)),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        // copy then delete on S3
+        self.store.rename(&self.location(from)?, &self.location(to)?).await.map_err(s3_error)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use object_store::memory::InMemory;
+    use std::{io, sync::Arc};
+
+    use super::{sha256_hex, BlobMeta, BlobStore, LocalStore, S3Store};
+
+    async fn check_store(store: &dyn BlobStore) {
+        store.put("images/01J/G0/a.webp", b"image".to_vec()).await.unwrap();
+        store.put("thumbs/01J/G0/a.webp", b"thumb".to_vec()).await.unwrap();
+        assert_eq!(store.get("images/01J/G0/a.webp").await.unwrap(), b"image");
+        assert_eq!(store.get("images/01J/G0/b.webp").await.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(store.get("images/../data.json").await.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(store.list("images").await.unwrap(), vec![BlobMeta { key: String::from("images/01J/G0/a.webp"), size: 5 }]);
+        assert!(store.list("restricted/images").await.unwrap().is_empty());
+
+        store.rename("images/01J/G0/a.webp", "restricted/images/01J/G0/a.webp").await.unwrap();
+        assert_eq!(store.stat("images/01J/G0/a.webp").await.unwrap(), None);
+        assert_eq!(store.stat("restricted/images/01J/G0/a.webp").await.unwrap().map(|meta| meta.size), Some(5));
+        store.delete("restricted/images/01J/G0/a.webp").await.unwrap();
+        store.delete("restricted/images/01J/G0/a.webp").await.unwrap();
+        assert!(store.list("restricted").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    pub async fn test_blob_stores() {
+        let dir = std::env::temp_dir().join(["kphis_blob_", &std::process::id().to_string()].concat());
+        check_store(&LocalStore::new(dir.clone())).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        // stand-in of S3 bucket
+        check_store(&S3Store::with_store(Arc::new(InMemory::new()), "kphis/")).await;
+    }
+
+    #[test]
+    pub fn test_sha256_hex() {
+        assert_eq!(sha256_hex(b"abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
@@ -0,0 +1,81 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// KPHIS image server and maintenance of its image store
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// TOML config file, `kphis.toml` is optional when not given
+    #[arg(long, global = true, env = "KPHIS_CONFIG")]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// start HTTP server, the default without subcommand
+    Serve {
+        /// override port of `server.bind` in config
+        #[arg(long)]
+        port: Option<u16>,
+    },
+    /// compare tables with files in volume, exit with 1 when a referenced file is missing
+    HealthCheck,
+    /// delete images used by no gallery and files referenced by no row
+    Gc {
+        /// keep images and files younger than this, in hours
+        #[arg(long, default_value_t = 24)]
+        min_age_hours: u64,
+        /// only list what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// import legacy photos listed in CSV `file,use_at,foreign_id,title,user,timestamp`
+    Import {
+        /// photo directory, `file` column is relative to it
+        dir: PathBuf,
+        csv: PathBuf,
+    },
+    /// write tables as JSON, `-` is stdout
+    Export {
+        output: String,
+    },
+    /// update tables saved by older versions to current schema
+    Migrate,
+    /// count rows and files
+    Stats,
+    /// re-read every file of tables, verify checksum and decoding, exit with 1 when a file is corrupt
+    Scrub {
+        /// move corrupt files to `quarantine/`
+        #[arg(long)]
+        quarantine: bool,
+    },
+    /// create thumbnails again from image files, all or of a Ulid prefix range
+    RebuildThumbs {
+        /// only create missing thumbnails
+        #[arg(long)]
+        missing_only: bool,
+        /// first Ulid prefix, ex. `01J` or `01J/G0`
+        #[arg(long)]
+        from: Option<String>,
+        /// last Ulid prefix, inclusive
+        #[arg(long)]
+        to: Option<String>,
+        /// thumbnails created at the same time
+        #[arg(long, default_value_t = 4)]
+        jobs: usize,
+    },
+}
+
+impl Command {
+    /// writes tables or files, so it must hold the data file lock and cannot run beside the server
+    pub fn writes(&self) -> bool {
+        match self {
+            Self::Serve { .. } | Self::Import { .. } | Self::Migrate | Self::RebuildThumbs { .. } => true,
+            Self::Gc { dry_run, .. } => !dry_run,
+            Self::Scrub { quarantine } => *quarantine,
+            Self::HealthCheck | Self::Export { .. } | Self::Stats => false,
+        }
+    }
+}
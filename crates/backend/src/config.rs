@@ -0,0 +1,399 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::OnceLock,
+};
+use tower_cookies::Key;
+
+pub const DEFAULT_CONFIG_FILE: &str = "kphis.toml";
+// env `KPHIS_SERVER_BIND` overrides `bind` of `[server]`
+const ENV_PREFIX: &str = "KPHIS_";
+// largest image size the browser canvas and webp encoder handle well
+const MAX_RENDITION_SIZE: u32 = 8192;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// config of running process, default config when `init` was not called, ex. in tests
+pub fn config() -> &'static Config {
+    CONFIG.get_or_init(Config::default)
+}
+
+pub fn init(config: Config) {
+    let _ = CONFIG.set(config);
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: Server,
+    pub storage: Storage,
+    pub renditions: Renditions,
+    pub auth: Auth,
+    pub tls: Tls,
+    pub s3: S3,
+    pub scrub: Scrub,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Server {
+    pub bind: SocketAddr,
+    // request body limit in bytes, multipart upload of many files needs more
+    pub body_limit: usize,
+    pub timeout_secs: u64,
+    // `RUST_LOG` env wins over this
+    pub log_filter: String,
+    pub compression: bool,
+    // smaller response is not worth compressing
+    pub compression_min_size: u16,
+    // wait for in-flight requests and background jobs after Ctrl-C or SIGTERM
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self {
+            bind: SocketAddr::from(([0, 0, 0, 0], 8088)),
+            body_limit: 4096000,
+            timeout_secs: 30,
+            log_filter: String::from("hyper=warn,tower_http=debug,axum=trace,backend=debug"),
+            compression: true,
+            compression_min_size: 1024,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    // root of `images`, `thumbs`, `restricted`, `pwa` and `fonts`
+    pub volume: PathBuf,
+    // tables as one JSON file instead of a database, `{volume}/data.json` when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data_file: Option<PathBuf>,
+    // `/readyz` fails below this free space of volume disk, in megabytes
+    pub min_free_mb: u64,
+    // where images, thumbnails and restricted files are kept
+    pub blob_store: BlobBackend,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Self { volume: PathBuf::from("volume"), data_file: None, min_free_mb: 1024, blob_store: BlobBackend::Local }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobBackend {
+    // under `storage.volume`
+    Local,
+    // bucket of `[s3]`
+    S3,
+}
+
+impl Storage {
+    pub fn data_file(&self) -> PathBuf {
+        self.data_file.clone().unwrap_or_else(|| self.volume.join("data.json"))
+    }
+}
+
+/// sizes of images created by backend, ex. FHIR upload, import and rebuilt thumbnails
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Renditions {
+    // longest side, smaller image is not enlarged
+    pub image_size: u32,
+    // square
+    pub thumb_size: u32,
+    // AVIF copy of every image and thumbnail next to webp, served to browsers which accept it
+    pub avif: bool,
+}
+
+impl Default for Renditions {
+    // same sizes as frontend
+    fn default() -> Self {
+        Self { image_size: imaging::IMAGE_SIZE, thumb_size: imaging::THUMB_SIZE, avif: false }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Auth {
+    pub user_cookie: String,
+    pub role_cookie: String,
+    // base64 of 64 or more random bytes shared with KPHIS, which encrypts user and role cookies with it
+    // (AES-256-GCM of `cookie` crate private jar), cookies are ignored when empty
+    pub cookie_key: String,
+    // request without valid cookies
+    pub default_user: String,
+    pub default_role: String,
+    // roles which can see image history, restricted files and revert image
+    pub privileged_roles: Vec<String>,
+}
+
+impl Auth {
+    /// key of `cookie_key`, `None` when it is not set
+    pub fn cookie_key(&self) -> Result<Option<Key>, String> {
+        if self.cookie_key.is_empty() {
+            return Ok(None);
+        }
+        let bytes = STANDARD.decode(&self.cookie_key)
+            .map_err(|e| format!("auth.cookie_key must be base64: {}", e))?;
+        Key::try_from(bytes.as_slice())
+            .map(Some)
+            .map_err(|e| format!("auth.cookie_key: {}", e))
+    }
+}
+
+impl Default for Auth {
+    fn default() -> Self {
+        Self {
+            user_cookie: String::from("kphis_user"),
+            role_cookie: String::from("kphis_role"),
+            cookie_key: String::new(),
+            default_user: String::from("user"),
+            default_role: String::from("user"),
+            privileged_roles: vec![String::from("admin"), String::from("doctor")],
+        }
+    }
+}
+
+/// HTTPS is served when both `cert` and `key` PEM files are set
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Tls {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<PathBuf>,
+    // plain HTTP listener which redirects to HTTPS, ex. `0.0.0.0:80`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub redirect_bind: Option<SocketAddr>,
+    // interval of checking certificate files for change
+    pub reload_secs: u64,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self { cert: None, key: None, redirect_bind: None, reload_secs: 30 }
+    }
+}
+
+/// S3 or S3-compatible service like MinIO, used when `storage.blob_store = "s3"`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3 {
+    // ex. `http://minio:9000`, AWS when not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub bucket: String,
+    // key prefix in bucket, ex. `kphis`
+    pub prefix: String,
+    // `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY` env when empty
+    pub access_key_id: String,
+    pub secret_access_key: String,
+}
+
+impl Default for S3 {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            region: String::from("us-east-1"),
+            bucket: String::new(),
+            prefix: String::new(),
+            access_key_id: String::new(),
+            secret_access_key: String::new(),
+        }
+    }
+}
+
+/// background re-read of stored files, verifies checksum and decoding
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Scrub {
+    // files verified every `interval_secs`, 0 disables scrubber
+    pub files_per_tick: usize,
+    pub interval_secs: u64,
+    // move corrupt files to `quarantine/`, otherwise only report them
+    pub quarantine: bool,
+}
+
+impl Default for Scrub {
+    fn default() -> Self {
+        Self { files_per_tick: 20, interval_secs: 60, quarantine: true }
+    }
+}
+
+impl Tls {
+    /// (cert, key) when HTTPS is enabled
+    pub fn pem_files(&self) -> Option<(PathBuf, PathBuf)> {
+        self.cert.clone().zip(self.key.clone())
+    }
+}
+
+impl Config {
+    /// read TOML file then apply `KPHIS_<SECTION>_<KEY>` env overrides, missing file is default config
+    /// unless `required`, env value of non-string key is parsed as TOML value, ex. `["admin"]`, others are taken as string
+    pub fn load(path: &Path, required: bool) -> Result<Self, String> {
+        let mut table = match std::fs::read_to_string(path) {
+            Ok(text) => text.parse::<toml::Table>().map_err(|e| format!("Invalid config {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => toml::Table::new(),
+            Err(e) => return Err(format!("Cannot read config {}: {}", path.display(), e)),
+        };
+        apply_env(&mut table, std::env::vars())?;
+        let config = toml::Value::Table(table).try_into::<Self>().map_err(|e| format!("Invalid config: {}", e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// all problems at once, so operator can fix config in one go
+    pub fn validate(&self) -> Result<(), String> {
+        let mut errors = Vec::new();
+        if self.server.body_limit == 0 {
+            errors.push(String::from("server.body_limit must be more than 0"));
+        }
+        if self.server.timeout_secs == 0 {
+            errors.push(String::from("server.timeout_secs must be more than 0"));
+        }
+        if self.storage.volume.as_os_str().is_empty() {
+            errors.push(String::from("storage.volume must not be empty"));
+        }
+        let renditions = &self.renditions;
+        if renditions.image_size == 0 || renditions.image_size > MAX_RENDITION_SIZE {
+            errors.push(format!("renditions.image_size must be 1 to {}", MAX_RENDITION_SIZE));
+        }
+        if renditions.thumb_size == 0 || renditions.thumb_size > renditions.image_size {
+            errors.push(String::from("renditions.thumb_size must be 1 to renditions.image_size"));
+        }
+        for (name, value) in [("auth.user_cookie", &self.auth.user_cookie), ("auth.role_cookie", &self.auth.role_cookie), ("auth.default_user", &self.auth.default_user), ("auth.default_role", &self.auth.default_role)] {
+            if value.is_empty() {
+                errors.push(format!("{} must not be empty", name));
+            }
+        }
+        if let Err(e) = self.auth.cookie_key() {
+            errors.push(e);
+        }
+        let tls = &self.tls;
+        match (&tls.cert, &tls.key) {
+            (Some(cert), Some(key)) => {
+                for (name, path) in [("tls.cert", cert), ("tls.key", key)] {
+                    if !path.is_file() {
+                        errors.push(format!("{} file {} does not exist", name, path.display()));
+                    }
+                }
+            }
+            (None, None) => {
+                if tls.redirect_bind.is_some() {
+                    errors.push(String::from("tls.redirect_bind needs tls.cert and tls.key"));
+                }
+            }
+            _ => errors.push(String::from("tls.cert and tls.key must be set together")),
+        }
+        if tls.redirect_bind.is_some_and(|redirect| redirect.port() == self.server.bind.port()) {
+            errors.push(String::from("tls.redirect_bind must use other port than server.bind"));
+        }
+        if tls.reload_secs == 0 {
+            errors.push(String::from("tls.reload_secs must be more than 0"));
+        }
+        if self.scrub.interval_secs == 0 {
+            errors.push(String::from("scrub.interval_secs must be more than 0"));
+        }
+        if self.storage.blob_store == BlobBackend::S3 {
+            if self.s3.bucket.is_empty() {
+                errors.push(String::from("s3.bucket must be set for storage.blob_store = \"s3\""));
+            }
+            if self.s3.access_key_id.is_empty() != self.s3.secret_access_key.is_empty() {
+                errors.push(String::from("s3.access_key_id and s3.secret_access_key must be set together"));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(["Invalid config:", &errors.join(", ")].join(" "))
+        }
+    }
+}
+
+/// override keys of known sections, section and key are lowercase of env name parts
+fn apply_env(table: &mut toml::Table, vars: impl Iterator<Item = (String, String)>) -> Result<(), String> {
+    let defaults = toml::Table::try_from(Config::default()).map_err(|e| e.to_string())?;
+    for (name, value) in vars {
+        let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let rest = rest.to_lowercase();
+        let Some((section, key)) = defaults.keys()
+            .find_map(|section| rest.strip_prefix(section.as_str())?.strip_prefix('_').map(|key| (section.clone(), key.to_owned())))
+        else {
+            continue;
+        };
+        // strings are taken as is, so `12345` or `true` stays a cookie key or bucket name,
+        // optional keys without default are paths, addresses or URLs
+        let typed = defaults[&section].get(&key).is_some_and(|default| !default.is_str());
+        let value = if typed {
+            ["v = ", &value].concat()
+                .parse::<toml::Table>()
+                .ok()
+                .and_then(|mut table| table.remove("v"))
+                .unwrap_or(toml::Value::String(value))
+        } else {
+            toml::Value::String(value)
+        };
+        let section = table.entry(section)
+            .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| format!("Invalid config: {} must be a table", name))?;
+        section.insert(key, value);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use std::net::SocketAddr;
+
+    use super::{apply_env, Config};
+
+    #[test]
+    pub fn test_config_env() {
+        let mut table = "[server]\nbind = \"127.0.0.1:80\"\ntimeout_secs = 10".parse::<toml::Table>().unwrap();
+        let vars = [
+            ("KPHIS_SERVER_TIMEOUT_SECS", "60"),
+            ("KPHIS_STORAGE_VOLUME", "/data/kphis"),
+            ("KPHIS_AUTH_PRIVILEGED_ROLES", "[\"admin\"]"),
+            ("KPHIS_AUTH_COOKIE_KEY", "12345"),
+            ("KPHIS_S3_BUCKET", "true"),
+            ("KPHIS_S3_ENDPOINT", "1e3"),
+            ("KPHIS_UNKNOWN_KEY", "1"),
+            ("PATH", "/bin"),
+        ].map(|(k, v)| (k.to_owned(), v.to_owned()));
+        apply_env(&mut table, vars.into_iter()).unwrap();
+        let config = toml::Value::Table(table).try_into::<Config>().unwrap();
+        assert_eq!(config.server.bind, SocketAddr::from(([127, 0, 0, 1], 80)));
+        assert_eq!(config.server.timeout_secs, 60);
+        assert_eq!(config.storage.data_file().to_str(), Some("/data/kphis/data.json"));
+        assert_eq!(config.auth.privileged_roles, vec![String::from("admin")]);
+        // numeric-looking strings
+        assert_eq!(config.auth.cookie_key, "12345");
+        assert_eq!(config.s3.bucket, "true");
+        assert_eq!(config.s3.endpoint.as_deref(), Some("1e3"));
+
+        let mut config = Config::default();
+        assert!(config.validate().is_ok());
+        config.renditions.thumb_size = 2048;
+        config.server.timeout_secs = 0;
+        assert_eq!(config.validate().unwrap_err().matches(", ").count(), 1);
+
+        let mut config = Config::default();
+        config.auth.cookie_key = STANDARD.encode([7; 32]);
+        assert!(config.validate().unwrap_err().contains("auth.cookie_key"));
+        config.auth.cookie_key = STANDARD.encode([7; 64]);
+        assert!(config.auth.cookie_key().unwrap().is_some());
+    }
+}
@@ -0,0 +1,202 @@
+use image::{imageops::FilterType, DynamicImage, RgbImage};
+use std::borrow::Cow;
+use time::{macros::format_description, OffsetDateTime};
+use ulid::Ulid;
+
+use model::ImageData;
+
+const SECONDARY_CAPTURE_IMAGE_STORAGE: &str = "1.2.840.10008.5.1.4.1.1.7";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+// 2.25 root with random UUID as integer, see DICOM PS3.5 B.2
+const IMPLEMENTATION_CLASS_UID: &str = "2.25.329800735698586629295641978511506172918";
+
+// flip unused top bits of Ulid timestamp, so study and series UIDs never collide with instance UIDs
+const STUDY_UID_BIT: u128 = 1 << 127;
+const SERIES_UID_BIT: u128 = 1 << 126;
+// rows and columns are US
+const MAX_DIMENSION: u32 = u16::MAX as u32;
+
+fn uid(value: u128) -> String {
+    ["2.25.", &value.to_string()].concat()
+}
+
+/// (DA, TM) of Ulid time
+fn date_time(ulid: Ulid) -> (String, String) {
+    let time = OffsetDateTime::from(ulid.datetime());
+    (
+        time.format(format_description!("[year][month][day]")).unwrap_or_default(),
+        time.format(format_description!("[hour][minute][second]")).unwrap_or_default(),
+    )
+}
+
+/// downscale image whose side does not fit rows or columns
+fn fit_dimensions(rgb: &RgbImage) -> Cow<'_, RgbImage> {
+    if rgb.width() <= MAX_DIMENSION && rgb.height() <= MAX_DIMENSION {
+        return Cow::Borrowed(rgb);
+    }
+    let resized = DynamicImage::ImageRgb8(rgb.clone()).resize(MAX_DIMENSION, MAX_DIMENSION, FilterType::Triangle);
+    Cow::Owned(resized.to_rgb8())
+}
+
+/// study of a gallery, all images share study from earliest image
+#[derive(Clone)]
+pub struct DicomStudy {
+    pub patient_id: String,
+    pub study_id: String,
+    // earliest image
+    pub ulid: Ulid,
+}
+
+impl DicomStudy {
+    pub fn study_uid(&self) -> String {
+        uid(u128::from(self.ulid) ^ STUDY_UID_BIT)
+    }
+}
+
+/// Secondary Capture Image Storage Part 10 file, one series per image so PACS shows title as series description,
+/// study date is of earliest image and content date is of this image
+pub fn secondary_capture(study: &DicomStudy, image: &ImageData, ulid: Ulid, rgb: &RgbImage) -> Vec<u8> {
+    let (date, time) = date_time(study.ulid);
+    let (content_date, content_time) = date_time(ulid);
+    let rgb = fit_dimensions(rgb);
+    let instance_uid = uid(u128::from(ulid));
+    let series_uid = uid(u128::from(ulid) ^ SERIES_UID_BIT);
+    let metadata = image.metadata.clone().unwrap_or_default();
+    let title = image.title.clone().unwrap_or_default();
+
+    let mut dataset = DicomWriter::default();
+    dataset.string(0x0008, 0x0005, b"CS", "ISO_IR 192");
+    dataset.string(0x0008, 0x0008, b"CS", "DERIVED\\SECONDARY");
+    dataset.string(0x0008, 0x0016, b"UI", SECONDARY_CAPTURE_IMAGE_STORAGE);
+    dataset.string(0x0008, 0x0018, b"UI", &instance_uid);
+    dataset.string(0x0008, 0x0020, b"DA", &date);
+    dataset.string(0x0008, 0x0023, b"DA", &content_date);
+    dataset.string(0x0008, 0x0030, b"TM", &time);
+    dataset.string(0x0008, 0x0033, b"TM", &content_time);
+    dataset.string(0x0008, 0x0050, b"SH", "");
+    dataset.string(0x0008, 0x0060, b"CS", metadata.modality.as_deref().unwrap_or("XC"));
+    dataset.string(0x0008, 0x0064, b"CS", "DI");
+    dataset.string(0x0008, 0x0090, b"PN", "");
+    dataset.string(0x0008, 0x0201, b"SH", "+0000");
+    dataset.string(0x0008, 0x103E, b"LO", &title);
+    dataset.string(0x0010, 0x0010, b"PN", &metadata.patient_name.unwrap_or_default().replace(' ', "^"));
+    dataset.string(0x0010, 0x0020, b"LO", &study.patient_id);
+    dataset.string(0x0010, 0x0030, b"DA", "");
+    dataset.string(0x0010, 0x0040, b"CS", "");
+    dataset.string(0x0020, 0x000D, b"UI", &study.study_uid());
+    dataset.string(0x0020, 0x000E, b"UI", &series_uid);
+    dataset.string(0x0020, 0x0010, b"SH", &study.study_id);
+    dataset.string(0x0020, 0x0011, b"IS", &(image.position + 1).to_string());
+    dataset.string(0x0020, 0x0013, b"IS", "1");
+    dataset.string(0x0020, 0x0020, b"CS", "");
+    dataset.string(0x0020, 0x4000, b"LT", &title);
+    dataset.u16(0x0028, 0x0002, 3);
+    dataset.string(0x0028, 0x0004, b"CS", "RGB");
+    dataset.u16(0x0028, 0x0006, 0);
+    dataset.u16(0x0028, 0x0010, rgb.height() as u16);
+    dataset.u16(0x0028, 0x0011, rgb.width() as u16);
+    dataset.u16(0x0028, 0x0100, 8);
+    dataset.u16(0x0028, 0x0101, 8);
+    dataset.u16(0x0028, 0x0102, 7);
+    dataset.u16(0x0028, 0x0103, 0);
+    dataset.element(0x7FE0, 0x0010, b"OB", rgb.as_raw());
+
+    let mut file_meta = DicomWriter::default();
+    file_meta.element(0x0002, 0x0001, b"OB", &[0, 1]);
+    file_meta.string(0x0002, 0x0002, b"UI", SECONDARY_CAPTURE_IMAGE_STORAGE);
+    file_meta.string(0x0002, 0x0003, b"UI", &instance_uid);
+    file_meta.string(0x0002, 0x0010, b"UI", EXPLICIT_VR_LITTLE_ENDIAN);
+    file_meta.string(0x0002, 0x0012, b"UI", IMPLEMENTATION_CLASS_UID);
+    file_meta.string(0x0002, 0x0013, b"SH", &["KPHIS-", env!("CARGO_PKG_VERSION")].concat());
+
+    let mut group_length = DicomWriter::default();
+    group_length.element(0x0002, 0x0000, b"UL", &(file_meta.bytes.len() as u32).to_le_bytes());
+
+    [vec![0; 128], b"DICM".to_vec(), group_length.bytes, file_meta.bytes, dataset.bytes].concat()
+}
+
+/// explicit VR little endian elements, caller must write in tag order
+#[derive(Default)]
+struct DicomWriter {
+    bytes: Vec<u8>,
+}
+
+impl DicomWriter {
+    fn element(&mut self, group: u16, element: u16, vr: &[u8; 2], value: &[u8]) {
+        // value length must be even
+        let padding = value.len() % 2;
+        let length = value.len() + padding;
+        self.bytes.extend_from_slice(&group.to_le_bytes());
+        self.bytes.extend_from_slice(&element.to_le_bytes());
+        self.bytes.extend_from_slice(vr);
+        if [b"OB", b"OW", b"SQ", b"UN", b"UT"].contains(&vr) {
+            self.bytes.extend_from_slice(&[0, 0]);
+            self.bytes.extend_from_slice(&(length as u32).to_le_bytes());
+        } else {
+            self.bytes.extend_from_slice(&(length as u16).to_le_bytes());
+        }
+        self.bytes.extend_from_slice(value);
+        if padding == 1 {
+            // UI and binary values pad with null, text values pad with space
+            self.bytes.push(if [b"UI", b"OB"].contains(&vr) { 0 } else { b' ' });
+        }
+    }
+
+    fn string(&mut self, group: u16, element: u16, vr: &[u8; 2], value: &str) {
+        self.element(group, element, vr, value.as_bytes());
+    }
+
+    fn u16(&mut self, group: u16, element: u16, value: u16) {
+        self.element(group, element, b"US", &value.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use image::{GenericImageView, RgbImage};
+    use model::ImageData;
+    use ulid::Ulid;
+
+    use crate::image_parser::path_to_ulid;
+
+    use super::{date_time, secondary_capture, DicomStudy};
+
+    #[test]
+    pub fn test_secondary_capture() {
+        let path = "01J/G0/M004KYHATX7J2W7MB28X4.webp";
+        let ulid = path_to_ulid(path).unwrap();
+        assert_eq!(ulid.to_string(), "01JG0M004KYHATX7J2W7MB28X4");
+        let image = ImageData {
+            image_id: 1,
+            foreign_id: 1,
+            path: path.to_owned(),
+            title: Some(String::from("แผลกดทับ")),
+            user: String::from("user"),
+            position: 0,
+            annotation: None,
+            metadata: None,
+            sha256: None,
+            thumb_sha256: None,
+            dhash: None,
+        };
+        let study = DicomStudy { patient_id: String::from("1"), study_id: String::from("1"), ulid };
+        let data = secondary_capture(&study, &image, ulid, &RgbImage::new(3, 2));
+        assert_eq!(&data[128..132], b"DICM");
+        // pixel data is the last element, 3x2 RGB is 18 bytes
+        assert_eq!(&data[data.len() - 30..data.len() - 26], &[0xE0, 0x7F, 0x10, 0x00]);
+        assert_ne!(study.study_uid(), ["2.25.", &u128::from(ulid).to_string()].concat());
+        // 2024-12-31 17:00:00 UTC of image, study from 2024-01-01
+        let earliest = Ulid::from_parts(1704067200000, 0);
+        let study = DicomStudy { ulid: earliest, ..study };
+        let data = secondary_capture(&study, &image, ulid, &RgbImage::new(3, 2));
+        let (_, time) = date_time(ulid);
+        let content = [&[0x08, 0x00, 0x33, 0x00], b"TM".as_slice(), &(time.len() as u16).to_le_bytes(), time.as_bytes()].concat();
+        assert!(data.windows(content.len()).any(|window| window == content));
+        assert!(data.windows(8).any(|window| window == b"20240101"));
+
+        // rows and columns fit US
+        let data = secondary_capture(&study, &image, ulid, &RgbImage::new(70000, 2));
+        let (decoded, _) = imaging::dicom::dicom_decode(&data).unwrap();
+        assert_eq!(decoded.dimensions(), (65535, 2));
+    }
+}
@@ -0,0 +1,352 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::Response,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde_derive::{Deserialize, Serialize};
+use std::io::Cursor;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::info;
+
+use model::{ImageData, VersionKind};
+
+use crate::{
+    AppState, add_count,
+    auth::User,
+    blob::{blob_key, blobs, sha256_hex},
+    handlers::{dhash_of, next_position, push_version, reserve_upload, write_blob, Upload, PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB},
+    image_parser::{image_bytes_parser, new_ulid_to_path, path_to_ulid},
+};
+
+const FHIR_JSON: &str = "application/fhir+json";
+const MEDIA_TYPE_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/media-type";
+// usage record to FHIR reference, first table is per patient and second table is per encounter
+const SUBJECT_TYPE: &str = "Patient";
+const ENCOUNTER_TYPE: &str = "Encounter";
+
+/// FHIR R4 Media resource, only elements used by KPHIS image
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Media {
+    pub resource_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    pub status: String,
+    #[serde(default, skip_serializing_if = "Option::is_none", rename = "type")]
+    pub media_type: Option<CodeableConcept>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<Reference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encounter: Option<Reference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_date_time: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator: Option<Reference>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    pub content: Attachment,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    // base64
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub creation: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Reference {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display: Option<String>,
+}
+
+impl Reference {
+    fn to(resource_type: &str, id: u32) -> Self {
+        Self { reference: Some([resource_type, "/", &id.to_string()].concat()), display: None }
+    }
+
+    /// id of `Patient/123` like reference
+    fn id_of(&self, resource_type: &str) -> Option<u32> {
+        parse_reference(self.reference.as_deref()?, resource_type)
+    }
+}
+
+/// `Patient/123` or `123` to 123
+fn parse_reference(reference: &str, resource_type: &str) -> Option<u32> {
+    let id = match reference.split_once('/') {
+        Some((prefix, id)) if prefix == resource_type => id,
+        Some(_) => return None,
+        None => reference,
+    };
+    id.parse().ok()
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CodeableConcept {
+    pub coding: Vec<Coding>,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Coding {
+    pub system: String,
+    pub code: String,
+    pub display: String,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    resource_type: &'static str,
+    #[serde(rename = "type")]
+    bundle_type: &'static str,
+    total: usize,
+    entry: Vec<BundleEntry>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleEntry {
+    full_url: String,
+    resource: Media,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaSearch {
+    subject: Option<String>,
+    // alias of subject
+    patient: Option<String>,
+    encounter: Option<String>,
+}
+
+fn fhir_response<T: serde::Serialize>(status: StatusCode, body: &T) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, FHIR_JSON)
+        .body(Body::from(serde_json::to_vec(body).unwrap_or_default()))
+        .unwrap()
+}
+
+/// FHIR error response
+fn operation_outcome(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    fhir_response(status, &serde_json::json!({
+        "resourceType": "OperationOutcome",
+        "issue": [{ "severity": "error", "code": code, "diagnostics": message }],
+    }))
+}
+
+/// `http://host/` from request, scheme from reverse proxy if any
+fn base_url(headers: &HeaderMap) -> String {
+    let host = headers.get(header::HOST).and_then(|v| v.to_str().ok()).unwrap_or("localhost");
+    let scheme = headers.get("x-forwarded-proto").and_then(|v| v.to_str().ok()).unwrap_or("http");
+    [scheme, "://", host, "/"].concat()
+}
+
+/// (width, height) from header of original rendition
+async fn image_dimensions(image: &ImageData) -> Option<(u32, u32)> {
+    let data = blobs().get(&blob_key(PATH_PREFIX_IMAGE, &image.path)).await.ok()?;
+    image::ImageReader::new(Cursor::new(data)).with_guessed_format().ok()?.into_dimensions().ok()
+}
+
+/// Media of image, subject and encounter from usage records
+async fn to_media(app: &AppState, image: &ImageData, base: &str) -> Media {
+    let subject = app.first_table.lock().unwrap().iter()
+        .find(|row| row.image_id == image.image_id)
+        .map(|row| Reference::to(SUBJECT_TYPE, row.foreign_id));
+    let encounter = app.second_table.lock().unwrap().iter()
+        .find(|row| row.image_id == image.image_id)
+        .map(|row| Reference::to(ENCOUNTER_TYPE, row.foreign_id));
+    let created = path_to_ulid(&image.path)
+        .and_then(|ulid| OffsetDateTime::from(ulid.datetime()).format(&Rfc3339).ok());
+    let dimensions = image_dimensions(image).await;
+    Media {
+        resource_type: String::from("Media"),
+        id: Some(image.image_id.to_string()),
+        status: String::from("completed"),
+        media_type: Some(CodeableConcept {
+            coding: vec![Coding {
+                system: String::from(MEDIA_TYPE_SYSTEM),
+                code: String::from("image"),
+                display: String::from("Image"),
+            }],
+        }),
+        subject,
+        encounter,
+        created_date_time: created.clone(),
+        operator: Some(Reference { reference: None, display: Some(image.user.clone()) }),
+        height: dimensions.map(|(_, h)| h),
+        width: dimensions.map(|(w, _)| w),
+        content: Attachment {
+            content_type: Some(String::from("image/webp")),
+            data: None,
+            url: Some([base, PATH_PREFIX_IMAGE, "/", &image.path].concat()),
+            title: image.title.clone(),
+            creation: created,
+        },
+    }
+}
+
+pub async fn get_media(
+    Path(image_id): Path<u32>,
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let image = app.images.lock().unwrap().iter().find(|image| image.image_id == image_id).cloned();
+    match image {
+        Some(image) => fhir_response(StatusCode::OK, &to_media(&app, &image, &base_url(&headers)).await),
+        None => operation_outcome(StatusCode::NOT_FOUND, "not-found", &format!("Media/{} not found", image_id)),
+    }
+}
+
+/// search by `subject`(`patient`) or `encounter`, without parameter returns all
+pub async fn search_media(
+    Query(search): Query<MediaSearch>,
+    State(app): State<AppState>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let subject = search.subject.or(search.patient);
+    let mut image_ids = None;
+    if let Some(subject) = subject {
+        let Some(id) = parse_reference(&subject, SUBJECT_TYPE) else {
+            return operation_outcome(StatusCode::BAD_REQUEST, "invalid", &format!("Invalid subject '{}'", subject));
+        };
+        let ids = usage_image_ids(&app.first_table.lock().unwrap(), id);
+        image_ids = Some(ids);
+    }
+    if let Some(encounter) = search.encounter {
+        let Some(id) = parse_reference(&encounter, ENCOUNTER_TYPE) else {
+            return operation_outcome(StatusCode::BAD_REQUEST, "invalid", &format!("Invalid encounter '{}'", encounter));
+        };
+        let ids = usage_image_ids(&app.second_table.lock().unwrap(), id);
+        // both parameters are AND
+        image_ids = Some(match image_ids {
+            Some(prev) => prev.into_iter().filter(|image_id| ids.contains(image_id)).collect(),
+            None => ids,
+        });
+    }
+    let images = app.images.lock().unwrap().iter()
+        .filter(|image| image_ids.as_ref().is_none_or(|ids| ids.contains(&image.image_id)))
+        .cloned()
+        .collect::<Vec<ImageData>>();
+    let base = base_url(&headers);
+    let mut entry = Vec::new();
+    for image in &images {
+        entry.push(BundleEntry {
+            full_url: [base.as_str(), "api/fhir/Media/", &image.image_id.to_string()].concat(),
+            resource: to_media(&app, image, &base).await,
+        });
+    }
+    fhir_response(StatusCode::OK, &Bundle {
+        resource_type: "Bundle",
+        bundle_type: "searchset",
+        total: entry.len(),
+        entry,
+    })
+}
+
+fn usage_image_ids(rows: &[ImageData], foreign_id: u32) -> Vec<u32> {
+    rows.iter().filter(|row| row.foreign_id == foreign_id).map(|row| row.image_id).collect()
+}
+
+/// create image from Media with base64 attachment, subject and encounter create usage records
+pub async fn post_media(
+    State(app): State<AppState>,
+    user: User,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response<Body> {
+    // accept both application/json and application/fhir+json
+    let media = match serde_json::from_slice::<Media>(&body) {
+        Ok(media) => media,
+        Err(e) => return operation_outcome(StatusCode::BAD_REQUEST, "structure", &format!("Invalid Media: {}", e)),
+    };
+    if media.resource_type != "Media" {
+        return operation_outcome(StatusCode::BAD_REQUEST, "invalid", "resourceType must be Media");
+    }
+    let Some(data) = media.content.data.as_deref() else {
+        return operation_outcome(StatusCode::BAD_REQUEST, "required", "content.data is required");
+    };
+    let raw_data = match STANDARD.decode(data) {
+        Ok(raw_data) => raw_data,
+        Err(e) => return operation_outcome(StatusCode::BAD_REQUEST, "invalid", &format!("Invalid base64 data: {}", e)),
+    };
+    let (image, thumb) = match tokio::task::spawn_blocking(move || image_bytes_parser(&raw_data)).await {
+        Ok(Ok(renditions)) => renditions,
+        Ok(Err(e)) => return operation_outcome(StatusCode::UNPROCESSABLE_ENTITY, "invalid", &format!("Cannot read image: {}", e)),
+        Err(e) => return operation_outcome(StatusCode::INTERNAL_SERVER_ERROR, "exception", &e.to_string()),
+    };
+    let sha256 = sha256_hex(&image);
+    let image_data = match reserve_upload(&app, &sha256).await {
+        // identical image, only usage records are added
+        Upload::Existing(existing) => *existing,
+        Upload::New(_reservation) => {
+            let path = new_ulid_to_path();
+            for (prefix, data) in [(PATH_PREFIX_IMAGE, &image), (PATH_PREFIX_THUMB, &thumb)] {
+                if write_blob(prefix, &path, data).await.is_err() {
+                    return operation_outcome(StatusCode::INTERNAL_SERVER_ERROR, "exception", "Cannot write image file");
+                }
+            }
+            let dhash = dhash_of(image).await;
+            let image_data = ImageData {
+                image_id: add_count(),
+                foreign_id: 0,
+                path,
+                title: media.content.title.clone(),
+                user: user.name.clone(),
+                position: 0,
+                annotation: None,
+                metadata: None,
+                sha256: Some(sha256),
+                thumb_sha256: Some(sha256_hex(&thumb)),
+                dhash,
+            };
+            app.images.lock().unwrap().push(image_data.clone());
+            let mut versions = app.image_versions.lock().unwrap();
+            push_version(&mut versions, &image_data, VersionKind::Upload, &user.name);
+            image_data
+        }
+    };
+    if let Some(id) = media.subject.as_ref().and_then(|subject| subject.id_of(SUBJECT_TYPE)) {
+        let mut lock = app.first_table.lock().unwrap();
+        let position = next_position(&lock, id);
+        lock.push(ImageData { foreign_id: id, position, ..image_data.clone() });
+    }
+    if let Some(id) = media.encounter.as_ref().and_then(|encounter| encounter.id_of(ENCOUNTER_TYPE)) {
+        let mut lock = app.second_table.lock().unwrap();
+        let position = next_position(&lock, id);
+        lock.push(ImageData { foreign_id: id, position, ..image_data.clone() });
+    }
+    info!("Media {} created from FHIR by {}", image_data.image_id, &user.name);
+    let mut response = fhir_response(StatusCode::CREATED, &to_media(&app, &image_data, &base_url(&headers)).await);
+    if let Ok(location) = ["api/fhir/Media/", &image_data.image_id.to_string()].concat().parse() {
+        response.headers_mut().insert(header::LOCATION, location);
+    }
+    response
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::parse_reference;
+
+    #[test]
+    pub fn test_parse_reference() {
+        assert_eq!(parse_reference("Patient/12", "Patient"), Some(12));
+        assert_eq!(parse_reference("12", "Patient"), Some(12));
+        assert_eq!(parse_reference("Encounter/12", "Patient"), None);
+        assert_eq!(parse_reference("Patient/abc", "Patient"), None);
+    }
+}
@@ -1,18 +1,50 @@
+use async_zip::{base::write::ZipFileWriter, Compression, ZipEntryBuilder};
 use axum::{
     body::Body,
-    extract::{Multipart, Path, State},
-    http::StatusCode,
+    extract::{Form, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{Html, IntoResponse, Response}, Json,
 };
-use tokio::{fs::File, io::AsyncWriteExt};
-use tracing::info;
+use image::codecs::jpeg::JpegEncoder;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{LazyLock, Mutex},
+};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+use tokio::{io::DuplexStream, sync::watch};
+use tokio_util::io::ReaderStream;
+use tracing::{error, info};
+use ulid::Ulid;
+
+use model::{ImageAnnotation, ImageData, ImageMetadata, ImageVersion, NearDuplicate, VersionKind};
 
-use model::ImageData;
+use crate::{
+    AppState, add_count,
+    auth::User,
+    avif,
+    blob::{blob_key, blobs, sha256_hex},
+    config::config,
+    dicom, image_parser, metrics,
+    report::{self, ReportGrid, ReportImage},
+    shutdown,
+    store::volume_path,
+};
 
-use crate::{AppState, add_count};
+pub const PATH_PREFIX_IMAGE: &str = "images";
+pub const PATH_PREFIX_THUMB: &str = "thumbs";
+pub const PATH_PREFIX_RESTRICTED: &str = "restricted";
+pub const PATH_PREFIX_QUARANTINE: &str = "quarantine";
+const FIELD_METADATA: &str = "metadata";
+const PATH_PREFIX_FONT: &str = "fonts";
+const REPORT_FONT: &str = "report.ttf";
+const ZIP_BUFFER_SIZE: usize = 64 * 1024;
+// differing bits of 64-bit perceptual hash, re-shot photo of the same wound is usually below this
+const NEAR_DUPLICATE_DISTANCE: u32 = 10;
 
-const PATH_PREFIX_IMAGE: &str = "images";
-const PATH_PREFIX_THUMB: &str = "thumbs";
+// uploads being written, by sha256
+static PENDING_UPLOADS: LazyLock<Mutex<HashMap<String, watch::Receiver<()>>>> = LazyLock::new(Default::default);
 
 pub async fn greet_handler() -> Html<&'static str> {
     Html("<h1>Nice to meet you!</h1>")
@@ -20,12 +52,35 @@ pub async fn greet_handler() -> Html<&'static str> {
 
 pub async fn post_image(
     State(app): State<AppState>,
+    user: User,
     mut multipart: Multipart,
 ) -> Result<Json<Vec<ImageData>>, Response<Body>> {
     let mut filenames = Vec::new();
+    // DICOM metadata by path, sent before thumbs field of the same file
+    let mut metadatas: HashMap<String, ImageMetadata> = HashMap::new();
+    // (sha256, dhash) of images field by path, and existing image of identical upload
+    let mut hashes: HashMap<String, (String, Option<String>)> = HashMap::new();
+    let mut duplicates: HashMap<String, ImageData> = HashMap::new();
+    // (path, path of identical file earlier in this batch whose row was not pushed yet)
+    let mut batch_duplicates: Vec<(String, String)> = Vec::new();
+    let mut reservations: HashMap<String, UploadReservation> = HashMap::new();
     while let Ok(Some(field)) = multipart.next_field().await {
         let field_name = field.name().unwrap_or("unnamed").to_owned();
-        if [PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB].contains(&field_name.as_str()) {
+        if field_name.as_str() == FIELD_METADATA {
+            let field_filename = field.file_name().unwrap_or("no_filename").to_owned();
+            let data = field.bytes().await.unwrap_or_default();
+            match serde_json::from_slice::<ImageMetadata>(&data) {
+                Ok(metadata) => {
+                    metadatas.insert(field_filename, metadata);
+                }
+                Err(e) => {
+                    return Err(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Body::from(format!("Invalid metadata of '{}': {}", field_filename, e)))
+                        .unwrap());
+                }
+            }
+        } else if [PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB].contains(&field_name.as_str()) {
             let field_filename = field.file_name().unwrap_or("no_filename").to_owned();
             let field_content_type = field.content_type().unwrap_or("no_content_type").to_owned();
             let data = match field.bytes().await {
@@ -37,33 +92,475 @@ pub async fn post_image(
                         .unwrap());
                 }
             };
-            let file_path = ["volume", &field_name, &field_filename].join("/");
-            let current = std::env::current_dir().unwrap();
-            let path = current.join(file_path);
-            let prefix = path.parent().unwrap();
-            tokio::fs::create_dir_all(prefix).await.unwrap();
-            let mut f = File::create(&path).await.unwrap();
-            f.write_all(&data).await.unwrap();
+            if field_name.as_str() == PATH_PREFIX_IMAGE {
+                let sha256 = sha256_hex(&data);
+                // reserved by this batch, waiting for it would never end
+                if let Some((first, _)) = hashes.iter().find(|(_, (hash, _))| *hash == sha256) {
+                    info!("Received field: {} {} is identical to {} of this upload", &field_name, &field_filename, first);
+                    batch_duplicates.push((field_filename, first.clone()));
+                    continue;
+                }
+                match reserve_upload(&app, &sha256).await {
+                    Upload::Existing(existing) => {
+                        info!("Received field: {} {} is identical to image {}", &field_name, &field_filename, existing.image_id);
+                        duplicates.insert(field_filename, *existing);
+                        continue;
+                    }
+                    Upload::New(reservation) => {
+                        reservations.insert(field_filename.clone(), reservation);
+                    }
+                }
+                let dhash = dhash_of(data.clone()).await;
+                hashes.insert(field_filename.clone(), (sha256, dhash));
+            } else if let Some(existing) = duplicates.remove(&field_filename) {
+                metadatas.remove(&field_filename);
+                filenames.push(existing);
+                continue;
+            } else if batch_duplicates.iter().any(|(path, _)| *path == field_filename) {
+                metadatas.remove(&field_filename);
+                continue;
+            }
+            write_blob(&field_name, &field_filename, &data).await?;
             info!("Received field: {} {} {} ({} bytes)", &field_name, &field_filename, &field_content_type, data.len());
             if field_name.as_str() == PATH_PREFIX_THUMB {
+                let (sha256, dhash) = hashes.remove(&field_filename).unzip();
+                let thumb_sha256 = Some(sha256_hex(&data));
                 let image = ImageData {
                     image_id: add_count(),
                     foreign_id: 0,
                     path: field_filename.clone(),
                     title: None,
-                    user: String::from("user"),
+                    user: user.name.clone(),
+                    position: 0,
+                    annotation: None,
+                    metadata: metadatas.remove(&field_filename),
+                    sha256,
+                    thumb_sha256,
+                    dhash: dhash.flatten(),
                 };
                 {
                     let mut lock = app.images.lock().unwrap();
                     lock.push(image.clone());
+                    let mut versions = app.image_versions.lock().unwrap();
+                    push_version(&mut versions, &image, VersionKind::Upload, &user.name);
                 }
+                // after the row is pushed, so identical uploads find it
+                reservations.remove(&field_filename);
                 filenames.push(image);
             }
         }
     }
+    // rows of batch duplicates after the other files
+    for (_, first) in batch_duplicates {
+        if let Some(image) = filenames.iter().find(|image| image.path == first).cloned() {
+            filenames.push(image);
+        }
+    }
     Ok(Json(filenames))
 }
 
+/// save edited image (rotate, flip, redact, crop) as new files and new version, 
+/// files of previous versions are kept for audit and revert,
+/// if `redacted` field is `true`, files of previous versions are moved to restricted storage
+pub async fn post_image_edit(
+    Path(image_id): Path<u32>,
+    State(app): State<AppState>,
+    user: User,
+    mut multipart: Multipart,
+) -> Result<Json<ImageData>, Response<Body>> {
+    let not_found = || Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::from(format!("Image {} not found", image_id)))
+        .unwrap();
+    if !app.images.lock().unwrap().iter().any(|image| image.image_id == image_id) {
+        return Err(not_found());
+    }
+    let mut new_path = None;
+    let mut new_sha256 = None;
+    let mut new_thumb_sha256 = None;
+    let mut new_dhash = None;
+    let mut redacted = false;
+    // files of a rejected edit are deleted, they are not referenced by any row
+    let mut written = Vec::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let field_name = field.name().unwrap_or("unnamed").to_owned();
+        if field_name.as_str() == "redacted" {
+            redacted = field.text().await.is_ok_and(|text| text == "true");
+        } else if [PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB].contains(&field_name.as_str()) {
+            let field_filename = field.file_name().unwrap_or("no_filename").to_owned();
+            let data = match field.bytes().await {
+                Ok(data) => data,
+                Err(e) => {
+                    delete_blobs(&written).await;
+                    return Err(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from(format!("Failed to read data for field '{}': {}", field_name, e)))
+                        .unwrap());
+                }
+            };
+            if let Err(response) = write_blob(&field_name, &field_filename, &data).await {
+                delete_blobs(&written).await;
+                return Err(response);
+            }
+            written.push(blob_key(&field_name, &field_filename));
+            info!("Received edited field: {} {} ({} bytes)", &field_name, &field_filename, data.len());
+            if field_name.as_str() == PATH_PREFIX_THUMB {
+                new_path = Some(field_filename);
+                new_thumb_sha256 = Some(sha256_hex(&data));
+            } else {
+                new_sha256 = Some(sha256_hex(&data));
+                new_dhash = dhash_of(data).await;
+            }
+        }
+    }
+    let Some(new_path) = new_path else {
+        delete_blobs(&written).await;
+        return Err(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from("Missing thumbs field"))
+            .unwrap());
+    };
+    // files are moved before versions are marked, a failed move must not leave restricted rows with public files
+    let restricted_paths = if redacted {
+        unrestricted_paths(&app.image_versions.lock().unwrap(), image_id)
+    } else {
+        Vec::new()
+    };
+    let moved = match restrict_files(&restricted_paths).await {
+        Ok(moved) => moved,
+        Err(e) => {
+            delete_blobs(&written).await;
+            return Err(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Failed to restrict files of image {}: {}", image_id, e)))
+                .unwrap());
+        }
+    };
+    let image = {
+        let mut lock = app.images.lock().unwrap();
+        lock.iter_mut().find(|image| image.image_id == image_id).map(|image| {
+            info!("Image {} edited from {} to {}", image_id, &image.path, &new_path);
+            image.path = new_path;
+            image.sha256 = new_sha256;
+            image.thumb_sha256 = new_thumb_sha256;
+            image.dhash = new_dhash;
+            let mut versions = app.image_versions.lock().unwrap();
+            restrict_versions(&mut versions, image_id, &restricted_paths);
+            let kind = if redacted { VersionKind::Redact } else { VersionKind::Edit };
+            push_version(&mut versions, image, kind, &user.name);
+            image.clone()
+        })
+    };
+    // deleted while files were written
+    let Some(image) = image else {
+        unrestrict_files(&moved).await;
+        delete_blobs(&written).await;
+        return Err(not_found());
+    };
+    for path in restricted_paths {
+        info!("Image {} unredacted file {} restricted by {}", image_id, &path, &user.name);
+    }
+    Ok(Json(image))
+}
+
+/// unredacted files of restricted versions, privileged roles only
+pub async fn get_restricted(
+    Path((prefix, path)): Path<(String, String)>,
+    user: User,
+) -> Result<Response<Body>, Response<Body>> {
+    if !user.is_privileged() {
+        return Err(Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("Permission denied"))
+            .unwrap());
+    }
+    if ![PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB].contains(&prefix.as_str()) || !is_valid_filename(&path) {
+        return Err(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(format!("Invalid filename '{}/{}'", prefix, path)))
+            .unwrap());
+    }
+    let key = blob_key(&restricted_prefix(&prefix), &path);
+    match blobs().get(&key).await {
+        Ok(data) => {
+            info!("Restricted file {}/{} read by {}", &prefix, &path, &user.name);
+            Ok(Response::builder()
+                .header(header::CONTENT_TYPE, content_type(&path))
+                .body(Body::from(data))
+                .unwrap())
+        }
+        Err(_) => Err(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from(format!("File '{}/{}' not found", prefix, path)))
+            .unwrap()),
+    }
+}
+
+/// `/images/{*path}` from blob store
+pub async fn get_image_file(Path(path): Path<String>, headers: HeaderMap) -> Response<Body> {
+    serve_blob(PATH_PREFIX_IMAGE, &path, &headers).await
+}
+
+/// `/thumbs/{*path}` from blob store
+pub async fn get_thumb_file(Path(path): Path<String>, headers: HeaderMap) -> Response<Body> {
+    serve_blob(PATH_PREFIX_THUMB, &path, &headers).await
+}
+
+/// AVIF copy of webp file when browser accepts it and it was written, otherwise file itself
+async fn serve_blob(prefix: &str, path: &str, headers: &HeaderMap) -> Response<Body> {
+    if !is_valid_filename(path) {
+        return Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(format!("Invalid filename '{}/{}'", prefix, path)))
+            .unwrap();
+    }
+    let avif = image_parser::avif_path(path).filter(|_| config().renditions.avif && accepts_avif(headers));
+    let (served, result) = match avif {
+        Some(avif) => match blobs().get(&blob_key(prefix, &avif)).await {
+            Ok(data) => (avif, Ok(data)),
+            Err(_) => (path.to_owned(), blobs().get(&blob_key(prefix, path)).await),
+        },
+        None => (path.to_owned(), blobs().get(&blob_key(prefix, path)).await),
+    };
+    match result {
+        Ok(data) => {
+            // sha256 of file, as stored on rows, changes when thumbnail is rebuilt
+            let etag = ["\"", &sha256_hex(&data), "\""].concat();
+            let builder = Response::builder()
+                // patient images must not be kept by shared proxies, and redacted files must not be served from cache
+                .header(header::CACHE_CONTROL, "private, no-cache")
+                .header(header::ETAG, &etag)
+                // AVIF or webp by Accept, only browser cache keeps a copy per Accept since response is private
+                .header(header::VARY, "Accept");
+            if matches_etag(headers, &etag) {
+                builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap()
+            } else {
+                builder.header(header::CONTENT_TYPE, content_type(&served)).body(Body::from(data)).unwrap()
+            }
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not found"))
+            .unwrap(),
+        Err(e) => {
+            error!("Cannot read {}/{}: {}", prefix, path, e);
+            Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::from(format!("Cannot read '{}/{}'", prefix, path)))
+                .unwrap()
+        }
+    }
+}
+
+/// `If-None-Match` has `etag` or `*`
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+    headers.get_all(header::IF_NONE_MATCH).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == etag || tag == "*")
+}
+
+/// `image/avif` in `Accept` without `q=0`
+fn accepts_avif(headers: &HeaderMap) -> bool {
+    headers.get_all(header::ACCEPT).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media| {
+            let mut params = media.split(';').map(str::trim);
+            params.next().is_some_and(|media_type| media_type.eq_ignore_ascii_case("image/avif"))
+                && !params.any(|param| {
+                    param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()).is_some_and(|q| q == 0.0)
+                })
+        })
+}
+
+/// by file extension, renditions are webp or avif
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).as_deref() {
+        Some("webp") => "image/webp",
+        Some("avif") => "image/avif",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ZipForm {
+    // comma separated image_id, from hidden form so browser streams download to disk
+    ids: String,
+}
+
+/// a file of zip archive, written as manifest.json
+#[derive(Serialize)]
+struct ZipManifestEntry {
+    file: String,
+    image_id: u32,
+    path: String,
+    title: Option<String>,
+    user: String,
+    created_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<ImageMetadata>,
+}
+
+/// stream zip of original renditions and manifest.json, archive is never buffered as a whole
+pub async fn post_image_zip(
+    State(app): State<AppState>,
+    user: User,
+    Form(form): Form<ZipForm>,
+) -> Response<Body> {
+    let ids = form.ids.split(',').filter_map(|id| id.trim().parse().ok()).collect::<Vec<u32>>();
+    let images = {
+        let lock = app.images.lock().unwrap();
+        ids.iter().filter_map(|id| lock.iter().find(|image| image.image_id == *id).cloned()).collect::<Vec<ImageData>>()
+    };
+    info!("Zip of {} images downloaded by {}", images.len(), &user.name);
+    let (writer, reader) = tokio::io::duplex(ZIP_BUFFER_SIZE);
+    shutdown::spawn(async move {
+        if let Err(e) = write_zip(writer, images).await {
+            // client sees truncated archive
+            error!("Cannot write zip: {}", e);
+        }
+    });
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"images_{}.zip\"", Ulid::new()))
+        .body(Body::from_stream(ReaderStream::new(reader)))
+        .unwrap()
+}
+
+async fn write_zip(writer: DuplexStream, images: Vec<ImageData>) -> Result<(), String> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    let mut manifest = Vec::new();
+    for image in images {
+        let data = blobs().get(&blob_key(PATH_PREFIX_IMAGE, &image.path)).await
+            .map_err(|e| format!("'{}': {}", &image.path, e))?;
+        let file = zip_entry_name(&image);
+        // webp is already compressed
+        zip.write_entry_whole(ZipEntryBuilder::new(file.clone().into(), Compression::Stored), &data).await
+            .map_err(|e| e.to_string())?;
+        manifest.push(ZipManifestEntry {
+            file,
+            image_id: image.image_id,
+            created_at: image_parser::path_to_ulid(&image.path)
+                .and_then(|ulid| OffsetDateTime::from(ulid.datetime()).format(&Rfc3339).ok()),
+            path: image.path,
+            title: image.title,
+            user: image.user,
+            metadata: image.metadata,
+        });
+    }
+    let manifest = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    zip.write_entry_whole(ZipEntryBuilder::new(String::from("manifest.json").into(), Compression::Deflate), &manifest).await
+        .map_err(|e| e.to_string())?;
+    zip.close().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// `title_01JG0M004KYHATX7J2W7MB28X4.webp`, or Ulid only if no title
+fn zip_entry_name(image: &ImageData) -> String {
+    let ulid = image_parser::path_to_ulid(&image.path).map(|ulid| ulid.to_string()).unwrap_or(image.image_id.to_string());
+    match image.title.as_deref().map(str::trim).filter(|title| !title.is_empty()) {
+        Some(title) => {
+            let safe = title.chars()
+                .map(|c| if c.is_control() || "/\\:*?\"<>|".contains(c) { '_' } else { c })
+                .take(60)
+                .collect::<String>();
+            [safe.as_str(), "_", &ulid, ".webp"].concat()
+        }
+        None => [ulid.as_str(), ".webp"].concat(),
+    }
+}
+
+/// all versions of image, newest first
+pub async fn get_image_versions(
+    Path(image_id): Path<u32>,
+    State(app): State<AppState>,
+    user: User,
+) -> Result<Json<Vec<ImageVersion>>, (StatusCode, Json<String>)> {
+    if !user.is_privileged() {
+        return Err((StatusCode::FORBIDDEN, Json(String::from("Permission denied"))));
+    }
+    if let Ok(lock) = app.image_versions.lock() {
+        let mut results = lock.iter()
+            .filter(|version| version.image_id == image_id)
+            .cloned()
+            .collect::<Vec<ImageVersion>>();
+        results.sort_by_key(|version| std::cmp::Reverse(version.version));
+
+        Ok(Json(results))
+    } else {
+        Err((StatusCode::INTERNAL_SERVER_ERROR, Json(String::from("Cannot read versions"))))
+    }
+}
+
+/// revert image to previous version as a new version
+pub async fn post_image_revert(
+    Path((image_id, version)): Path<(u32, u32)>,
+    State(app): State<AppState>,
+    user: User,
+) -> Result<Json<ImageData>, (StatusCode, Json<String>)> {
+    if !user.is_privileged() {
+        return Err((StatusCode::FORBIDDEN, Json(String::from("Permission denied"))));
+    }
+    let mut lock = app.images.lock().unwrap();
+    let mut versions = app.image_versions.lock().unwrap();
+    let Some(target) = versions.iter().find(|v| v.image_id == image_id && v.version == version).cloned() else {
+        return Err((StatusCode::NOT_FOUND, Json(format!("Version {} of image {} not found", version, image_id))));
+    };
+    if target.restricted {
+        return Err((StatusCode::CONFLICT, Json(format!("Version {} of image {} is restricted", version, image_id))));
+    }
+    let Some(image) = lock.iter_mut().find(|image| image.image_id == image_id) else {
+        return Err((StatusCode::NOT_FOUND, Json(format!("Image {} not found", image_id))));
+    };
+    info!("Image {} reverted to version {} by {}", image_id, version, &user.name);
+    image.path = target.path;
+    image.title = target.title;
+    image.sha256 = target.sha256;
+    image.thumb_sha256 = target.thumb_sha256;
+    image.dhash = target.dhash;
+    push_version(&mut versions, image, VersionKind::Revert(version), &user.name);
+
+    Ok(Json(image.clone()))
+}
+
+pub async fn get_image_annotation(
+    Path(image_id): Path<u32>,
+    State(app): State<AppState>,
+) -> impl IntoResponse {
+    if let Ok(lock) = app.annotations.lock() {
+        let result = lock.iter().find(|an| an.image_id == image_id).cloned();
+
+        (StatusCode::OK, Json(result))
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(None))
+    }
+}
+
+/// replace annotation of image, empty shapes will remove annotation
+pub async fn put_image_annotation(
+    Path(image_id): Path<u32>,
+    State(app): State<AppState>,
+    user: User,
+    Json(mut payload): Json<ImageAnnotation>,
+) -> impl IntoResponse {
+    if let Ok(mut lock) = app.annotations.lock() {
+        payload.image_id = image_id;
+        info!("Annotation of image {} saved by {} ({} shapes)", image_id, &user.name, payload.shapes.len());
+        lock.retain(|an| an.image_id != image_id);
+        if !payload.shapes.is_empty() {
+            lock.push(payload);
+        }
+
+        (StatusCode::OK, Json::<Vec<String>>(Vec::new()))
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
+    }
+}
+
 pub async fn get_first(
     Path(foreign_id): Path<u32>, 
     State(app): State<AppState>,
@@ -82,10 +579,18 @@ pub async fn get_first(
         for result in results.iter_mut() {
             if let Ok(image) = app.images.lock() {
                 if let Some(im) = image.iter().find(|im| *im == result) {
+                    result.path = im.path.clone();
                     result.title = im.title.clone();
+                    result.metadata = im.metadata.clone();
                 }
             }
+            if let Ok(annotations) = app.annotations.lock() {
+                result.annotation = annotations.iter()
+                    .find(|an| an.image_id == result.image_id && an.path == result.path)
+                    .cloned();
+            }
         }
+        results.sort_by_key(|data| data.position);
 
         (StatusCode::OK, Json(results))
     } else {
@@ -111,10 +616,18 @@ pub async fn get_second(
         for result in results.iter_mut() {
             if let Ok(image) = app.images.lock() {
                 if let Some(im) = image.iter().find(|im| *im == result) {
+                    result.path = im.path.clone();
                     result.title = im.title.clone();
+                    result.metadata = im.metadata.clone();
                 }
             }
+            if let Ok(annotations) = app.annotations.lock() {
+                result.annotation = annotations.iter()
+                    .find(|an| an.image_id == result.image_id && an.path == result.path)
+                    .cloned();
+            }
         }
+        results.sort_by_key(|data| data.position);
 
         (StatusCode::OK, Json(results))
     } else {
@@ -122,6 +635,128 @@ pub async fn get_second(
     }
 }
 
+#[derive(Deserialize)]
+pub struct DicomExportQuery {
+    // default is foreign_id
+    patient_id: Option<String>,
+}
+
+/// gallery as zip of DICOM Secondary Capture files, for PACS
+pub async fn get_first_dicom(
+    Path(foreign_id): Path<u32>,
+    Query(query): Query<DicomExportQuery>,
+    State(app): State<AppState>,
+) -> Result<Response<Body>, Response<Body>> {
+    let rows = app.first_table.lock().unwrap().clone();
+    let images = gallery_images(&app, &rows, foreign_id);
+    let patient_id = query.patient_id.unwrap_or(foreign_id.to_string());
+    dicom_zip(images, foreign_id, patient_id, ["first_", &foreign_id.to_string(), "_dicom.zip"].concat()).await
+}
+
+/// gallery as zip of DICOM Secondary Capture files, for PACS
+pub async fn get_second_dicom(
+    Path(foreign_id): Path<u32>,
+    Query(query): Query<DicomExportQuery>,
+    State(app): State<AppState>,
+) -> Result<Response<Body>, Response<Body>> {
+    let rows = app.second_table.lock().unwrap().clone();
+    let images = gallery_images(&app, &rows, foreign_id);
+    let patient_id = query.patient_id.unwrap_or(foreign_id.to_string());
+    dicom_zip(images, foreign_id, patient_id, ["second_", &foreign_id.to_string(), "_dicom.zip"].concat()).await
+}
+
+#[derive(Deserialize)]
+pub struct PdfExportQuery {
+    columns: Option<u32>,
+    rows: Option<u32>,
+}
+
+/// gallery as printable PDF report
+pub async fn get_first_pdf(
+    Path(foreign_id): Path<u32>,
+    Query(query): Query<PdfExportQuery>,
+    State(app): State<AppState>,
+) -> Result<Response<Body>, Response<Body>> {
+    let rows = app.first_table.lock().unwrap().clone();
+    let images = gallery_images(&app, &rows, foreign_id);
+    let grid = ReportGrid::new(query.columns, query.rows);
+    pdf_report(images, ["first ", &foreign_id.to_string()].concat(), grid, ["first_", &foreign_id.to_string(), ".pdf"].concat()).await
+}
+
+/// gallery as printable PDF report
+pub async fn get_second_pdf(
+    Path(foreign_id): Path<u32>,
+    Query(query): Query<PdfExportQuery>,
+    State(app): State<AppState>,
+) -> Result<Response<Body>, Response<Body>> {
+    let rows = app.second_table.lock().unwrap().clone();
+    let images = gallery_images(&app, &rows, foreign_id);
+    let grid = ReportGrid::new(query.columns, query.rows);
+    pdf_report(images, ["second ", &foreign_id.to_string()].concat(), grid, ["second_", &foreign_id.to_string(), ".pdf"].concat()).await
+}
+
+#[derive(Deserialize)]
+pub struct SimilarQuery {
+    // comma separated image_id of new uploads, default is every image of gallery
+    image_ids: Option<String>,
+    // max differing bits of perceptual hash
+    distance: Option<u32>,
+}
+
+/// possible duplicates of new uploads (or of each other) in gallery, to warn before saving
+pub async fn get_first_similar(
+    Path(foreign_id): Path<u32>,
+    Query(query): Query<SimilarQuery>,
+    State(app): State<AppState>,
+) -> Json<Vec<NearDuplicate>> {
+    let rows = app.first_table.lock().unwrap().clone();
+    Json(similar_images(&app, &rows, foreign_id, query))
+}
+
+/// possible duplicates of new uploads (or of each other) in gallery, to warn before saving
+pub async fn get_second_similar(
+    Path(foreign_id): Path<u32>,
+    Query(query): Query<SimilarQuery>,
+    State(app): State<AppState>,
+) -> Json<Vec<NearDuplicate>> {
+    let rows = app.second_table.lock().unwrap().clone();
+    Json(similar_images(&app, &rows, foreign_id, query))
+}
+
+fn similar_images(app: &AppState, rows: &[ImageData], foreign_id: u32, query: SimilarQuery) -> Vec<NearDuplicate> {
+    let gallery = gallery_images(app, rows, foreign_id);
+    let candidates = match query.image_ids {
+        Some(ids) => {
+            let ids = ids.split(',').filter_map(|id| id.trim().parse().ok()).collect::<Vec<u32>>();
+            let lock = app.images.lock().unwrap();
+            lock.iter().filter(|image| ids.contains(&image.image_id)).cloned().collect::<Vec<ImageData>>()
+        }
+        None => gallery.clone(),
+    };
+    near_duplicates(&candidates, &gallery, query.distance.unwrap_or(NEAR_DUPLICATE_DISTANCE))
+}
+
+/// gallery images within `max_distance` of each candidate, closest first, pair of two candidates is listed once
+fn near_duplicates(candidates: &[ImageData], gallery: &[ImageData], max_distance: u32) -> Vec<NearDuplicate> {
+    let mut results = Vec::new();
+    for candidate in candidates {
+        let Some(hash) = &candidate.dhash else {
+            continue;
+        };
+        for image in gallery {
+            if image.image_id == candidate.image_id || (image.image_id < candidate.image_id && candidates.contains(image)) {
+                continue;
+            }
+            let distance = image.dhash.as_deref().and_then(|other| imaging::dhash_distance(hash, other));
+            if let Some(distance) = distance.filter(|distance| *distance <= max_distance) {
+                results.push(NearDuplicate { image_id: candidate.image_id, similar: image.clone(), distance });
+            }
+        }
+    }
+    results.sort_by_key(|duplicate| duplicate.distance);
+    results
+}
+
 pub async fn post_first(
     State(app): State<AppState>,
     Json(payloads): Json<Vec<ImageData>>,
@@ -129,6 +764,12 @@ pub async fn post_first(
     if let Ok(mut lock) = app.first_table.lock() {
         for mut payload in payloads {
             payload.foreign_id = 1;
+            // identical upload returns image which may be in this gallery already
+            if lock.iter().any(|row| row.foreign_id == payload.foreign_id && row.image_id == payload.image_id) {
+                continue;
+            }
+            // append to the end of gallery
+            payload.position = next_position(&lock, payload.foreign_id);
             lock.push(payload);
         }
 
@@ -145,6 +786,12 @@ pub async fn post_second(
     if let Ok(mut lock) = app.second_table.lock() {
         for mut payload in payloads {
             payload.foreign_id = 1;
+            // identical upload returns image which may be in this gallery already
+            if lock.iter().any(|row| row.foreign_id == payload.foreign_id && row.image_id == payload.image_id) {
+                continue;
+            }
+            // append to the end of gallery
+            payload.position = next_position(&lock, payload.foreign_id);
             lock.push(payload);
         }
 
@@ -156,11 +803,17 @@ pub async fn post_second(
 
 pub async fn put_image(
     State(app): State<AppState>,
+    user: User,
     Json(payload): Json<ImageData>,
 ) -> impl IntoResponse {
     if let Ok(mut lock) = app.images.lock() {
         if let Some(old) = lock.iter_mut().find(|data| **data == payload) {
-            old.title = payload.title;
+            if old.title != payload.title {
+                old.title = payload.title;
+                // title only version, share files with previous version
+                let mut versions = app.image_versions.lock().unwrap();
+                push_version(&mut versions, old, VersionKind::Title, &user.name);
+            }
         }
 
         (StatusCode::OK, Json::<Vec<String>>(Vec::new()))
@@ -169,6 +822,20 @@ pub async fn put_image(
     }
 }
 
+pub async fn put_first(
+    Path(foreign_id): Path<u32>,
+    State(app): State<AppState>,
+    Json(payloads): Json<Vec<u32>>,
+) -> impl IntoResponse {
+    if let Ok(mut lock) = app.first_table.lock() {
+        reorder(&mut lock, foreign_id, &payloads);
+
+        (StatusCode::OK, Json::<Vec<String>>(Vec::new()))
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
+    }
+}
+
 pub async fn delete_first(
     State(app): State<AppState>,
     Json(payloads): Json<Vec<u32>>,
@@ -186,6 +853,20 @@ pub async fn delete_first(
     }
 }
 
+pub async fn put_second(
+    Path(foreign_id): Path<u32>,
+    State(app): State<AppState>,
+    Json(payloads): Json<Vec<u32>>,
+) -> impl IntoResponse {
+    if let Ok(mut lock) = app.second_table.lock() {
+        reorder(&mut lock, foreign_id, &payloads);
+
+        (StatusCode::OK, Json::<Vec<String>>(Vec::new()))
+    } else {
+        (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
+    }
+}
+
 pub async fn delete_second(
     State(app): State<AppState>,
     Json(payloads): Json<Vec<u32>>,
@@ -201,4 +882,517 @@ pub async fn delete_second(
     } else {
         (StatusCode::INTERNAL_SERVER_ERROR, Json(Vec::new()))
     }
-}
\ No newline at end of file
+}
+
+/// only accept `01J/G0/M004KYHATX7J2W7MB28X4.webp` like filename
+fn is_valid_filename(filename: &str) -> bool {
+    !filename.split('/').any(|part| part.is_empty() || part.starts_with('.'))
+}
+
+/// write to blob `{field_name}/{filename}`
+pub async fn write_blob(field_name: &str, filename: &str, data: &[u8]) -> Result<(), Response<Body>> {
+    if !is_valid_filename(filename) {
+        return Err(Response::builder()
+            .status(StatusCode::BAD_REQUEST)
+            .body(Body::from(format!("Invalid filename '{}'", filename)))
+            .unwrap());
+    }
+    metrics::add_uploaded_bytes(field_name, data.len());
+    let key = blob_key(field_name, filename);
+    blobs().put(&key, data.to_vec()).await.map_err(|e| {
+        Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(format!("Failed to write '{}': {}", key, e)))
+            .unwrap()
+    })?;
+    if [PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB].contains(&field_name) {
+        avif::spawn_avif(field_name, filename, data);
+    }
+    Ok(())
+}
+
+/// `restricted/<prefix>`
+pub fn restricted_prefix(prefix: &str) -> String {
+    [PATH_PREFIX_RESTRICTED, "/", prefix].concat()
+}
+
+/// image, thumbnail and their AVIF copies of paths, as (prefix, filename)
+fn rendition_files(paths: &[String]) -> Vec<(&'static str, String)> {
+    paths.iter()
+        .flat_map(|path| [PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB].map(|prefix| (prefix, path)))
+        .flat_map(|(prefix, path)| std::iter::once(path.clone()).chain(image_parser::avif_path(path)).map(move |file| (prefix, file)))
+        .collect()
+}
+
+/// move blob, `false` when it does not exist, ex. AVIF not written or moved by its job
+async fn move_blob(from: &str, to: &str) -> io::Result<bool> {
+    if blobs().stat(from).await?.is_none() {
+        return Ok(false);
+    }
+    match blobs().rename(from, to).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// move files of paths from `<prefix>` to `restricted/<prefix>` and return moved files,
+/// on failure the moved files are put back so nothing is half restricted
+async fn restrict_files(paths: &[String]) -> io::Result<Vec<(&'static str, String)>> {
+    let mut moved = Vec::new();
+    for (prefix, file) in rendition_files(paths) {
+        match move_blob(&blob_key(prefix, &file), &blob_key(&restricted_prefix(prefix), &file)).await {
+            Ok(true) => moved.push((prefix, file)),
+            Ok(false) => {}
+            Err(e) => {
+                unrestrict_files(&moved).await;
+                return Err(io::Error::new(e.kind(), format!("'{}/{}': {}", prefix, file, e)));
+            }
+        }
+    }
+    Ok(moved)
+}
+
+/// delete files written by a rejected request, failure is logged only
+async fn delete_blobs(keys: &[String]) {
+    for key in keys {
+        if let Err(e) = blobs().delete(key).await {
+            error!("Cannot delete {}: {}", key, e);
+        }
+    }
+}
+
+/// undo `restrict_files`, failure is logged since the request already fails
+async fn unrestrict_files(moved: &[(&'static str, String)]) {
+    for (prefix, file) in moved.iter().rev() {
+        if let Err(e) = move_blob(&blob_key(&restricted_prefix(prefix), file), &blob_key(prefix, file)).await {
+            error!("Cannot move back restricted file {}/{}: {}", prefix, file, e);
+        }
+    }
+}
+
+/// paths of versions of image which are not restricted yet
+fn unrestricted_paths(versions: &[ImageVersion], image_id: u32) -> Vec<String> {
+    let mut paths = Vec::new();
+    for version in versions.iter().filter(|v| v.image_id == image_id && !v.restricted) {
+        if !paths.contains(&version.path) {
+            paths.push(version.path.clone());
+        }
+    }
+    paths
+}
+
+/// mark versions of image with given paths restricted, after their files were moved
+fn restrict_versions(versions: &mut [ImageVersion], image_id: u32, paths: &[String]) {
+    for version in versions.iter_mut().filter(|v| v.image_id == image_id && paths.contains(&v.path)) {
+        version.restricted = true;
+    }
+}
+
+pub fn push_version(versions: &mut Vec<ImageVersion>, image: &ImageData, kind: VersionKind, user: &str) {
+    push_version_at(versions, image, kind, user, OffsetDateTime::now_utc());
+}
+
+/// version created at given time, ex. original time of imported photo
+pub fn push_version_at(versions: &mut Vec<ImageVersion>, image: &ImageData, kind: VersionKind, user: &str, created_at: OffsetDateTime) {
+    let version = versions.iter()
+        .filter(|v| v.image_id == image.image_id)
+        .map(|v| v.version)
+        .max()
+        .unwrap_or_default() + 1;
+    versions.push(ImageVersion {
+        image_id: image.image_id,
+        version,
+        kind,
+        path: image.path.clone(),
+        title: image.title.clone(),
+        user: user.to_owned(),
+        created_at,
+        restricted: false,
+        sha256: image.sha256.clone(),
+        thumb_sha256: image.thumb_sha256.clone(),
+        dhash: image.dhash.clone(),
+    });
+}
+
+/// perceptual hash of image file, decoded off the async runtime
+pub async fn dhash_of(data: impl AsRef<[u8]> + Send + 'static) -> Option<String> {
+    tokio::task::spawn_blocking(move || imaging::dhash_bytes(data.as_ref())).await.ok().flatten()
+}
+
+/// image with the same file content, so identical upload reuses its files and row
+pub fn find_by_sha256(images: &[ImageData], sha256: &str) -> Option<ImageData> {
+    images.iter().find(|image| image.sha256.as_deref() == Some(sha256)).cloned()
+}
+
+/// sha256 of an upload whose row is not pushed yet, released when the row is pushed or the upload fails
+pub struct UploadReservation {
+    sha256: String,
+    // dropped after removal from `PENDING_UPLOADS`, which wakes waiting identical uploads
+    _done: watch::Sender<()>,
+}
+
+impl Drop for UploadReservation {
+    fn drop(&mut self) {
+        PENDING_UPLOADS.lock().unwrap().remove(&self.sha256);
+    }
+}
+
+pub enum Upload {
+    Existing(Box<ImageData>),
+    New(UploadReservation),
+}
+
+/// existing image with the same file content, or reservation of sha256 checked under the same images lock,
+/// so concurrent identical uploads make one row, the later one waits for the earlier to push its row or fail
+pub async fn reserve_upload(app: &AppState, sha256: &str) -> Upload {
+    loop {
+        let mut pending = {
+            let images = app.images.lock().unwrap();
+            if let Some(existing) = find_by_sha256(&images, sha256) {
+                return Upload::Existing(Box::new(existing));
+            }
+            let mut uploads = PENDING_UPLOADS.lock().unwrap();
+            match uploads.get(sha256) {
+                Some(pending) => pending.clone(),
+                None => {
+                    let (done, pending) = watch::channel(());
+                    uploads.insert(sha256.to_owned(), pending);
+                    return Upload::New(UploadReservation { sha256: sha256.to_owned(), _done: done });
+                }
+            }
+        };
+        // closed when the reservation is dropped, then look again
+        let _ = pending.changed().await;
+    }
+}
+
+/// gallery rows of `foreign_id` with path, title and metadata from images table, by position
+fn gallery_images(app: &AppState, rows: &[ImageData], foreign_id: u32) -> Vec<ImageData> {
+    let images = app.images.lock().unwrap();
+    let mut results = rows.iter()
+        .filter(|row| row.foreign_id == foreign_id)
+        .filter_map(|row| images.iter().find(|im| *im == row).map(|im| ImageData {
+            foreign_id: row.foreign_id,
+            position: row.position,
+            ..im.clone()
+        }))
+        .collect::<Vec<ImageData>>();
+    results.sort_by_key(|data| data.position);
+    results
+}
+
+/// original renditions of gallery images, in the same order
+async fn read_images(images: &[ImageData]) -> Result<Vec<Vec<u8>>, Response<Body>> {
+    let mut files = Vec::new();
+    for image in images {
+        let key = blob_key(PATH_PREFIX_IMAGE, &image.path);
+        match blobs().get(&key).await {
+            Ok(data) => files.push(data),
+            Err(e) => return Err(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from(format!("Cannot read '{}': {}", key, e)))
+                .unwrap()),
+        }
+    }
+    Ok(files)
+}
+
+async fn dicom_zip(images: Vec<ImageData>, foreign_id: u32, patient_id: String, filename: String) -> Result<Response<Body>, Response<Body>> {
+    let mut ulids = Vec::new();
+    for image in &images {
+        let Some(ulid) = image_parser::path_to_ulid(&image.path) else {
+            return Err(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Invalid path '{}'", image.path)))
+                .unwrap());
+        };
+        ulids.push(ulid);
+    }
+    let Some(earliest) = ulids.iter().min().copied() else {
+        return Err(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("No image to export"))
+            .unwrap());
+    };
+    // files are read while streaming, missing ones are reported before the response starts
+    for image in &images {
+        let key = blob_key(PATH_PREFIX_IMAGE, &image.path);
+        match blobs().stat(&key).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return Err(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from(format!("Cannot read '{}': not found", key)))
+                .unwrap()),
+            Err(e) => return Err(Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(format!("Cannot read '{}': {}", key, e)))
+                .unwrap()),
+        }
+    }
+    let study = dicom::DicomStudy { patient_id, study_id: foreign_id.to_string(), ulid: earliest };
+    let (writer, reader) = tokio::io::duplex(ZIP_BUFFER_SIZE);
+    shutdown::spawn(async move {
+        if let Err(e) = write_dicom_zip(writer, study, images, ulids).await {
+            // client sees truncated archive
+            error!("Cannot write DICOM zip: {}", e);
+        }
+    });
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/zip")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename))
+        .body(Body::from_stream(ReaderStream::new(reader)))
+        .unwrap())
+}
+
+async fn write_dicom_zip(writer: DuplexStream, study: dicom::DicomStudy, images: Vec<ImageData>, ulids: Vec<Ulid>) -> Result<(), String> {
+    let mut zip = ZipFileWriter::with_tokio(writer);
+    for (image, ulid) in images.into_iter().zip(ulids) {
+        let data = blobs().get(&blob_key(PATH_PREFIX_IMAGE, &image.path)).await
+            .map_err(|e| format!("'{}': {}", &image.path, e))?;
+        let study = study.clone();
+        let file = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+            let rgb = image::load_from_memory(&data).map_err(|e| format!("Cannot read '{}': {}", image.path, e))?.to_rgb8();
+            Ok(dicom::secondary_capture(&study, &image, ulid, &rgb))
+        }).await.map_err(|e| e.to_string())??;
+        // pixel data is uncompressed, unlike webp
+        zip.write_entry_whole(ZipEntryBuilder::new([ulid.to_string(), String::from(".dcm")].concat().into(), Compression::Deflate), &file).await
+            .map_err(|e| e.to_string())?;
+    }
+    zip.close().await.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// `yyyy-mm-dd hh:mm UTC`
+fn format_time(datetime: OffsetDateTime) -> String {
+    datetime.format(format_description!("[year]-[month]-[day] [hour]:[minute] UTC")).unwrap_or_default()
+}
+
+async fn pdf_report(images: Vec<ImageData>, heading: String, grid: ReportGrid, filename: String) -> Result<Response<Body>, Response<Body>> {
+    let files = read_images(&images).await?;
+    let result = tokio::task::spawn_blocking(move || -> Result<Vec<u8>, String> {
+        let mut report_images = Vec::new();
+        for (image, data) in images.into_iter().zip(files) {
+            let rgb = image::load_from_memory(&data).map_err(|e| format!("Cannot read '{}': {}", image.path, e))?.to_rgb8();
+            let mut jpeg = Vec::new();
+            JpegEncoder::new_with_quality(&mut jpeg, 85).encode_image(&rgb).map_err(|e| e.to_string())?;
+            let created = image_parser::path_to_ulid(&image.path)
+                .map(|ulid| format_time(OffsetDateTime::from(ulid.datetime())))
+                .unwrap_or_default();
+            report_images.push(ReportImage {
+                title: image.title,
+                user: image.user,
+                created,
+                width: rgb.width(),
+                height: rgb.height(),
+                jpeg,
+            });
+        }
+        // TrueType font with Thai glyphs, ex. Sarabun
+        let font = std::fs::read(volume_path(PATH_PREFIX_FONT, REPORT_FONT)).ok();
+        let printed = format_time(OffsetDateTime::now_utc());
+        report::gallery_pdf(&heading, &printed, report_images, grid, font)
+    }).await;
+    match result {
+        Ok(Ok(data)) => Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "application/pdf")
+            .header(header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", filename))
+            .body(Body::from(data))
+            .unwrap()),
+        Ok(Err(e)) => Err(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e))
+            .unwrap()),
+        Err(e) => Err(Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from(e.to_string()))
+            .unwrap()),
+    }
+}
+
+pub fn next_position(rows: &[ImageData], foreign_id: u32) -> u32 {
+    rows.iter()
+        .filter(|row| row.foreign_id == foreign_id)
+        .map(|row| row.position + 1)
+        .max()
+        .unwrap_or_default()
+}
+
+/// set `position` of gallery rows by `image_ids` order, 
+/// rows not in `image_ids` keep their relative order after the given ones
+fn reorder(rows: &mut [ImageData], foreign_id: u32, image_ids: &[u32]) {
+    let mut gallery = rows.iter_mut()
+        .filter(|row| row.foreign_id == foreign_id)
+        .collect::<Vec<&mut ImageData>>();
+    gallery.sort_by_key(|row| {
+        let pos = image_ids.iter().position(|id| *id == row.image_id).unwrap_or(image_ids.len());
+        (pos, row.position)
+    });
+    for (pos, row) in gallery.into_iter().enumerate() {
+        row.position = pos as u32;
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use model::{ImageData, ImageVersion, VersionKind};
+    use std::{sync::{Arc, Mutex}, time::Duration};
+    use time::OffsetDateTime;
+
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    use crate::AppState;
+
+    use super::{
+        accepts_avif, matches_etag, near_duplicates, rendition_files, reorder, reserve_upload, restrict_versions,
+        unrestricted_paths, zip_entry_name, Upload,
+    };
+
+    fn row(image_id: u32, foreign_id: u32, position: u32) -> ImageData {
+        ImageData {
+            image_id,
+            foreign_id,
+            path: String::new(),
+            title: None,
+            user: String::from("user"),
+            position,
+            annotation: None,
+            metadata: None,
+            sha256: None,
+            thumb_sha256: None,
+            dhash: None,
+        }
+    }
+
+    #[test]
+    pub fn test_reorder() {
+        let mut rows = vec![row(1, 1, 0), row(2, 1, 1), row(3, 2, 0), row(4, 1, 2)];
+        reorder(&mut rows, 1, &[4, 1]);
+        let positions = rows.iter().map(|row| (row.image_id, row.position)).collect::<Vec<(u32, u32)>>();
+        assert_eq!(positions, vec![(1, 1), (2, 2), (3, 0), (4, 0)]);
+    }
+
+    #[test]
+    pub fn test_near_duplicates() {
+        let hashed = |image_id: u32, dhash: &str| ImageData { dhash: Some(dhash.to_owned()), ..row(image_id, 1, image_id) };
+        let gallery = vec![hashed(1, "00000000000000ff"), hashed(2, "ffffffffffffff00"), row(3, 1, 3)];
+        let candidates = vec![hashed(4, "00000000000000fe"), hashed(5, "00000000000000ff")];
+        let found = near_duplicates(&candidates, &[gallery, candidates.clone()].concat(), 10).into_iter()
+            .map(|duplicate| (duplicate.image_id, duplicate.similar.image_id, duplicate.distance))
+            .collect::<Vec<(u32, u32, u32)>>();
+        assert_eq!(found, vec![(5, 1, 0), (4, 1, 1), (4, 5, 1)]);
+    }
+
+    #[test]
+    pub fn test_restrict_versions() {
+        let version = |image_id: u32, version: u32, path: &str| ImageVersion {
+            image_id,
+            version,
+            kind: VersionKind::Upload,
+            path: path.to_owned(),
+            title: None,
+            user: String::from("user"),
+            created_at: OffsetDateTime::UNIX_EPOCH,
+            restricted: false,
+            sha256: None,
+            thumb_sha256: None,
+            dhash: None,
+        };
+        let mut versions = vec![version(1, 1, "a"), version(1, 2, "a"), version(2, 1, "b"), version(1, 3, "c")];
+        let paths = unrestricted_paths(&versions, 1);
+        assert_eq!(paths, vec![String::from("a"), String::from("c")]);
+        // version added while files were moved keeps its public files
+        versions.push(version(1, 4, "d"));
+        restrict_versions(&mut versions, 1, &paths);
+        assert!(versions[..2].iter().all(|v| v.restricted));
+        assert!(!versions[2].restricted);
+        assert_eq!(unrestricted_paths(&versions, 1), vec![String::from("d")]);
+
+        let files = rendition_files(&[String::from("01J/G0/M004KYHATX7J2W7MB28X4.webp")]);
+        assert_eq!(files.len(), 4);
+        assert!(files.contains(&("thumbs", String::from("01J/G0/M004KYHATX7J2W7MB28X4.avif"))));
+    }
+
+    #[test]
+    pub fn test_zip_entry_name() {
+        let mut image = ImageData {
+            image_id: 1,
+            foreign_id: 0,
+            path: String::from("01J/G0/M004KYHATX7J2W7MB28X4.webp"),
+            title: None,
+            user: String::from("user"),
+            position: 0,
+            annotation: None,
+            metadata: None,
+            sha256: None,
+            thumb_sha256: None,
+            dhash: None,
+        };
+        assert_eq!(zip_entry_name(&image), "01JG0M004KYHATX7J2W7MB28X4.webp");
+        image.title = Some(String::from("แผล a/b"));
+        assert_eq!(zip_entry_name(&image), "แผล a_b_01JG0M004KYHATX7J2W7MB28X4.webp");
+    }
+
+    #[test]
+    pub fn test_accepts_avif() {
+        let accept = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, HeaderValue::from_static(value));
+            accepts_avif(&headers)
+        };
+        assert!(accept("image/avif,image/webp,image/apng,image/svg+xml,image/*,*/*;q=0.8"));
+        assert!(accept("image/webp, IMAGE/AVIF;q=0.9"));
+        assert!(!accept("image/avif;q=0, image/webp"));
+        assert!(!accept("image/webp,*/*"));
+        assert!(!accepts_avif(&HeaderMap::new()));
+    }
+
+    #[test]
+    pub fn test_matches_etag() {
+        let if_none_match = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(matches_etag(&if_none_match("\"ab12\""), "\"ab12\""));
+        assert!(matches_etag(&if_none_match("\"cd34\", W/\"ab12\""), "\"ab12\""));
+        assert!(matches_etag(&if_none_match("*"), "\"ab12\""));
+        assert!(!matches_etag(&if_none_match("\"cd34\""), "\"ab12\""));
+        assert!(!matches_etag(&HeaderMap::new(), "\"ab12\""));
+    }
+
+    #[tokio::test]
+    pub async fn test_reserve_upload() {
+        let app = AppState {
+            images: Arc::new(Mutex::new(Vec::new())),
+            image_versions: Arc::new(Mutex::new(Vec::new())),
+            annotations: Arc::new(Mutex::new(Vec::new())),
+            first_table: Arc::new(Mutex::new(Vec::new())),
+            second_table: Arc::new(Mutex::new(Vec::new())),
+        };
+        let Upload::New(reservation) = reserve_upload(&app, "aa").await else {
+            panic!("first upload must reserve");
+        };
+        // identical upload at the same time waits for the first one
+        let waiting = tokio::spawn({
+            let app = app.clone();
+            async move { reserve_upload(&app, "aa").await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        app.images.lock().unwrap().push(ImageData { sha256: Some(String::from("aa")), ..row(5, 0, 0) });
+        drop(reservation);
+        assert!(matches!(waiting.await.unwrap(), Upload::Existing(image) if image.image_id == 5));
+
+        // failed upload lets the next one write files
+        let Upload::New(reservation) = reserve_upload(&app, "bb").await else {
+            panic!("first upload must reserve");
+        };
+        let waiting = tokio::spawn({
+            let app = app.clone();
+            async move { reserve_upload(&app, "bb").await }
+        });
+        drop(reservation);
+        assert!(matches!(waiting.await.unwrap(), Upload::New(_)));
+    }
+}
@@ -0,0 +1,160 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde_derive::Serialize;
+use std::{
+    path::Path,
+    sync::LazyLock,
+    time::Instant,
+};
+
+use crate::{
+    blob::{blob_key, blobs},
+    config::config,
+    handlers::{PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB},
+    shutdown,
+    store::TEMP_SUFFIX,
+    AppState,
+};
+
+// removed right away, local one is also removed by `remove_temp_files` when the process dies in between
+const PROBE_FILE: &str = ".readyz";
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+#[derive(Serialize)]
+pub struct Liveness {
+    pub status: &'static str,
+    pub version: &'static str,
+    pub uptime_secs: u64,
+}
+
+#[derive(Serialize)]
+pub struct Readiness {
+    pub status: &'static str,
+    pub checks: Vec<Check>,
+}
+
+#[derive(Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, result: Result<(), String>) -> Self {
+        match result {
+            Ok(()) => Self { name, ok: true, error: None },
+            Err(e) => Self { name, ok: false, error: Some(e) },
+        }
+    }
+}
+
+/// start uptime clock, called once before serving
+pub fn start() {
+    LazyLock::force(&STARTED);
+}
+
+/// `GET /healthz`, process is up and answering
+pub async fn get_healthz() -> Json<Liveness> {
+    Json(Liveness {
+        status: "ok",
+        version: env!("CARGO_PKG_VERSION"),
+        uptime_secs: STARTED.elapsed().as_secs(),
+    })
+}
+
+/// `GET /readyz`, 503 with failed checks when requests should go to other instance
+pub async fn get_readyz(State(app): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let mut checks = vec![Check::new("tables", tables(&app))];
+    // filesystem may block, ex. stalled network volume
+    let files = tokio::task::spawn_blocking(file_checks).await
+        .unwrap_or_else(|e| vec![Check::new("volume", Err(e.to_string()))]);
+    checks.extend(files);
+    for prefix in [PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB] {
+        checks.push(Check::new(prefix, blob_writable(prefix).await));
+    }
+    // drained instance must leave the pool before it stops
+    if shutdown::token().is_cancelled() {
+        checks.push(Check::new("shutdown", Err(String::from("Shutting down"))));
+    }
+    if checks.iter().all(|check| check.ok) {
+        (StatusCode::OK, Json(Readiness { status: "ready", checks }))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(Readiness { status: "unavailable", checks }))
+    }
+}
+
+/// in-memory tables are usable when no writer panicked while holding a lock
+fn tables(app: &AppState) -> Result<(), String> {
+    let poisoned = [
+        ("images", app.images.is_poisoned()),
+        ("image_versions", app.image_versions.is_poisoned()),
+        ("annotations", app.annotations.is_poisoned()),
+        ("first_table", app.first_table.is_poisoned()),
+        ("second_table", app.second_table.is_poisoned()),
+    ].into_iter()
+        .filter_map(|(table, poisoned)| poisoned.then_some(table))
+        .collect::<Vec<_>>();
+    if poisoned.is_empty() {
+        Ok(())
+    } else {
+        Err(["Poisoned lock of", &poisoned.join(", ")].join(" "))
+    }
+}
+
+fn file_checks() -> Vec<Check> {
+    let storage = &config().storage;
+    // tables are saved there on shutdown
+    let data_dir = storage.data_file().parent().map(Path::to_path_buf).unwrap_or_default();
+    vec![
+        Check::new("data_file", writable(&data_dir)),
+        Check::new("disk", free_space(&storage.volume, storage.min_free_mb)),
+    ]
+}
+
+/// put and delete a probe blob, so unreachable S3 or read-only volume fails here
+async fn blob_writable(prefix: &str) -> Result<(), String> {
+    let key = blob_key(prefix, PROBE_FILE);
+    let result = match blobs().put(&key, b"ok".to_vec()).await {
+        Ok(()) => blobs().delete(&key).await,
+        Err(e) => Err(e),
+    };
+    result.map_err(|e| format!("Cannot write {}: {}", key, e))
+}
+
+/// create, write and remove a probe file, permission or read-only mount fails here
+fn writable(dir: &Path) -> Result<(), String> {
+    let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+    let probe = dir.join([PROBE_FILE, TEMP_SUFFIX].concat());
+    std::fs::create_dir_all(dir)
+        .and_then(|_| std::fs::write(&probe, b"ok"))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format!("Cannot write {}: {}", dir.display(), e))
+}
+
+fn free_space(volume: &Path, min_free_mb: u64) -> Result<(), String> {
+    let available = fs4::available_space(volume).map_err(|e| format!("Cannot read free space of {}: {}", volume.display(), e))?;
+    let available_mb = available / 1024 / 1024;
+    if available_mb < min_free_mb {
+        Err(format!("{} MB free, below {} MB", available_mb, min_free_mb))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{free_space, writable};
+
+    #[test]
+    pub fn test_readiness_checks() {
+        let dir = std::env::temp_dir().join(["kphis_readyz_", &std::process::id().to_string()].concat());
+        assert!(writable(&dir.join("images")).is_ok());
+        assert_eq!(std::fs::read_dir(dir.join("images")).unwrap().count(), 0);
+        assert!(free_space(&dir, 0).is_ok());
+        assert!(free_space(&dir, u64::MAX).is_err());
+        assert!(free_space(&dir.join("missing"), 0).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,71 @@
+use image::{DynamicImage, ImageResult};
+use imaging::Profile;
+use ulid::Ulid;
+
+use crate::config::config;
+
+/// create (image, thumbnail) webp bytes, for images uploaded without browser, ex. FHIR
+pub fn image_bytes_parser(raw_data: &[u8]) -> ImageResult<(Vec<u8>, Vec<u8>)> {
+    image_renditions(imaging::image_decode(raw_data)?)
+}
+
+/// (image, thumbnail) profiles of `renditions` sizes in config
+pub fn profiles() -> (Profile, Profile) {
+    let renditions = &config().renditions;
+    (Profile::IMAGE.with_size(renditions.image_size), Profile::THUMB.with_size(renditions.thumb_size))
+}
+
+pub fn image_renditions(raw_image: DynamicImage) -> ImageResult<(Vec<u8>, Vec<u8>)> {
+    let (image, thumb) = profiles();
+    imaging::renditions(raw_image, image, thumb)
+}
+
+/// square webp thumbnail from center of image
+pub fn image_thumbnail(image: &DynamicImage) -> ImageResult<Vec<u8>> {
+    profiles().1.render(image)
+}
+
+/// `01JG0M004KYHATX7J2W7MB28X4` Ulid to `01J/G0/M004KYHATX7J2W7MB28X4.webp` path
+pub fn new_ulid_to_path() -> String {
+    ulid_to_path(Ulid::new())
+}
+
+pub fn ulid_to_path(ulid: Ulid) -> String {
+    let mut s = ulid.to_string();
+    s.insert_str(s.len(), ".webp");
+    s.insert(5, '/');
+    s.insert(3, '/');
+    s
+}
+
+/// Ulid of stored file, `01J/G0/M004KYHATX7J2W7MB28X4.webp` or `.avif` is `01JG0M004KYHATX7J2W7MB28X4`
+pub fn path_to_ulid(path: &str) -> Option<Ulid> {
+    let stem = path.strip_suffix(".webp").or_else(|| path.strip_suffix(".avif")).unwrap_or(path);
+    Ulid::from_string(&stem.replace('/', "")).ok()
+}
+
+/// AVIF rendition next to webp file, `01J/G0/M004KYHATX7J2W7MB28X4.avif`
+pub fn avif_path(path: &str) -> Option<String> {
+    path.strip_suffix(".webp").map(|stem| [stem, ".avif"].concat())
+}
+
+/// webp file of AVIF rendition
+pub fn webp_path(avif_path: &str) -> Option<String> {
+    avif_path.strip_suffix(".avif").map(|stem| [stem, ".webp"].concat())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{avif_path, new_ulid_to_path, path_to_ulid, webp_path};
+
+    #[test]
+    pub fn test_path_to_ulid() {
+        let path = new_ulid_to_path();
+        let ulid = path_to_ulid(&path).unwrap();
+        assert_eq!(path.replace('/', ""), [ulid.to_string(), String::from(".webp")].concat());
+        let avif = avif_path(&path).unwrap();
+        assert_eq!(path_to_ulid(&avif), Some(ulid));
+        assert_eq!(webp_path(&avif).as_ref(), Some(&path));
+        assert_eq!(avif_path(&avif), None);
+    }
+}
@@ -0,0 +1,193 @@
+use serde_derive::Deserialize;
+use std::{
+    collections::HashSet,
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    time::SystemTime,
+};
+use time::{
+    format_description::well_known::Rfc3339, macros::{format_description, offset}, OffsetDateTime, PrimitiveDateTime, UtcOffset,
+};
+use tracing::{info, warn};
+use ulid::Ulid;
+
+use imaging::dhash_bytes;
+use model::{ImageData, VersionKind};
+
+use crate::{
+    add_count,
+    avif::update_avif,
+    blob::{blob_key, blobs, sha256_hex},
+    handlers::{find_by_sha256, next_position, push_version_at, PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB},
+    image_parser::{image_bytes_parser, ulid_to_path},
+    AppState,
+};
+
+// legacy KPHIS saved local time without offset
+const LEGACY_OFFSET: UtcOffset = offset!(+7);
+// save tables and progress every `BATCH_SIZE` imported files
+const BATCH_SIZE: usize = 100;
+
+/// gallery table of imported image
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UseAt {
+    // per patient
+    First,
+    // per visit
+    Second,
+}
+
+/// a row of mapping CSV, header is `file,use_at,foreign_id,title,user,timestamp`
+#[derive(Debug, Deserialize)]
+pub struct ImportRow {
+    // relative to import directory
+    pub file: String,
+    pub use_at: UseAt,
+    pub foreign_id: u32,
+    pub title: Option<String>,
+    pub user: String,
+    // RFC3339 or `2024-12-31 23:59:59` legacy local time
+    pub timestamp: String,
+}
+
+impl ImportRow {
+    // same file can be imported into more than one gallery
+    fn progress_key(&self) -> String {
+        [format!("{:?}", self.use_at), self.foreign_id.to_string(), self.file.clone()].join("\t")
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// import photos under `dir` listed in `csv`, completed rows are recorded in `<csv>.progress`
+/// so an interrupted import continues from the last saved batch when run again
+pub async fn import(dir: &Path, csv: &Path) -> io::Result<ImportSummary> {
+    let app = AppState::load()?;
+    let mut progress_path = csv.as_os_str().to_owned();
+    progress_path.push(".progress");
+    let done = match std::fs::read_to_string(&progress_path) {
+        Ok(text) => text.lines().map(str::to_owned).collect::<HashSet<String>>(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => HashSet::new(),
+        Err(e) => return Err(e),
+    };
+    let mut progress = OpenOptions::new().create(true).append(true).open(&progress_path)?;
+    let mut reader = ::csv::ReaderBuilder::new().trim(::csv::Trim::All).from_path(csv).map_err(io::Error::other)?;
+
+    let mut summary = ImportSummary::default();
+    let mut batch = Vec::new();
+    for (line, record) in reader.deserialize::<ImportRow>().enumerate() {
+        let row = match record {
+            Ok(row) => row,
+            Err(e) => {
+                // header is line 1
+                warn!("CSV line {}: {}", line + 2, e);
+                summary.failed += 1;
+                continue;
+            }
+        };
+        let key = row.progress_key();
+        if done.contains(&key) {
+            summary.skipped += 1;
+            continue;
+        }
+        match import_row(&app, dir, &row).await {
+            Ok(()) => {
+                summary.imported += 1;
+                batch.push(key);
+            }
+            Err(e) => {
+                warn!("Cannot import '{}': {}", row.file, e);
+                summary.failed += 1;
+            }
+        }
+        if batch.len() >= BATCH_SIZE {
+            save_batch(&app, &mut progress, &mut batch)?;
+            info!("Imported {} files", summary.imported);
+        }
+    }
+    save_batch(&app, &mut progress, &mut batch)?;
+    Ok(summary)
+}
+
+// tables first, so a crash in between re-imports the batch instead of losing it
+fn save_batch(app: &AppState, progress: &mut std::fs::File, batch: &mut Vec<String>) -> io::Result<()> {
+    app.save()?;
+    for key in batch.drain(..) {
+        writeln!(progress, "{}", key)?;
+    }
+    progress.sync_data()
+}
+
+async fn import_row(app: &AppState, dir: &Path, row: &ImportRow) -> io::Result<()> {
+    let created_at = parse_timestamp(&row.timestamp)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid timestamp '{}'", row.timestamp)))?;
+    let raw_data = std::fs::read(dir.join(&row.file))?;
+    let (image, thumb) = image_bytes_parser(&raw_data).map_err(io::Error::other)?;
+    let sha256 = sha256_hex(&image);
+    let existing = find_by_sha256(&app.images.lock().unwrap(), &sha256);
+    // photo listed for several galleries is stored once
+    let image_data = match existing {
+        Some(existing) => existing,
+        None => {
+            // Ulid from original time, so file lands in folder of that time
+            let path = ulid_to_path(Ulid::from_datetime(SystemTime::from(created_at)));
+            for (prefix, data) in [(PATH_PREFIX_IMAGE, &image), (PATH_PREFIX_THUMB, &thumb)] {
+                blobs().put(&blob_key(prefix, &path), data.clone()).await?;
+                update_avif(prefix, &path, data.clone()).await;
+            }
+            let image_data = ImageData {
+                image_id: add_count(),
+                foreign_id: 0,
+                path,
+                title: row.title.clone(),
+                user: row.user.clone(),
+                position: 0,
+                annotation: None,
+                metadata: None,
+                sha256: Some(sha256),
+                thumb_sha256: Some(sha256_hex(&thumb)),
+                dhash: dhash_bytes(&image),
+            };
+            app.images.lock().unwrap().push(image_data.clone());
+            push_version_at(&mut app.image_versions.lock().unwrap(), &image_data, VersionKind::Upload, &row.user, created_at);
+            image_data
+        }
+    };
+    let table = match row.use_at {
+        UseAt::First => &app.first_table,
+        UseAt::Second => &app.second_table,
+    };
+    let mut lock = table.lock().unwrap();
+    let position = next_position(&lock, row.foreign_id);
+    lock.push(ImageData { foreign_id: row.foreign_id, position, ..image_data });
+    Ok(())
+}
+
+fn parse_timestamp(text: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(text, &Rfc3339).ok().or_else(|| {
+        PrimitiveDateTime::parse(text, format_description!("[year]-[month]-[day] [hour]:[minute]:[second]"))
+            .ok()
+            .map(|datetime| datetime.assume_offset(LEGACY_OFFSET))
+    })
+}
+
+#[cfg(test)]
+pub mod tests {
+    use time::macros::datetime;
+
+    use super::parse_timestamp;
+
+    #[test]
+    pub fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("2024-12-31 23:59:59"), Some(datetime!(2024-12-31 16:59:59 UTC)));
+        assert_eq!(parse_timestamp("2024-12-31T23:59:59Z"), Some(datetime!(2024-12-31 23:59:59 UTC)));
+        assert_eq!(parse_timestamp("31/12/2024"), None);
+    }
+}
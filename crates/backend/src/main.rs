@@ -1,78 +1,185 @@
+mod auth;
+mod avif;
+mod blob;
+mod cli;
+mod config;
+mod dicom;
+mod fhir;
 mod handlers;
+mod health;
+mod image_parser;
+mod import;
+mod maintenance;
+mod metrics;
+mod report;
 mod route;
+mod scrub;
+mod shutdown;
+mod store;
+mod tls;
 
-use axum::{handler::HandlerWithoutStateExt, http::StatusCode, Router};
+use axum::{handler::HandlerWithoutStateExt, http::StatusCode, routing::get, Router};
+use clap::Parser;
 use std::{
+    future::IntoFuture,
+    io,
     net::SocketAddr, 
+    path::Path,
     sync::{atomic::{AtomicU32, Ordering}, Arc, Mutex},
+    time::Duration,
 };
 use tower_http::services::ServeDir;
-use tracing::info;
+use tracing::{error, info, warn};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-use model::ImageData;
+use model::{ImageAnnotation, ImageData, ImageVersion};
+
+use cli::{Cli, Command};
+use config::Config;
 
 static GLOBAL_COUNT: AtomicU32 = AtomicU32::new(1);
 
 #[derive(Clone)]
 pub struct AppState {
     pub images: Arc<Mutex<Vec<ImageData>>>,
+    pub image_versions: Arc<Mutex<Vec<ImageVersion>>>,
+    pub annotations: Arc<Mutex<Vec<ImageAnnotation>>>,
     pub first_table: Arc<Mutex<Vec<ImageData>>>,
     pub second_table: Arc<Mutex<Vec<ImageData>>>,
 }
 
-impl AppState {
-    fn new() -> Self {
-        Self { 
-            images: Arc::new(Mutex::new(Vec::new())),
-            first_table: Arc::new(Mutex::new(Vec::new())),
-            second_table: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-}
-
 // return old value
 pub fn add_count() -> u32 {
     GLOBAL_COUNT.fetch_add(1, Ordering::SeqCst)
 }
 
+// next value is at least `next`
+pub fn set_count(next: u32) {
+    GLOBAL_COUNT.fetch_max(next, Ordering::SeqCst);
+}
+
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
+    // tracing is not ready yet, config has its filter
+    let config = match &cli.config {
+        Some(path) => Config::load(path, true),
+        None => Config::load(Path::new(config::DEFAULT_CONFIG_FILE), false),
+    }.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "hyper=warn,tower_http=debug,axum=trace,backend=debug".into()),
+                .unwrap_or_else(|_| config.server.log_filter.as_str().into()),
         )
         .with(tracing_subscriber::fmt::layer())
         .init();
+    let blob_store = blob::from_config(&config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    });
+    blob::init(blob_store);
+    config::init(config);
 
+    let command = cli.command.unwrap_or(Command::Serve { port: None });
+    // rows of a running server are newer than data file, and server would overwrite changes of a command
+    let _lock = command.writes().then(|| store::lock_data().unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    }));
+    let result = match command {
+        Command::Serve { port } => serve(port).await,
+        Command::HealthCheck => maintenance::health_check().await.map(|healthy| {
+            if !healthy {
+                std::process::exit(1);
+            }
+        }),
+        Command::Gc { min_age_hours, dry_run } => maintenance::gc(Duration::from_secs(min_age_hours * 3600), dry_run).await,
+        Command::Import { dir, csv } => import::import(&dir, &csv).await.map(|summary| {
+            info!("Import finished, {} imported, {} skipped, {} failed", summary.imported, summary.skipped, summary.failed);
+        }),
+        Command::Export { output } => maintenance::export(&output),
+        Command::Migrate => maintenance::migrate().await,
+        Command::Stats => maintenance::stats().await,
+        Command::Scrub { quarantine } => scrub::scrub(quarantine).await.map(|healthy| {
+            if !healthy {
+                std::process::exit(1);
+            }
+        }),
+        Command::RebuildThumbs { missing_only, from, to, jobs } => {
+            let range = maintenance::UlidRange::new(from.as_deref(), to.as_deref());
+            maintenance::rebuild_thumbs(missing_only, &range, jobs).await
+        }
+    };
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+async fn serve(port: Option<u16>) -> io::Result<()> {
     let handle_404 = handle_404.into_service();
-    let root_dir = ServeDir::new("volume/pwa")
+    let root_dir = ServeDir::new(store::volume_path("pwa", ""))
         .precompressed_br()
         .precompressed_gzip()
         // .precompressed_deflate()
         // .precompressed_zstd()
         .not_found_service(handle_404);
-    let images_dir = ServeDir::new("volume/images");
-    let thumbs_dir = ServeDir::new("volume/thumbs");
 
-    let state = AppState::new();
+    health::start();
+    let removed = shutdown::remove_temp_files();
+    if removed > 0 {
+        info!("Removed {} temporary files of interrupted writes", removed);
+    }
+    // refuse to start with empty tables when data file is unreadable
+    let state = AppState::load()?;
+    scrub::start(state.clone());
     let app = Router::new()
-        .nest("/api", route::router(state))
-        .nest_service("/images", images_dir)
-        .nest_service("/thumbs", thumbs_dir)
+        .route("/healthz", get(health::get_healthz))
+        .route("/readyz", get(health::get_readyz))
+        .route("/metrics", get(metrics::get_metrics))
+        .route("/images/{*path}", get(handlers::get_image_file))
+        .route("/thumbs/{*path}", get(handlers::get_thumb_file))
+        .with_state(state.clone())
+        .nest("/api", route::router(state.clone()))
         .fallback_service(root_dir);
-    serve_http(8088, app).await;
+    let config = config::config();
+    let mut addr = config.server.bind;
+    if let Some(port) = port {
+        addr.set_port(port);
+    }
+    let timeout = Duration::from_secs(config.server.shutdown_timeout_secs);
+    shutdown::listen();
+    match config.tls.pem_files() {
+        Some((cert, key)) => tls::serve_https(addr, app, &config.tls, cert, key, timeout).await?,
+        None => serve_http(addr, app, timeout).await?,
+    }
+
+    // requests are done or abandoned, tables are complete
+    shutdown::drain(timeout).await;
+    state.persist().await?;
+    shutdown::remove_temp_files();
+    info!("Server stopped, tables saved to {}", config.storage.data_file().display());
+    Ok(())
 }
 
-async fn serve_http(port: u16, app: Router) {
-    let http_addr = SocketAddr::from(([0, 0, 0, 0], port));
-    let listener = tokio::net::TcpListener::bind(http_addr).await.unwrap();
+/// serve until shutdown signal, then wait for in-flight requests at most `timeout`
+async fn serve_http(http_addr: SocketAddr, app: Router, timeout: Duration) -> io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(http_addr).await?;
     info!(
         "HTTP server started listening on {}, please Ctrl-c to terminate server.",
-        listener.local_addr().unwrap()
+        listener.local_addr()?
     );
-    axum::serve(listener, app).await.unwrap();
+    let server = axum::serve(listener, app).with_graceful_shutdown(shutdown::token().cancelled_owned());
+    tokio::select! {
+        result = server.into_future() => result,
+        _ = shutdown::deadline(timeout) => {
+            warn!("In-flight requests did not finish within {:?}, abandon them", timeout);
+            Ok(())
+        }
+    }
 }
 
 async fn handle_404() -> (StatusCode, &'static str) {
@@ -0,0 +1,551 @@
+use futures_util::StreamExt;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{self, Write},
+    time::{Duration, SystemTime},
+};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use imaging::dhash_bytes;
+use model::{ImageData, ImageVersion, VersionKind};
+
+use crate::{
+    avif::update_avif,
+    blob::{blob_key, blobs, sha256_hex},
+    handlers::{push_version_at, PATH_PREFIX_IMAGE, PATH_PREFIX_RESTRICTED, PATH_PREFIX_THUMB},
+    image_parser::{image_thumbnail, path_to_ulid, webp_path},
+    config::config,
+    store::{volume_path, write_file, Snapshot},
+};
+
+// in volume, read by `/metrics` of running server
+const OUTCOMES_FILE: &str = "maintenance.json";
+
+/// results of last `gc` and `health-check` commands and last scrub pass
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Outcomes {
+    #[serde(default)]
+    pub gc: Option<GcOutcome>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheckOutcome>,
+    #[serde(default)]
+    pub scrub: Option<ScrubOutcome>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct GcOutcome {
+    #[serde(with = "time::serde::rfc3339")]
+    pub finished_at: OffsetDateTime,
+    pub deleted_images: usize,
+    pub deleted_files: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct HealthCheckOutcome {
+    #[serde(with = "time::serde::rfc3339")]
+    pub finished_at: OffsetDateTime,
+    pub healthy: bool,
+    pub missing_files: usize,
+    pub missing_image_rows: usize,
+    pub orphan_files: usize,
+    #[serde(default)]
+    pub hash_mismatches: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ScrubOutcome {
+    #[serde(with = "time::serde::rfc3339")]
+    pub finished_at: OffsetDateTime,
+    pub checked: usize,
+    // `images/01J/G0/M004KYHATX7J2W7MB28X4.webp: checksum mismatch`
+    pub corrupt_files: Vec<String>,
+}
+
+impl Outcomes {
+    /// empty when no command has run yet or file is unreadable
+    pub fn load() -> Self {
+        std::fs::read(volume_path("", OUTCOMES_FILE)).ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    }
+
+    /// keep outcome of the other commands
+    pub fn record(update: impl FnOnce(&mut Self)) {
+        let mut outcomes = Self::load();
+        update(&mut outcomes);
+        let result = serde_json::to_vec(&outcomes).map_err(io::Error::other)
+            .and_then(|data| write_file(&volume_path("", OUTCOMES_FILE), &data));
+        if let Err(e) = result {
+            warn!("Cannot record outcome to {}: {}", OUTCOMES_FILE, e);
+        }
+    }
+}
+
+/// `images`, `thumbs`, `restricted/images` and `restricted/thumbs`
+pub fn file_prefixes() -> [String; 4] {
+    [
+        PATH_PREFIX_IMAGE.to_owned(),
+        PATH_PREFIX_THUMB.to_owned(),
+        [PATH_PREFIX_RESTRICTED, "/", PATH_PREFIX_IMAGE].concat(),
+        [PATH_PREFIX_RESTRICTED, "/", PATH_PREFIX_THUMB].concat(),
+    ]
+}
+
+/// (prefix, path) of every file the tables refer to with its SHA-256 when known,
+/// restricted versions live under `restricted/`
+pub fn checksums(images: &[ImageData], versions: &[ImageVersion]) -> BTreeMap<(String, String), Option<String>> {
+    let [image, thumb, restricted_image, restricted_thumb] = file_prefixes();
+    let mut files = BTreeMap::new();
+    let mut insert = |prefix: &String, path: &String, sha256: &Option<String>| {
+        let known = files.entry((prefix.clone(), path.clone())).or_insert(None);
+        if known.is_none() {
+            known.clone_from(sha256);
+        }
+    };
+    for version in versions {
+        let (image, thumb) = if version.restricted { (&restricted_image, &restricted_thumb) } else { (&image, &thumb) };
+        insert(image, &version.path, &version.sha256);
+        insert(thumb, &version.path, &version.thumb_sha256);
+    }
+    for data in images {
+        insert(&image, &data.path, &data.sha256);
+        insert(&thumb, &data.path, &data.thumb_sha256);
+    }
+    files
+}
+
+/// set checksums of rows and versions from (prefix, path) of their files
+fn set_checksums(snapshot: &mut Snapshot, hashes: &HashMap<(String, String), String>) {
+    let [image, thumb, restricted_image, restricted_thumb] = file_prefixes();
+    let hash = |prefix: &String, path: &String| hashes.get(&(prefix.clone(), path.clone())).cloned();
+    for version in snapshot.image_versions.iter_mut() {
+        let (image, thumb) = if version.restricted { (&restricted_image, &restricted_thumb) } else { (&image, &thumb) };
+        version.sha256 = hash(image, &version.path).or(version.sha256.take());
+        version.thumb_sha256 = hash(thumb, &version.path).or(version.thumb_sha256.take());
+    }
+    for data in snapshot.images.iter_mut() {
+        data.sha256 = hash(&image, &data.path).or(data.sha256.take());
+        data.thumb_sha256 = hash(&thumb, &data.path).or(data.thumb_sha256.take());
+    }
+}
+
+/// (prefix, path) of every file the tables refer to
+fn referenced_files(snapshot: &Snapshot) -> HashSet<(String, String)> {
+    checksums(&snapshot.images, &snapshot.image_versions).into_keys().collect()
+}
+
+/// (prefix, path) of every file in blob store to its size
+async fn stored_files() -> io::Result<BTreeMap<(String, String), u64>> {
+    let mut files = BTreeMap::new();
+    for prefix in file_prefixes() {
+        let dir = [prefix.as_str(), "/"].concat();
+        for blob in blobs().list(&prefix).await? {
+            if let Some(path) = blob.key.strip_prefix(&dir) {
+                files.insert((prefix.clone(), path.to_owned()), blob.size);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// stored files which are not referenced by tables, with their prefix,
+/// AVIF copy is referenced with its webp file
+fn orphan_files(snapshot: &Snapshot, stored: &BTreeMap<(String, String), u64>) -> Vec<(String, String)> {
+    let referenced = referenced_files(snapshot);
+    stored.keys()
+        .filter(|(prefix, path)| {
+            let path = webp_path(path).unwrap_or_else(|| path.clone());
+            !referenced.contains(&(prefix.clone(), path))
+        })
+        .cloned()
+        .collect()
+}
+
+/// (prefix, path) of files whose content differs from checksum of their row or version,
+/// unreadable file is a mismatch
+async fn hash_mismatches(snapshot: &Snapshot, stored: &BTreeMap<(String, String), u64>) -> Vec<(String, String)> {
+    let mut mismatches = Vec::new();
+    for (key, sha256) in checksums(&snapshot.images, &snapshot.image_versions) {
+        // missing file is reported as missing
+        let Some(sha256) = sha256.filter(|_| stored.contains_key(&key)) else {
+            continue;
+        };
+        match blobs().get(&blob_key(&key.0, &key.1)).await {
+            Ok(data) if sha256_hex(&data) == sha256 => {}
+            _ => mismatches.push(key),
+        }
+    }
+    mismatches
+}
+
+/// compare tables with files, return `false` when a referenced file or image row is missing or image file changed
+pub async fn health_check() -> io::Result<bool> {
+    let snapshot = Snapshot::load()?;
+    let stored = stored_files().await?;
+    let mut missing = referenced_files(&snapshot).into_iter()
+        .filter(|key| !stored.contains_key(key))
+        .collect::<Vec<(String, String)>>();
+    missing.sort();
+    for (prefix, path) in &missing {
+        println!("missing file: {}/{}", prefix, path);
+    }
+    let dangling = snapshot.first_table.iter()
+        .chain(snapshot.second_table.iter())
+        .filter(|row| !snapshot.images.contains(row))
+        .collect::<Vec<&ImageData>>();
+    for row in &dangling {
+        println!("missing image row: image_id {} of gallery {}", row.image_id, row.foreign_id);
+    }
+    let orphans = orphan_files(&snapshot, &stored);
+    for (prefix, path) in &orphans {
+        println!("orphan file: {}/{}", prefix, path);
+    }
+    let mismatches = hash_mismatches(&snapshot, &stored).await;
+    for (prefix, path) in &mismatches {
+        println!("hash mismatch: {}/{}", prefix, path);
+    }
+    println!(
+        "{} missing files, {} missing image rows, {} orphan files, {} hash mismatches",
+        missing.len(), dangling.len(), orphans.len(), mismatches.len(),
+    );
+    let healthy = missing.is_empty() && dangling.is_empty() && mismatches.is_empty();
+    Outcomes::record(|outcomes| outcomes.health_check = Some(HealthCheckOutcome {
+        finished_at: OffsetDateTime::now_utc(),
+        healthy,
+        missing_files: missing.len(),
+        missing_image_rows: dangling.len(),
+        orphan_files: orphans.len(),
+        hash_mismatches: mismatches.len(),
+    }));
+    Ok(healthy)
+}
+
+/// images used by no gallery, and created before `cutoff` so fresh uploads waiting for `post_first` are kept
+fn unused_image_ids(snapshot: &Snapshot, cutoff: SystemTime) -> Vec<u32> {
+    let used = snapshot.first_table.iter()
+        .chain(snapshot.second_table.iter())
+        .map(|row| row.image_id)
+        .collect::<HashSet<u32>>();
+    snapshot.images.iter()
+        .filter(|image| !used.contains(&image.image_id))
+        .filter(|image| path_to_ulid(&image.path).is_some_and(|ulid| ulid.datetime() < cutoff))
+        .map(|image| image.image_id)
+        .collect()
+}
+
+/// delete unused images with all their versions and files, then orphan files older than `min_age`
+pub async fn gc(min_age: Duration, dry_run: bool) -> io::Result<()> {
+    let mut snapshot = Snapshot::load()?;
+    let cutoff = SystemTime::now() - min_age;
+    let unused = unused_image_ids(&snapshot, cutoff);
+    snapshot.images.retain(|image| !unused.contains(&image.image_id));
+    snapshot.image_versions.retain(|version| !unused.contains(&version.image_id));
+    snapshot.annotations.retain(|annotation| !unused.contains(&annotation.image_id));
+
+    // files of removed rows are orphans now, files without Ulid name are left alone
+    let stored = stored_files().await?;
+    let orphans = orphan_files(&snapshot, &stored).into_iter()
+        .filter(|(_, path)| path_to_ulid(path).is_some_and(|ulid| ulid.datetime() < cutoff))
+        .collect::<Vec<(String, String)>>();
+    let mut deleted_files = 0;
+    for (prefix, path) in &orphans {
+        if dry_run {
+            println!("would delete {}/{}", prefix, path);
+        } else {
+            match blobs().delete(&blob_key(prefix, path)).await {
+                Ok(()) => deleted_files += 1,
+                Err(e) => warn!("Cannot delete {}/{}: {}", prefix, path, e),
+            }
+        }
+    }
+    if dry_run {
+        println!("would delete {} unused images and {} files", unused.len(), orphans.len());
+    } else {
+        snapshot.save()?;
+        info!("Deleted {} unused images and {} files", unused.len(), deleted_files);
+        Outcomes::record(|outcomes| outcomes.gc = Some(GcOutcome {
+            finished_at: OffsetDateTime::now_utc(),
+            deleted_images: unused.len(),
+            deleted_files,
+        }));
+    }
+    Ok(())
+}
+
+/// write tables as JSON to `output`, `-` is stdout
+pub fn export(output: &str) -> io::Result<()> {
+    let snapshot = Snapshot::load()?;
+    let data = serde_json::to_vec_pretty(&snapshot).map_err(io::Error::other)?;
+    if output == "-" {
+        io::stdout().write_all(&data)
+    } else {
+        std::fs::write(output, data)
+    }
+}
+
+/// bring tables saved by older versions up to date, then save with current schema
+pub async fn migrate() -> io::Result<()> {
+    let mut snapshot = Snapshot::load()?;
+    // images from before versioning get an upload version at time of their Ulid
+    let versioned = snapshot.image_versions.iter().map(|version| version.image_id).collect::<HashSet<u32>>();
+    let mut backfilled = 0;
+    for image in snapshot.images.iter().filter(|image| !versioned.contains(&image.image_id)) {
+        let created_at = path_to_ulid(&image.path)
+            .map(|ulid| OffsetDateTime::from(ulid.datetime()))
+            .unwrap_or(OffsetDateTime::UNIX_EPOCH);
+        push_version_at(&mut snapshot.image_versions, image, VersionKind::Upload, &image.user, created_at);
+        backfilled += 1;
+    }
+    // rows from before ordering all have position 0, number them in stored order
+    let renumbered = renumber_positions(&mut snapshot.first_table) + renumber_positions(&mut snapshot.second_table);
+    // files from before checksums get them from current content, so identical uploads and scrubber find them
+    let mut hashes = HashMap::new();
+    let mut hashed = 0;
+    for ((prefix, path), sha256) in checksums(&snapshot.images, &snapshot.image_versions) {
+        if let Some(sha256) = sha256 {
+            hashes.insert((prefix, path), sha256);
+            continue;
+        }
+        match blobs().get(&blob_key(&prefix, &path)).await {
+            Ok(data) => {
+                hashes.insert((prefix, path), sha256_hex(&data));
+                hashed += 1;
+            }
+            Err(e) => warn!("Cannot hash {}/{}: {}", prefix, path, e),
+        }
+    }
+    set_checksums(&mut snapshot, &hashes);
+    // perceptual hash for near-duplicate search
+    for image in snapshot.images.iter_mut().filter(|image| image.dhash.is_none()) {
+        match blobs().get(&blob_key(PATH_PREFIX_IMAGE, &image.path)).await {
+            Ok(data) => image.dhash = dhash_bytes(&data),
+            Err(e) => warn!("Cannot hash {}/{}: {}", PATH_PREFIX_IMAGE, image.path, e),
+        }
+    }
+    snapshot.save()?;
+    info!(
+        "Migrated {}, {} versions added, {} galleries renumbered, {} files hashed",
+        config().storage.data_file().display(), backfilled, renumbered, hashed,
+    );
+    Ok(())
+}
+
+/// renumber galleries with duplicate positions, return number of renumbered galleries
+fn renumber_positions(rows: &mut [ImageData]) -> usize {
+    let mut positions = HashMap::<u32, Vec<u32>>::new();
+    for row in rows.iter() {
+        positions.entry(row.foreign_id).or_default().push(row.position);
+    }
+    let broken = positions.into_iter()
+        .filter(|(_, positions)| positions.len() != positions.iter().collect::<HashSet<&u32>>().len())
+        .map(|(foreign_id, _)| foreign_id)
+        .collect::<Vec<u32>>();
+    for foreign_id in &broken {
+        // stable sort keeps stored order of equal positions
+        let mut indexes = (0..rows.len()).filter(|i| rows[*i].foreign_id == *foreign_id).collect::<Vec<usize>>();
+        indexes.sort_by_key(|i| rows[*i].position);
+        for (position, i) in indexes.into_iter().enumerate() {
+            rows[i].position = position as u32;
+        }
+    }
+    broken.len()
+}
+
+/// count rows and files
+pub async fn stats() -> io::Result<()> {
+    let snapshot = Snapshot::load()?;
+    println!("images: {}", snapshot.images.len());
+    println!("image versions: {}", snapshot.image_versions.len());
+    println!("restricted versions: {}", snapshot.image_versions.iter().filter(|v| v.restricted).count());
+    println!("annotations: {}", snapshot.annotations.len());
+    for (name, rows) in [("first", &snapshot.first_table), ("second", &snapshot.second_table)] {
+        let galleries = rows.iter().map(|row| row.foreign_id).collect::<HashSet<u32>>();
+        println!("{} table: {} rows in {} galleries", name, rows.len(), galleries.len());
+    }
+    let stored = stored_files().await?;
+    for prefix in file_prefixes() {
+        let sizes = stored.iter()
+            .filter(|((file_prefix, _), _)| *file_prefix == prefix)
+            .map(|(_, size)| *size)
+            .collect::<Vec<u64>>();
+        let bytes = sizes.iter().sum::<u64>();
+        println!("{}: {} files, {:.1} MB", prefix, sizes.len(), bytes as f64 / 1_000_000.0);
+    }
+    Ok(())
+}
+
+/// Ulid prefixes, both inclusive, `01J` to `01JG` or `01J/G0`, `None` is open end
+#[derive(Debug, Default, Clone)]
+pub struct UlidRange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl UlidRange {
+    pub fn new(from: Option<&str>, to: Option<&str>) -> Self {
+        let normalize = |prefix: &str| prefix.replace('/', "").to_uppercase();
+        Self { from: from.map(normalize), to: to.map(normalize) }
+    }
+
+    /// Ulid of stored file `path` is in range, prefix of Ulid compared with `to`
+    pub fn contains(&self, path: &str) -> bool {
+        let Some(ulid) = path_to_ulid(path) else {
+            return false;
+        };
+        let ulid = ulid.to_string();
+        self.from.as_ref().is_none_or(|from| ulid.as_str() >= from.as_str())
+            && self.to.as_ref().is_none_or(|to| &ulid[..to.len().min(ulid.len())] <= to.as_str())
+    }
+}
+
+/// create thumbnails again from image files of `range`, ex. after thumbnail size changed, and update their checksums,
+/// `jobs` thumbnails are created at the same time
+pub async fn rebuild_thumbs(missing_only: bool, range: &UlidRange, jobs: usize) -> io::Result<()> {
+    let mut snapshot = Snapshot::load()?;
+    let stored = if missing_only { stored_files().await? } else { BTreeMap::new() };
+    let [image_prefix, thumb_prefix, restricted_image, restricted_thumb] = file_prefixes();
+    let mut sources = referenced_files(&snapshot).into_iter()
+        .filter(|(_, path)| range.contains(path))
+        .filter_map(|(prefix, path)| {
+            if prefix == image_prefix {
+                Some((image_prefix.clone(), thumb_prefix.clone(), path))
+            } else if prefix == restricted_image {
+                Some((restricted_image.clone(), restricted_thumb.clone(), path))
+            } else {
+                None
+            }
+        })
+        .filter(|(_, thumb_prefix, path)| !stored.contains_key(&(thumb_prefix.clone(), path.clone())))
+        .collect::<Vec<(String, String, String)>>();
+    sources.sort();
+    info!("Rebuilding {} thumbnails, {} at a time", sources.len(), jobs);
+    let results = futures_util::stream::iter(sources)
+        .map(|(image_prefix, thumb_prefix, path)| async move {
+            let result = rebuild_thumb(&image_prefix, &thumb_prefix, &path).await;
+            (image_prefix, thumb_prefix, path, result)
+        })
+        .buffer_unordered(jobs.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    let (mut rebuilt, mut failed) = (0, 0);
+    let mut hashes = HashMap::new();
+    for (image_prefix, thumb_prefix, path, result) in results {
+        match result {
+            Ok(sha256) => {
+                hashes.insert((thumb_prefix, path), sha256);
+                rebuilt += 1;
+            }
+            Err(e) => {
+                warn!("Cannot rebuild thumbnail of {}/{}: {}", image_prefix, path, e);
+                failed += 1;
+            }
+        }
+    }
+    if rebuilt > 0 {
+        set_checksums(&mut snapshot, &hashes);
+        snapshot.save()?;
+    }
+    info!("Rebuilt {} thumbnails, {} failed", rebuilt, failed);
+    Ok(())
+}
+
+/// write thumbnail of one image file, return its SHA-256
+async fn rebuild_thumb(image_prefix: &str, thumb_prefix: &str, path: &str) -> io::Result<String> {
+    let data = blobs().get(&blob_key(image_prefix, path)).await?;
+    let thumb = tokio::task::spawn_blocking(move || {
+        image::load_from_memory(&data).and_then(|image| image_thumbnail(&image))
+    })
+        .await
+        .map_err(io::Error::other)?
+        .map_err(io::Error::other)?;
+    let sha256 = sha256_hex(&thumb);
+    blobs().put(&blob_key(thumb_prefix, path), thumb.clone()).await?;
+    update_avif(thumb_prefix, path, thumb).await;
+    Ok(sha256)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::time::{Duration, SystemTime};
+    use time::OffsetDateTime;
+
+    use model::{ImageData, ImageVersion, VersionKind};
+
+    use crate::store::Snapshot;
+
+    use super::{orphan_files, referenced_files, renumber_positions, unused_image_ids, UlidRange};
+
+    fn image(image_id: u32, foreign_id: u32, path: &str) -> ImageData {
+        ImageData {
+            image_id,
+            foreign_id,
+            path: path.to_owned(),
+            title: None,
+            user: String::from("user"),
+            position: 0,
+            annotation: None,
+            metadata: None,
+            sha256: None,
+            thumb_sha256: None,
+            dhash: None,
+        }
+    }
+
+    #[test]
+    pub fn test_gc() {
+        // 2019 and 2024
+        let old = "01D/53/A9KW092VPC6N5411E2NX9.webp";
+        let new = "01J/G0/M004KYHATX7J2W7MB28X4.webp";
+        let snapshot = Snapshot {
+            images: vec![image(1, 0, old), image(2, 0, old), image(3, 0, new)],
+            image_versions: vec![ImageVersion {
+                image_id: 1,
+                version: 1,
+                kind: VersionKind::Upload,
+                path: String::from("01D/53/A9KW092VPC6N5411E2NX8.webp"),
+                title: None,
+                user: String::from("user"),
+                created_at: OffsetDateTime::UNIX_EPOCH,
+                restricted: true,
+                sha256: None,
+                thumb_sha256: None,
+                dhash: None,
+            }],
+            first_table: vec![image(2, 1, old)],
+            ..Default::default()
+        };
+        let cutoff = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(unused_image_ids(&snapshot, cutoff), vec![1]);
+        let files = referenced_files(&snapshot);
+        assert!(files.contains(&(String::from("restricted/thumbs"), String::from("01D/53/A9KW092VPC6N5411E2NX8.webp"))));
+        assert_eq!(files.len(), 6);
+        let stored = [
+            ("images", "01J/G0/M004KYHATX7J2W7MB28X4.avif"),
+            ("images", "01J/G0/M004KYHATX7J2W7MB28X5.avif"),
+            ("thumbs", new),
+        ].map(|(prefix, path)| ((prefix.to_owned(), path.to_owned()), 0)).into();
+        assert_eq!(orphan_files(&snapshot, &stored), vec![(String::from("images"), String::from("01J/G0/M004KYHATX7J2W7MB28X5.avif"))]);
+    }
+
+    #[test]
+    pub fn test_renumber_positions() {
+        let mut rows = vec![image(1, 1, "a"), image(2, 2, "b"), image(3, 1, "c"), image(4, 2, "d")];
+        rows[3].position = 1;
+        assert_eq!(renumber_positions(&mut rows), 1);
+        assert_eq!(rows.iter().map(|row| row.position).collect::<Vec<u32>>(), vec![0, 0, 1, 1]);
+    }
+
+    #[test]
+    pub fn test_ulid_range() {
+        let path = "01J/G0/M004KYHATX7J2W7MB28X4.webp";
+        assert!(UlidRange::default().contains(path));
+        assert!(UlidRange::new(Some("01j/g0"), Some("01J/G0")).contains(path));
+        assert!(UlidRange::new(Some("01H"), Some("01J")).contains(path));
+        assert!(UlidRange::new(None, Some("01JG0M004KYHATX7J2W7MB28X4")).contains(path));
+        assert!(!UlidRange::new(Some("01JG1"), None).contains(path));
+        assert!(!UlidRange::new(None, Some("01H")).contains(path));
+        assert!(!UlidRange::default().contains("not a ulid"));
+    }
+}
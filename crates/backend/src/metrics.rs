@@ -0,0 +1,218 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        LazyLock, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    blob::blobs,
+    handlers::{PATH_PREFIX_IMAGE, PATH_PREFIX_THUMB},
+    maintenance::{file_prefixes, Outcomes},
+    AppState,
+};
+
+// seconds
+const LATENCY_BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+// walking whole volume is slow, scrape more often than this gets cached sizes
+const STORAGE_CACHE_TTL: Duration = Duration::from_secs(300);
+// label of requests which match no route, keeps label values bounded
+const UNMATCHED_ROUTE: &str = "unmatched";
+
+#[derive(Default)]
+struct Histogram {
+    // count of each bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+// (method, route) to latency
+type Latencies = BTreeMap<(String, String), Histogram>;
+// (method, route, status) to count
+type Requests = BTreeMap<(String, String, u16), u64>;
+static LATENCY: LazyLock<Mutex<Latencies>> = LazyLock::new(Default::default);
+static REQUESTS: LazyLock<Mutex<Requests>> = LazyLock::new(Default::default);
+static UPLOADED_IMAGE_BYTES: AtomicU64 = AtomicU64::new(0);
+static UPLOADED_THUMB_BYTES: AtomicU64 = AtomicU64::new(0);
+// (prefix, top directory) to (files, bytes)
+type StorageSizes = BTreeMap<(String, String), (u64, u64)>;
+static STORAGE: LazyLock<tokio::sync::Mutex<Option<(Instant, StorageSizes)>>> = LazyLock::new(Default::default);
+
+/// middleware of `route.rs`, count requests and latency by matched route
+pub async fn track_request(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request.extensions().get::<MatchedPath>()
+        .map(|path| path.as_str().to_owned())
+        .unwrap_or(String::from(UNMATCHED_ROUTE));
+    let start = Instant::now();
+    let response = next.run(request).await;
+    let seconds = start.elapsed().as_secs_f64();
+    let status = response.status().as_u16();
+    *REQUESTS.lock().unwrap().entry((method.clone(), route.clone(), status)).or_default() += 1;
+    LATENCY.lock().unwrap().entry((method, route)).or_default().observe(seconds);
+    response
+}
+
+/// count bytes of image or thumbnail written by upload, edit or FHIR
+pub fn add_uploaded_bytes(prefix: &str, bytes: usize) {
+    let counter = match prefix {
+        PATH_PREFIX_IMAGE => &UPLOADED_IMAGE_BYTES,
+        PATH_PREFIX_THUMB => &UPLOADED_THUMB_BYTES,
+        _ => return,
+    };
+    counter.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+async fn storage_sizes() -> StorageSizes {
+    // held while listing, so concurrent scrapes wait for one listing
+    let mut cache = STORAGE.lock().await;
+    if let Some((at, sizes)) = cache.as_ref() {
+        if at.elapsed() < STORAGE_CACHE_TTL {
+            return sizes.clone();
+        }
+    }
+    let mut sizes = StorageSizes::new();
+    for prefix in file_prefixes() {
+        let blob_dir = [prefix.as_str(), "/"].concat();
+        for blob in blobs().list(&prefix).await.unwrap_or_default() {
+            // `01J` of `images/01J/G0/M004KYHATX7J2W7MB28X4.webp`, a directory per 397 days
+            let Some((dir, _)) = blob.key.strip_prefix(&blob_dir).and_then(|path| path.split_once('/')) else {
+                continue;
+            };
+            let size = sizes.entry((prefix.clone(), dir.to_owned())).or_default();
+            size.0 += 1;
+            size.1 += blob.size;
+        }
+    }
+    *cache = Some((Instant::now(), sizes.clone()));
+    sizes
+}
+
+/// `GET /metrics` in Prometheus text format
+pub async fn get_metrics(State(app): State<AppState>) -> Response<Body> {
+    let rows = [
+        ("images", app.images.lock().unwrap().len()),
+        ("image_versions", app.image_versions.lock().unwrap().len()),
+        ("annotations", app.annotations.lock().unwrap().len()),
+        ("first_table", app.first_table.lock().unwrap().len()),
+        ("second_table", app.second_table.lock().unwrap().len()),
+    ];
+    let storage = storage_sizes().await;
+    let outcomes = Outcomes::load();
+    Response::builder()
+        .header(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")
+        .body(Body::from(render(&rows, &storage, &outcomes)))
+        .unwrap()
+}
+
+fn render(rows: &[(&str, usize)], storage: &StorageSizes, outcomes: &Outcomes) -> String {
+    let mut out = String::new();
+    out.push_str("# HELP http_requests_total HTTP requests by route and status.\n# TYPE http_requests_total counter\n");
+    for ((method, route, status), count) in REQUESTS.lock().unwrap().iter() {
+        let _ = writeln!(out, "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}", method, escape(route), status, count);
+    }
+    out.push_str("# HELP http_request_duration_seconds HTTP request latency by route.\n# TYPE http_request_duration_seconds histogram\n");
+    for ((method, route), histogram) in LATENCY.lock().unwrap().iter() {
+        let labels = format!("method=\"{}\",route=\"{}\"", method, escape(route));
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, bound, cumulative);
+        }
+        let _ = writeln!(out, "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count);
+        let _ = writeln!(out, "http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+        let _ = writeln!(out, "http_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+    }
+    out.push_str("# HELP kphis_uploaded_bytes_total Bytes of renditions written by upload, edit and FHIR.\n# TYPE kphis_uploaded_bytes_total counter\n");
+    for (rendition, counter) in [("image", &UPLOADED_IMAGE_BYTES), ("thumb", &UPLOADED_THUMB_BYTES)] {
+        let _ = writeln!(out, "kphis_uploaded_bytes_total{{rendition=\"{}\"}} {}", rendition, counter.load(Ordering::Relaxed));
+    }
+    out.push_str("# HELP kphis_table_rows Rows of each table, first_table and second_table are usage rows.\n# TYPE kphis_table_rows gauge\n");
+    for (table, count) in rows {
+        let _ = writeln!(out, "kphis_table_rows{{table=\"{}\"}} {}", table, count);
+    }
+    out.push_str("# HELP kphis_storage_bytes Bytes of files per top-level Ulid directory.\n# TYPE kphis_storage_bytes gauge\n");
+    for ((prefix, dir), (_, bytes)) in storage {
+        let _ = writeln!(out, "kphis_storage_bytes{{prefix=\"{}\",dir=\"{}\"}} {}", prefix, escape(dir), bytes);
+    }
+    out.push_str("# HELP kphis_storage_files Files per top-level Ulid directory.\n# TYPE kphis_storage_files gauge\n");
+    for ((prefix, dir), (files, _)) in storage {
+        let _ = writeln!(out, "kphis_storage_files{{prefix=\"{}\",dir=\"{}\"}} {}", prefix, escape(dir), files);
+    }
+    if let Some(gc) = &outcomes.gc {
+        out.push_str("# HELP kphis_gc_last_run_timestamp_seconds Finish time of last gc command.\n# TYPE kphis_gc_last_run_timestamp_seconds gauge\n");
+        let _ = writeln!(out, "kphis_gc_last_run_timestamp_seconds {}", gc.finished_at.unix_timestamp());
+        out.push_str("# HELP kphis_gc_deleted Images and files deleted by last gc command.\n# TYPE kphis_gc_deleted gauge\n");
+        let _ = writeln!(out, "kphis_gc_deleted{{kind=\"images\"}} {}", gc.deleted_images);
+        let _ = writeln!(out, "kphis_gc_deleted{{kind=\"files\"}} {}", gc.deleted_files);
+    }
+    if let Some(check) = &outcomes.health_check {
+        out.push_str("# HELP kphis_health_check_last_run_timestamp_seconds Finish time of last health-check command.\n# TYPE kphis_health_check_last_run_timestamp_seconds gauge\n");
+        let _ = writeln!(out, "kphis_health_check_last_run_timestamp_seconds {}", check.finished_at.unix_timestamp());
+        out.push_str("# HELP kphis_health_check_healthy 1 when last health-check found no missing file, image row or hash mismatch.\n# TYPE kphis_health_check_healthy gauge\n");
+        let _ = writeln!(out, "kphis_health_check_healthy {}", u8::from(check.healthy));
+        out.push_str("# HELP kphis_health_check_problems Problems found by last health-check command.\n# TYPE kphis_health_check_problems gauge\n");
+        let _ = writeln!(out, "kphis_health_check_problems{{kind=\"missing_files\"}} {}", check.missing_files);
+        let _ = writeln!(out, "kphis_health_check_problems{{kind=\"missing_image_rows\"}} {}", check.missing_image_rows);
+        let _ = writeln!(out, "kphis_health_check_problems{{kind=\"orphan_files\"}} {}", check.orphan_files);
+        let _ = writeln!(out, "kphis_health_check_problems{{kind=\"hash_mismatches\"}} {}", check.hash_mismatches);
+    }
+    if let Some(scrub) = &outcomes.scrub {
+        out.push_str("# HELP kphis_scrub_last_pass_timestamp_seconds Finish time of last full scrub pass.\n# TYPE kphis_scrub_last_pass_timestamp_seconds gauge\n");
+        let _ = writeln!(out, "kphis_scrub_last_pass_timestamp_seconds {}", scrub.finished_at.unix_timestamp());
+        out.push_str("# HELP kphis_scrub_files Files verified and found corrupt by last full scrub pass.\n# TYPE kphis_scrub_files gauge\n");
+        let _ = writeln!(out, "kphis_scrub_files{{result=\"checked\"}} {}", scrub.checked);
+        let _ = writeln!(out, "kphis_scrub_files{{result=\"corrupt\"}} {}", scrub.corrupt_files.len());
+    }
+    out
+}
+
+/// label value escaping of Prometheus text format
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+pub mod tests {
+    use crate::maintenance::Outcomes;
+
+    use super::{escape, render, Histogram, StorageSizes};
+
+    #[test]
+    pub fn test_render_metrics() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.003);
+        histogram.observe(0.2);
+        histogram.observe(100.0);
+        assert_eq!((histogram.buckets[0], histogram.buckets[5], histogram.count), (1, 1, 3));
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+
+        let mut storage = StorageSizes::new();
+        storage.insert((String::from("images"), String::from("01J")), (2, 2048));
+        let text = render(&[("images", 2)], &storage, &Outcomes::default());
+        assert!(text.contains("kphis_table_rows{table=\"images\"} 2\n"));
+        assert!(text.contains("kphis_storage_bytes{prefix=\"images\",dir=\"01J\"} 2048\n"));
+        assert!(!text.contains("kphis_gc_"));
+    }
+}
@@ -0,0 +1,158 @@
+use printpdf::{
+    BuiltinFont, ColorBits, ColorSpace, Image, ImageFilter, ImageTransform, ImageXObject, IndirectFontRef, Mm,
+    PdfDocument, PdfLayerReference, Px,
+};
+
+// A4 portrait
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 12.0;
+const HEADER_HEIGHT: f32 = 14.0;
+const CAPTION_HEIGHT: f32 = 10.0;
+const CELL_PADDING: f32 = 2.0;
+const TITLE_SIZE: f32 = 9.0;
+const DETAIL_SIZE: f32 = 7.0;
+const HEADER_SIZE: f32 = 11.0;
+const MAX_GRID: u32 = 6;
+
+/// images per page, `columns` x `rows`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReportGrid {
+    pub columns: u32,
+    pub rows: u32,
+}
+
+impl ReportGrid {
+    /// default 2x3, at most 6x6
+    pub fn new(columns: Option<u32>, rows: Option<u32>) -> Self {
+        Self {
+            columns: columns.unwrap_or(2).clamp(1, MAX_GRID),
+            rows: rows.unwrap_or(3).clamp(1, MAX_GRID),
+        }
+    }
+
+    fn per_page(&self) -> usize {
+        (self.columns * self.rows) as usize
+    }
+
+    /// (x, y) of bottom-left corner of cell `index` within page, and (width, height) of cell, in mm
+    fn cell(&self, index: usize) -> ((f32, f32), (f32, f32)) {
+        let width = (PAGE_WIDTH - 2.0 * MARGIN) / self.columns as f32;
+        let height = (PAGE_HEIGHT - 2.0 * MARGIN - HEADER_HEIGHT) / self.rows as f32;
+        let column = (index % self.columns as usize) as f32;
+        let row = (index / self.columns as usize) as f32;
+        let x = MARGIN + column * width;
+        let y = PAGE_HEIGHT - MARGIN - HEADER_HEIGHT - (row + 1.0) * height;
+        ((x, y), (width, height))
+    }
+}
+
+/// an image of report, `jpeg` is baseline jpeg of stored webp
+pub struct ReportImage {
+    pub title: Option<String>,
+    pub user: String,
+    pub created: String,
+    pub width: u32,
+    pub height: u32,
+    pub jpeg: Vec<u8>,
+}
+
+/// paginated PDF of images with captions, `font` is TrueType font for non-latin text like Thai,
+/// without `font` the PDF uses builtin Helvetica
+pub fn gallery_pdf(heading: &str, printed: &str, images: Vec<ReportImage>, grid: ReportGrid, font: Option<Vec<u8>>) -> Result<Vec<u8>, String> {
+    let (doc, first_page, first_layer) = PdfDocument::new(heading, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "images");
+    let font = match font {
+        Some(data) => doc.add_external_font(data.as_slice()),
+        None => doc.add_builtin_font(BuiltinFont::Helvetica),
+    }.map_err(|e| e.to_string())?;
+    let pages = images.len().div_ceil(grid.per_page()).max(1);
+    let mut images = images.into_iter();
+    for page in 0..pages {
+        let layer = if page == 0 {
+            doc.get_page(first_page).get_layer(first_layer)
+        } else {
+            let (page, layer) = doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "images");
+            doc.get_page(page).get_layer(layer)
+        };
+        let header_y = PAGE_HEIGHT - MARGIN - HEADER_SIZE * 0.3528;
+        layer.use_text(heading, HEADER_SIZE, Mm(MARGIN), Mm(header_y), &font);
+        let page_text = [printed, "  ", &(page + 1).to_string(), "/", &pages.to_string()].concat();
+        layer.use_text(page_text, DETAIL_SIZE, Mm(MARGIN), Mm(header_y - 6.0), &font);
+
+        for (index, image) in images.by_ref().take(grid.per_page()).enumerate() {
+            add_cell(&layer, &font, grid.cell(index), image);
+        }
+    }
+    doc.save_to_bytes().map_err(|e| e.to_string())
+}
+
+fn add_cell(
+    layer: &PdfLayerReference,
+    font: &IndirectFontRef,
+    ((x, y), (width, height)): ((f32, f32), (f32, f32)),
+    image: ReportImage,
+) {
+    // fit image into cell above caption, keep aspect ratio
+    let box_w = width - 2.0 * CELL_PADDING;
+    let box_h = height - CAPTION_HEIGHT - 2.0 * CELL_PADDING;
+    let ratio = (box_w / image.width as f32).min(box_h / image.height as f32);
+    let image_w = image.width as f32 * ratio;
+    let image_h = image.height as f32 * ratio;
+    let image_x = x + CELL_PADDING + (box_w - image_w) / 2.0;
+    let image_y = y + CAPTION_HEIGHT + CELL_PADDING + (box_h - image_h) / 2.0;
+    // 1 px is 1/300 inch by default
+    let natural_w = image.width as f32 * 25.4 / 300.0;
+    let natural_h = image.height as f32 * 25.4 / 300.0;
+    Image::from(ImageXObject {
+        width: Px(image.width as usize),
+        height: Px(image.height as usize),
+        color_space: ColorSpace::Rgb,
+        bits_per_component: ColorBits::Bit8,
+        interpolate: true,
+        image_data: image.jpeg,
+        image_filter: Some(ImageFilter::DCT),
+        smask: None,
+        clipping_bbox: None,
+    }).add_to_layer(layer.clone(), ImageTransform {
+        translate_x: Some(Mm(image_x)),
+        translate_y: Some(Mm(image_y)),
+        scale_x: Some(image_w / natural_w),
+        scale_y: Some(image_h / natural_h),
+        ..Default::default()
+    });
+
+    let title = image.title.unwrap_or(String::from("-"));
+    let detail = [image.user.as_str(), " ", &image.created].concat();
+    layer.use_text(truncate(&title, width, TITLE_SIZE), TITLE_SIZE, Mm(x + CELL_PADDING), Mm(y + CAPTION_HEIGHT - 4.0), font);
+    layer.use_text(truncate(&detail, width, DETAIL_SIZE), DETAIL_SIZE, Mm(x + CELL_PADDING), Mm(y + CAPTION_HEIGHT - 8.0), font);
+}
+
+/// cut text longer than cell, assume average char width is half of font size
+fn truncate(text: &str, width: f32, font_size: f32) -> String {
+    let char_mm = font_size * 0.3528 * 0.5;
+    let max_chars = ((width - 2.0 * CELL_PADDING) / char_mm).max(1.0) as usize;
+    if text.chars().count() > max_chars {
+        // builtin font has no ellipsis glyph
+        let mut result = text.chars().take(max_chars.saturating_sub(3)).collect::<String>();
+        result.push_str("...");
+        result
+    } else {
+        text.to_owned()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{truncate, ReportGrid};
+
+    #[test]
+    pub fn test_report_grid() {
+        let grid = ReportGrid::new(None, Some(10));
+        assert_eq!((grid.columns, grid.rows), (2, 6));
+        let ((x0, y0), (w, h)) = grid.cell(0);
+        let ((x1, y1), _) = grid.cell(3);
+        assert_eq!((x1, y1), (x0 + w, y0 - h));
+        assert_eq!(truncate("abcdefghij", 20.0, 10.0), "abcdef...");
+        assert_eq!(truncate("abc", 20.0, 10.0), "abc");
+    }
+}
@@ -1,8 +1,9 @@
 use axum::{
     body::Body,
+    extract::MatchedPath,
     http::{
         header::{self, HeaderValue},
-        Request,
+        Request, StatusCode,
     },
     routing::{get, post},
     Router,
@@ -21,41 +22,65 @@ use tower_http::{
 };
 use tracing::Level;
 
-use crate::{AppState, handlers};
+use crate::{AppState, config::config, fhir, handlers, metrics, store};
 
 pub fn router(state: AppState) -> Router {
-    let compression_predicate = SizeAbove::new(1024)
+    let server = &config().server;
+    let compression_predicate = SizeAbove::new(server.compression_min_size)
         // SSE *MUST NOT* COMPRESS, if compressed, data will send once when closed
         .and(NotForContentType::const_new("text/event-stream"))
         // image and woff file already compressed
         .and(NotForContentType::IMAGES)
+        .and(NotForContentType::const_new("application/zip"))
         .and(NotForContentType::const_new("font/woff"))
         .and(NotForContentType::const_new("font/woff2"));
 
     Router::new()
         .route("/greet", get(handlers::greet_handler))
         .route("/image", post(handlers::post_image).put(handlers::put_image))
-        .route("/first/{id}", get(handlers::get_first))
+        .route("/image/zip", post(handlers::post_image_zip))
+        .route("/image/{id}", post(handlers::post_image_edit))
+        .route("/image/{id}/versions", get(handlers::get_image_versions))
+        .route("/image/{id}/annotation", get(handlers::get_image_annotation).put(handlers::put_image_annotation))
+        .route("/image/{id}/versions/{version}", post(handlers::post_image_revert))
+        .route("/restricted/{prefix}/{*path}", get(handlers::get_restricted))
+        .route("/fhir/Media", get(fhir::search_media).post(fhir::post_media))
+        .route("/fhir/Media/{id}", get(fhir::get_media))
+        .route("/first/{id}", get(handlers::get_first).put(handlers::put_first))
+        .route("/first/{id}/dicom", get(handlers::get_first_dicom))
+        .route("/first/{id}/pdf", get(handlers::get_first_pdf))
+        .route("/first/{id}/similar", get(handlers::get_first_similar))
         .route("/first", post(handlers::post_first).delete(handlers::delete_first))
-        .route("/second/{id}", get(handlers::get_second))
+        .route("/second/{id}", get(handlers::get_second).put(handlers::put_second))
+        .route("/second/{id}/dicom", get(handlers::get_second_dicom))
+        .route("/second/{id}/pdf", get(handlers::get_second_pdf))
+        .route("/second/{id}/similar", get(handlers::get_second_similar))
         .route("/second", post(handlers::post_second).delete(handlers::delete_second))
-        .with_state(state)
-        .layer(RequestBodyLimitLayer::new(4096000))
-        .layer(TimeoutLayer::new(Duration::from_secs(30)))
+        .with_state(state.clone())
+        .layer(RequestBodyLimitLayer::new(server.body_limit))
+        .layer(TimeoutLayer::with_status_code(StatusCode::REQUEST_TIMEOUT, Duration::from_secs(server.timeout_secs)))
+        // outside timeout, so changes of a timed out handler are saved too
+        .layer(axum::middleware::from_fn_with_state(state, store::persist_tables))
         .layer(CookieManagerLayer::new())
+        .layer(axum::middleware::from_fn(metrics::track_request))
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &Request<Body>| {
+                let route = request.extensions().get::<MatchedPath>().map(MatchedPath::as_str);
                 tracing::span!(
                     Level::DEBUG,
                     "request",
                     method = tracing::field::display(request.method()),
                     uri = tracing::field::display(request.uri()),
+                    route = tracing::field::debug(route),
                     version = tracing::field::debug(request.version()),
                     request_id = tracing::field::display(ulid::Ulid::new()),
                 )
             }),
         )
-        .layer(CompressionLayer::new().compress_when(compression_predicate))
+        .layer(CompressionLayer::new()
+            .br(server.compression)
+            .gzip(server.compression)
+            .compress_when(compression_predicate))
         .layer(SetResponseHeaderLayer::if_not_present(
             header::CACHE_CONTROL,
             HeaderValue::from_static("no-store"),
@@ -0,0 +1,147 @@
+use std::{io, time::Duration};
+use time::OffsetDateTime;
+use tracing::{error, info, warn};
+
+use crate::{
+    blob::{blob_key, blobs, sha256_hex},
+    config::config,
+    handlers::PATH_PREFIX_QUARANTINE,
+    maintenance::{checksums, Outcomes, ScrubOutcome},
+    shutdown,
+    store::Snapshot,
+    AppState,
+};
+
+/// problem of stored file, `None` when content matches `sha256` and decodes as image
+pub fn verify(data: &[u8], sha256: Option<&str>) -> Option<String> {
+    if sha256.is_some_and(|sha256| sha256_hex(data) != sha256) {
+        return Some(String::from("checksum mismatch"));
+    }
+    image::load_from_memory(data).err().map(|e| ["cannot decode: ", &e.to_string()].concat())
+}
+
+/// files checked so far in a pass
+#[derive(Default)]
+struct Pass {
+    checked: usize,
+    corrupt_files: Vec<String>,
+}
+
+impl Pass {
+    /// re-read a file, move it to `quarantine/{prefix}/{path}` when corrupt and `quarantine`,
+    /// missing file was moved by edit or gc meanwhile and is skipped
+    async fn check(&mut self, prefix: &str, path: &str, sha256: Option<String>, quarantine: bool) {
+        let key = blob_key(prefix, path);
+        let data = match blobs().get(&key).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                warn!("Cannot scrub {}: {}", key, e);
+                return;
+            }
+        };
+        self.checked += 1;
+        let problem = match tokio::task::spawn_blocking(move || verify(&data, sha256.as_deref())).await {
+            Ok(problem) => problem,
+            Err(e) => Some(e.to_string()),
+        };
+        let Some(problem) = problem else {
+            return;
+        };
+        error!("Corrupt file {}: {}", key, problem);
+        if quarantine {
+            match blobs().rename(&key, &blob_key(PATH_PREFIX_QUARANTINE, &key)).await {
+                Ok(()) => warn!("Corrupt file {} moved to {}", key, PATH_PREFIX_QUARANTINE),
+                Err(e) => warn!("Cannot quarantine {}: {}", key, e),
+            }
+        }
+        self.corrupt_files.push([key.as_str(), ": ", &problem].concat());
+    }
+
+    fn record(self) {
+        Outcomes::record(|outcomes| outcomes.scrub = Some(ScrubOutcome {
+            finished_at: OffsetDateTime::now_utc(),
+            checked: self.checked,
+            corrupt_files: self.corrupt_files,
+        }));
+    }
+}
+
+/// one full pass over every file of tables, return `false` when a file is corrupt
+pub async fn scrub(quarantine: bool) -> io::Result<bool> {
+    let snapshot = Snapshot::load()?;
+    let mut pass = Pass::default();
+    for ((prefix, path), sha256) in checksums(&snapshot.images, &snapshot.image_versions) {
+        pass.check(&prefix, &path, sha256, quarantine).await;
+    }
+    for file in &pass.corrupt_files {
+        println!("corrupt file: {}", file);
+    }
+    println!("{} files checked, {} corrupt", pass.checked, pass.corrupt_files.len());
+    let healthy = pass.corrupt_files.is_empty();
+    pass.record();
+    Ok(healthy)
+}
+
+/// verify `scrub.files_per_tick` files every `scrub.interval_secs` in background,
+/// a pass covers files of tables at its start and is recorded for `/metrics` when done
+pub fn start(app: AppState) {
+    let scrub = &config().scrub;
+    if scrub.files_per_tick == 0 {
+        return;
+    }
+    tokio::spawn(run(app, scrub.files_per_tick, Duration::from_secs(scrub.interval_secs), scrub.quarantine));
+}
+
+async fn run(app: AppState, files_per_tick: usize, interval: Duration, quarantine: bool) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let token = shutdown::token();
+    let mut queue = Vec::new();
+    let mut pass = Pass::default();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = token.cancelled() => return,
+        }
+        if queue.is_empty() {
+            let files = checksums(&app.images.lock().unwrap(), &app.image_versions.lock().unwrap());
+            // popped from the end, so oldest Ulid first
+            queue = files.into_iter().rev().collect();
+        }
+        for _ in 0..files_per_tick {
+            let Some(((prefix, path), sha256)) = queue.pop() else {
+                break;
+            };
+            pass.check(&prefix, &path, sha256, quarantine).await;
+        }
+        if queue.is_empty() && pass.checked > 0 {
+            info!("Scrub pass finished, {} files checked, {} corrupt", pass.checked, pass.corrupt_files.len());
+            std::mem::take(&mut pass).record();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::io::Cursor;
+
+    use image::{DynamicImage, ImageFormat, RgbImage};
+
+    use crate::blob::sha256_hex;
+
+    use super::verify;
+
+    #[test]
+    pub fn test_verify() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(4, 4)).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let sha256 = sha256_hex(&png);
+        assert_eq!(verify(&png, Some(&sha256)), None);
+        assert_eq!(verify(&png, None), None);
+        let mut flipped = png.clone();
+        flipped[20] ^= 1;
+        assert_eq!(verify(&flipped, Some(&sha256)), Some(String::from("checksum mismatch")));
+        assert!(verify(&png[..png.len() / 2], None).is_some_and(|problem| problem.starts_with("cannot decode")));
+    }
+}
@@ -0,0 +1,92 @@
+use std::{future::Future, sync::LazyLock, time::Duration};
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+use crate::store::{volume_files, volume_path, TEMP_SUFFIX};
+
+static SHUTDOWN: LazyLock<CancellationToken> = LazyLock::new(CancellationToken::new);
+static TASKS: LazyLock<TaskTracker> = LazyLock::new(TaskTracker::new);
+
+/// cancelled when Ctrl-C or SIGTERM is received
+pub fn token() -> CancellationToken {
+    SHUTDOWN.clone()
+}
+
+/// cancel `token` on Ctrl-C or SIGTERM
+pub fn listen() {
+    tokio::spawn(async {
+        let ctrl_c = async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                warn!("Cannot listen Ctrl-C: {}", e);
+                std::future::pending::<()>().await;
+            }
+        };
+        #[cfg(unix)]
+        let terminate = async {
+            match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                Ok(mut signal) => {
+                    signal.recv().await;
+                }
+                Err(e) => {
+                    warn!("Cannot listen SIGTERM: {}", e);
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+        tokio::select! {
+            _ = ctrl_c => {},
+            _ = terminate => {},
+        }
+        info!("Shutdown signal received, stop accepting requests");
+        SHUTDOWN.cancel();
+    });
+}
+
+/// completes `timeout` after shutdown started, bound of waiting for in-flight requests
+pub async fn deadline(timeout: Duration) {
+    SHUTDOWN.cancelled().await;
+    tokio::time::sleep(timeout).await;
+}
+
+/// background job which shutdown waits for, ex. zip writer which outlives its handler
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    TASKS.spawn(future)
+}
+
+/// wait for background jobs at most `timeout`, return `false` when some are still running
+pub async fn drain(timeout: Duration) -> bool {
+    TASKS.close();
+    let drained = tokio::time::timeout(timeout, TASKS.wait()).await.is_ok();
+    if !drained {
+        warn!("{} background jobs still running after {:?}", TASKS.len(), timeout);
+    }
+    drained
+}
+
+/// remove files left by interrupted writes, files are written to `*.tmp` then renamed
+pub fn remove_temp_files() -> usize {
+    let files = match volume_files("") {
+        Ok(files) => files,
+        Err(e) => {
+            warn!("Cannot list temporary files: {}", e);
+            return 0;
+        }
+    };
+    files.into_iter()
+        .filter(|path| path.ends_with(TEMP_SUFFIX))
+        .filter(|path| match std::fs::remove_file(volume_path("", path)) {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Cannot remove {}: {}", path, e);
+                false
+            }
+        })
+        .count()
+}
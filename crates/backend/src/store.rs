@@ -0,0 +1,274 @@
+use axum::{
+    extract::{Request, State},
+    http::Method,
+    middleware::Next,
+    response::Response,
+};
+use serde_derive::{Deserialize, Serialize};
+use fs4::fs_std::FileExt;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tracing::error;
+
+use model::{ImageAnnotation, ImageData, ImageVersion};
+
+use crate::{config::config, set_count, AppState};
+
+/// suffix of file being written, renamed to real name when complete
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+// concurrent saves would write the same temporary file
+static SAVING: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// `{path}.tmp`
+pub fn temp_path(path: &Path) -> PathBuf {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(TEMP_SUFFIX);
+    PathBuf::from(temp)
+}
+
+/// write to temporary file then rename, so reader never sees half-written file
+pub fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp_path = temp_path(path);
+    std::fs::write(&tmp_path, data)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// exclusive lock of data file, held by the server and by commands which write tables or files,
+/// released when dropped or when the process exits, even by kill
+pub struct DataLock {
+    _file: File,
+}
+
+/// lock data file of config, fail at once when the server or other command holds it
+pub fn lock_data() -> io::Result<DataLock> {
+    lock_data_file(&config().storage.data_file())
+}
+
+/// `{data file}.lock` is locked, data file itself is replaced by rename on every save
+pub fn lock_data_file(data_file: &Path) -> io::Result<DataLock> {
+    let mut path = data_file.as_os_str().to_owned();
+    path.push(".lock");
+    let path = PathBuf::from(path);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+    if !file.try_lock_exclusive()? {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            format!("{} is locked by the server or another command, stop it first", path.display()),
+        ));
+    }
+    Ok(DataLock { _file: file })
+}
+
+/// `volume/{prefix}/{path}`, prefix can be nested like `restricted/images`, volume is from config
+pub fn volume_path(prefix: &str, path: &str) -> PathBuf {
+    config().storage.volume.join(prefix).join(path)
+}
+
+/// all file paths under `volume/{prefix}`, relative to it with `/` separator
+pub fn volume_files(prefix: &str) -> io::Result<Vec<String>> {
+    list_files(&config().storage.volume.join(prefix))
+}
+
+/// all file paths under `root`, relative to it with `/` separator, missing root has no file
+pub fn list_files(root: &Path) -> io::Result<Vec<String>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if let Ok(relative) = path.strip_prefix(root) {
+                let parts = relative.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>();
+                files.push(parts.join("/"));
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// all tables of `AppState`, saved as one JSON file
+#[derive(Default, Deserialize, Serialize)]
+pub struct Snapshot {
+    #[serde(default)]
+    pub images: Vec<ImageData>,
+    #[serde(default)]
+    pub image_versions: Vec<ImageVersion>,
+    #[serde(default)]
+    pub annotations: Vec<ImageAnnotation>,
+    #[serde(default)]
+    pub first_table: Vec<ImageData>,
+    #[serde(default)]
+    pub second_table: Vec<ImageData>,
+}
+
+impl Snapshot {
+    /// load tables from data file of config, missing file is empty tables
+    pub fn load() -> io::Result<Self> {
+        Self::load_from(&config().storage.data_file())
+    }
+
+    pub fn load_from(path: &Path) -> io::Result<Self> {
+        match std::fs::read(path) {
+            Ok(data) => serde_json::from_slice::<Self>(&data).map_err(io::Error::other),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(io::Error::new(e.kind(), format!("Cannot read {}: {}", path.display(), e))),
+        }
+    }
+
+    /// save tables to data file of config
+    pub fn save(&self) -> io::Result<()> {
+        self.save_to(&config().storage.data_file())
+    }
+
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        let data = serde_json::to_vec(self).map_err(io::Error::other)?;
+        write_file(path, &data)
+    }
+
+    pub fn max_image_id(&self) -> u32 {
+        self.images.iter()
+            .map(|image| image.image_id)
+            .chain(self.image_versions.iter().map(|version| version.image_id))
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+impl AppState {
+    pub fn load() -> io::Result<Self> {
+        let snapshot = Snapshot::load()?;
+        // continue ids after the largest one
+        set_count(snapshot.max_image_id() + 1);
+        Ok(Self {
+            images: Arc::new(Mutex::new(snapshot.images)),
+            image_versions: Arc::new(Mutex::new(snapshot.image_versions)),
+            annotations: Arc::new(Mutex::new(snapshot.annotations)),
+            first_table: Arc::new(Mutex::new(snapshot.first_table)),
+            second_table: Arc::new(Mutex::new(snapshot.second_table)),
+        })
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            images: self.images.lock().unwrap().clone(),
+            image_versions: self.image_versions.lock().unwrap().clone(),
+            annotations: self.annotations.lock().unwrap().clone(),
+            first_table: self.first_table.lock().unwrap().clone(),
+            second_table: self.second_table.lock().unwrap().clone(),
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        self.snapshot().save()
+    }
+
+    /// save tables to data file of config off the async runtime
+    pub async fn persist(&self) -> io::Result<()> {
+        self.persist_to(config().storage.data_file()).await
+    }
+
+    /// one save at a time, snapshot is taken in turn so the newest tables are written last
+    pub async fn persist_to(&self, path: PathBuf) -> io::Result<()> {
+        let _saving = SAVING.lock().await;
+        let snapshot = self.snapshot();
+        tokio::task::spawn_blocking(move || snapshot.save_to(&path)).await.map_err(io::Error::other)?
+    }
+}
+
+/// request which may change tables
+fn is_mutating(method: &Method) -> bool {
+    ![Method::GET, Method::HEAD, Method::OPTIONS].contains(method)
+}
+
+/// save tables after every request which may change them, so a crash or kill loses no acknowledged row,
+/// failed requests are saved too since a handler may fail after changing a table
+pub async fn persist_tables(State(app): State<AppState>, request: Request, next: Next) -> Response {
+    let mutating = is_mutating(request.method());
+    let response = next.run(request).await;
+    if mutating {
+        if let Err(e) = app.persist().await {
+            error!("Cannot save tables to {}: {}", config().storage.data_file().display(), e);
+        }
+    }
+    response
+}
+
+#[cfg(test)]
+pub mod tests {
+    use axum::http::Method;
+    use std::sync::{Arc, Mutex};
+
+    use model::ImageData;
+
+    use crate::AppState;
+
+    use super::{is_mutating, lock_data_file, Snapshot};
+
+    #[tokio::test]
+    pub async fn test_persist() {
+        assert!(is_mutating(&Method::POST));
+        assert!(is_mutating(&Method::DELETE));
+        assert!(!is_mutating(&Method::GET));
+
+        let dir = std::env::temp_dir().join(["kphis_persist_", &std::process::id().to_string()].concat());
+        let path = dir.join("data.json");
+        let app = AppState {
+            images: Arc::new(Mutex::new(Vec::new())),
+            image_versions: Arc::new(Mutex::new(Vec::new())),
+            annotations: Arc::new(Mutex::new(Vec::new())),
+            first_table: Arc::new(Mutex::new(Vec::new())),
+            second_table: Arc::new(Mutex::new(Vec::new())),
+        };
+        app.images.lock().unwrap().push(ImageData {
+            image_id: 7,
+            foreign_id: 1,
+            path: String::from("01J/G0/M004KYHATX7J2W7MB28X4.webp"),
+            title: None,
+            user: String::from("user"),
+            position: 0,
+            annotation: None,
+            metadata: None,
+            sha256: None,
+            thumb_sha256: None,
+            dhash: None,
+        });
+        app.persist_to(path.clone()).await.unwrap();
+        // process killed here, `save` on shutdown never runs
+        drop(app);
+        let snapshot = Snapshot::load_from(&path).unwrap();
+        assert_eq!(snapshot.images.iter().map(|image| image.image_id).collect::<Vec<u32>>(), vec![7]);
+        assert!(Snapshot::load_from(&dir.join("missing.json")).unwrap().images.is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    pub fn test_lock_data_file() {
+        let dir = std::env::temp_dir().join(["kphis_lock_", &std::process::id().to_string()].concat());
+        let path = dir.join("data.json");
+        let lock = lock_data_file(&path).unwrap();
+        // ex. gc from cron while server runs
+        assert_eq!(lock_data_file(&path).err().map(|e| e.kind()), Some(std::io::ErrorKind::WouldBlock));
+        drop(lock);
+        assert!(lock_data_file(&path).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
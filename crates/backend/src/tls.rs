@@ -0,0 +1,125 @@
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, StatusCode, Uri},
+    response::Response,
+    Router,
+};
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info, warn};
+
+use crate::{config::Tls, shutdown};
+
+/// HTTPS with PEM certificate and key of config, certificate files are checked every `reload_secs`
+/// and reloaded when changed, open connections keep their session
+pub async fn serve_https(addr: SocketAddr, app: Router, tls: &Tls, cert: PathBuf, key: PathBuf, timeout: Duration) -> io::Result<()> {
+    let rustls = RustlsConfig::from_pem_file(&cert, &key).await
+        .map_err(|e| io::Error::new(e.kind(), format!("Cannot load certificate {}: {}", cert.display(), e)))?;
+    tokio::spawn(watch_certificates(rustls.clone(), cert, key, Duration::from_secs(tls.reload_secs)));
+    if let Some(redirect_addr) = tls.redirect_bind {
+        tokio::spawn(serve_redirect(redirect_addr, addr.port()));
+    }
+    // stop accepting on shutdown signal, then wait for in-flight requests at most `timeout`
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown::token().cancelled().await;
+        shutdown_handle.graceful_shutdown(Some(timeout));
+    });
+    info!("HTTPS server started listening on {}, please Ctrl-c to terminate server.", addr);
+    axum_server::bind_rustls(addr, rustls).handle(handle).serve(app.into_make_service()).await
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+async fn watch_certificates(rustls: RustlsConfig, cert: PathBuf, key: PathBuf, interval: Duration) {
+    let mut loaded = (modified(&cert), modified(&key));
+    let mut ticker = tokio::time::interval(interval);
+    ticker.tick().await;
+    let token = shutdown::token();
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {},
+            _ = token.cancelled() => return,
+        }
+        let current = (modified(&cert), modified(&key));
+        if current == loaded {
+            continue;
+        }
+        // cert and key are often replaced one after the other, a mismatched pair is retried on next tick
+        match rustls.reload_from_pem_file(&cert, &key).await {
+            Ok(()) => {
+                info!("Certificate {} reloaded", cert.display());
+                loaded = current;
+            }
+            Err(e) => warn!("Cannot reload certificate {}, keep the old one: {}", cert.display(), e),
+        }
+    }
+}
+
+async fn serve_redirect(addr: SocketAddr, https_port: u16) {
+    let app = Router::new().fallback(move |headers: HeaderMap, uri: Uri| async move {
+        let host = headers.get(header::HOST).and_then(|host| host.to_str().ok());
+        match host.and_then(|host| https_url(host, &uri, https_port)) {
+            Some(location) => Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(header::LOCATION, location)
+                .body(Body::empty())
+                .unwrap(),
+            None => Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::from("Missing Host header"))
+                .unwrap(),
+        }
+    });
+    match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => {
+            info!("HTTP to HTTPS redirect listening on {}", addr);
+            if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(shutdown::token().cancelled_owned()).await {
+                error!("HTTP redirect stopped: {}", e);
+            }
+        }
+        Err(e) => error!("Cannot listen HTTP redirect on {}: {}", addr, e),
+    }
+}
+
+/// same host and path on HTTPS port, `host` may have port or be IPv6 like `[::1]:80`
+fn https_url(host: &str, uri: &Uri, https_port: u16) -> Option<String> {
+    let hostname = if host.starts_with('[') {
+        &host[..=host.find(']')?]
+    } else {
+        host.split(':').next()?
+    };
+    if hostname.is_empty() {
+        return None;
+    }
+    let path = uri.path_and_query().map(|path| path.as_str()).unwrap_or("/");
+    if https_port == 443 {
+        Some(["https://", hostname, path].concat())
+    } else {
+        Some(["https://", hostname, ":", &https_port.to_string(), path].concat())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use axum::http::Uri;
+
+    use super::https_url;
+
+    #[test]
+    pub fn test_https_url() {
+        let uri = "/images/a.webp?v=1".parse::<Uri>().unwrap();
+        assert_eq!(https_url("192.168.1.10", &uri, 443).as_deref(), Some("https://192.168.1.10/images/a.webp?v=1"));
+        assert_eq!(https_url("kphis.local:80", &uri, 8443).as_deref(), Some("https://kphis.local:8443/images/a.webp?v=1"));
+        assert_eq!(https_url("[::1]:80", &Uri::from_static("/"), 443).as_deref(), Some("https://[::1]/"));
+        assert_eq!(https_url("", &uri, 443), None);
+    }
+}
@@ -0,0 +1,586 @@
+use dominator::{clone, Dom, events, html, svg, with_node};
+use futures_signals::{
+    map_ref,
+    signal::{Mutable, Signal, SignalExt},
+};
+use std::{cell::Cell, f64::consts::PI, rc::Rc};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    CanvasRenderingContext2d, HtmlAnchorElement, HtmlButtonElement, HtmlCanvasElement,
+    HtmlImageElement, HtmlInputElement, HtmlSelectElement, Url, window,
+};
+use imaging::rgba_to_webp;
+use model::{ImageAnnotation, ImageData, Shape, ShapeKind};
+
+use crate::{
+    App,
+    fetch::{bytes_to_blob, put_image_annotation},
+    mixins,
+};
+
+const COLORS: [(&str, &str); 6] = [
+    ("red", "แดง"),
+    ("yellow", "เหลือง"),
+    ("lime", "เขียว"),
+    ("cyan", "ฟ้า"),
+    ("white", "ขาว"),
+    ("black", "ดำ"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Tool {
+    Arrow,
+    Circle,
+    Freehand,
+    Measure,
+    Text,
+}
+
+impl Tool {
+    fn icon(&self) -> &'static str {
+        match self {
+            Self::Arrow => "fa-arrow-right-long",
+            Self::Circle => "fa-circle",
+            Self::Freehand => "fa-pen",
+            Self::Measure => "fa-ruler",
+            Self::Text => "fa-font",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            Self::Arrow => "ลูกศร",
+            Self::Circle => "วงกลม",
+            Self::Freehand => "วาดเส้น",
+            Self::Measure => "วัดระยะ",
+            Self::Text => "ข้อความ",
+        }
+    }
+
+    /// start drawing at (x, y)
+    fn start(&self, x: f64, y: f64) -> Option<ShapeKind> {
+        match self {
+            Self::Arrow => Some(ShapeKind::Arrow { x1: x, y1: y, x2: x, y2: y }),
+            Self::Circle => Some(ShapeKind::Circle { cx: x, cy: y, rx: 0.0, ry: 0.0 }),
+            Self::Freehand => Some(ShapeKind::Freehand { points: vec![(x, y)] }),
+            Self::Measure => Some(ShapeKind::Measure { x1: x, y1: y, x2: x, y2: y }),
+            Self::Text => None,
+        }
+    }
+}
+
+/// draw arrows, circles, freehand lines, measurements and text labels on image
+pub struct AnnotationEditorCpn {
+    image_data: ImageData,
+    // None until image was loaded, image size is needed
+    annotation: Mutable<Option<ImageAnnotation>>,
+    tool: Mutable<Tool>,
+    color: Mutable<String>,
+    draft: Mutable<Option<Shape>>,
+    // pointer down point while drawing, in image pixels
+    draft_start: Cell<Option<(f64, f64)>>,
+}
+
+impl AnnotationEditorCpn {
+    pub fn new(image_data: &ImageData) -> Rc<Self> {
+        Rc::new(Self {
+            image_data: image_data.clone(),
+            annotation: Mutable::new(image_data.annotation.clone()),
+            tool: Mutable::new(Tool::Arrow),
+            color: Mutable::new(String::from(COLORS[0].0)),
+            draft: Mutable::new(None),
+            draft_start: Cell::new(None),
+        })
+    }
+
+    pub fn image_id(&self) -> u32 {
+        self.image_data.image_id
+    }
+
+    pub async fn save(&self) -> Result<(), String> {
+        if let Some(annotation) = self.annotation.get_cloned() {
+            put_image_annotation(&annotation).await?;
+        }
+        Ok(())
+    }
+
+    /// download flattened webp
+    pub async fn export(&self) -> Result<(), JsValue> {
+        if let Some(annotation) = self.annotation.get_cloned() {
+            let bytes = flatten_webp(&annotation).await?;
+            // 01J/G0/M004KYHATX7J2W7MB28X4.webp to 01JG0M004KYHATX7J2W7MB28X4_annotated.webp
+            let filename = [&self.image_data.path.trim_end_matches(".webp").replace('/', ""), "_annotated.webp"].concat();
+            download_bytes(&bytes, &filename).await?;
+        }
+        Ok(())
+    }
+
+    fn has_shapes_signal(&self) -> impl Signal<Item = bool> {
+        self.annotation.signal_ref(|opt| opt.as_ref().is_some_and(|an| !an.shapes.is_empty()))
+    }
+
+    fn has_measure_signal(&self) -> impl Signal<Item = bool> {
+        self.annotation.signal_ref(|opt| opt.as_ref().is_some_and(|an| first_measure_px(an).is_some()))
+    }
+
+    /// client point to image pixels
+    fn image_point(element: &web_sys::Element, client_x: i32, client_y: i32, annotation: &ImageAnnotation) -> (f64, f64) {
+        let rect = element.get_bounding_client_rect();
+        let x = (client_x as f64 - rect.left()) / rect.width().max(1.0) * annotation.width as f64;
+        let y = (client_y as f64 - rect.top()) / rect.height().max(1.0) * annotation.height as f64;
+        (x.clamp(0.0, annotation.width as f64), y.clamp(0.0, annotation.height as f64))
+    }
+
+    fn pointer_down(&self, x: f64, y: f64) {
+        let color = self.color.get_cloned();
+        match self.tool.get().start(x, y) {
+            Some(kind) => {
+                self.draft_start.set(Some((x, y)));
+                self.draft.set(Some(Shape { color, kind }));
+            }
+            None => {
+                let text = window()
+                    .and_then(|w| w.prompt_with_message("ข้อความ").ok().flatten())
+                    .filter(|text| !text.trim().is_empty());
+                if let (Some(text), Some(annotation)) = (text, self.annotation.lock_mut().as_mut()) {
+                    annotation.shapes.push(Shape { color, kind: ShapeKind::Text { x, y, text } });
+                }
+            }
+        }
+    }
+
+    fn pointer_move(&self, x: f64, y: f64) {
+        if let Some((x1, y1)) = self.draft_start.get() {
+            if let Some(shape) = self.draft.lock_mut().as_mut() {
+                match &mut shape.kind {
+                    ShapeKind::Arrow { x2, y2, .. } | ShapeKind::Measure { x2, y2, .. } => {
+                        *x2 = x;
+                        *y2 = y;
+                    }
+                    ShapeKind::Circle { cx, cy, rx, ry } => {
+                        *cx = (x1 + x) / 2.0;
+                        *cy = (y1 + y) / 2.0;
+                        *rx = (x - x1).abs() / 2.0;
+                        *ry = (y - y1).abs() / 2.0;
+                    }
+                    ShapeKind::Freehand { points } => points.push((x, y)),
+                    ShapeKind::Text { .. } => {}
+                }
+            }
+        }
+    }
+
+    fn pointer_up(&self) {
+        self.draft_start.set(None);
+        if let Some(shape) = self.draft.replace(None) {
+            // ignore click without dragging
+            let is_dot = match &shape.kind {
+                ShapeKind::Arrow { x1, y1, x2, y2 } | ShapeKind::Measure { x1, y1, x2, y2 } => (x2 - x1).hypot(y2 - y1) < 2.0,
+                ShapeKind::Circle { rx, ry, .. } => *rx < 1.0 || *ry < 1.0,
+                ShapeKind::Freehand { points } => points.len() < 2,
+                ShapeKind::Text { .. } => false,
+            };
+            if !is_dot {
+                if let Some(annotation) = self.annotation.lock_mut().as_mut() {
+                    annotation.shapes.push(shape);
+                }
+            }
+        }
+    }
+
+    fn tool_button(page: &Rc<Self>, tool: Tool) -> Dom {
+        html!("button", {
+            .attr("type","button")
+            .attr("title", tool.title())
+            .class(["btn","btn-sm"])
+            .class_signal("btn-secondary", page.tool.signal().map(move |v| v != tool))
+            .class_signal("btn-warning", page.tool.signal().map(move |v| v == tool))
+            .child(html!("i", {
+                .class([if tool == Tool::Circle {"far"} else {"fas"}, tool.icon()])
+            }))
+            .event(clone!(page => move |_: events::Click| {
+                page.tool.set(tool);
+            }))
+        })
+    }
+
+    pub fn render<S, C>(page: Rc<Self>, app: Rc<App>, on_save: S, on_cancel: C) -> Dom
+    where
+        S: Fn() + 'static,
+        C: Fn() + 'static,
+    {
+        html!("div", {
+            .class(["p-1","text-center"])
+            .child(html!("div", {
+                .class(["d-flex","flex-wrap","justify-content-center","gap-1","mb-1"])
+                .child(html!("div", {
+                    .class("btn-group")
+                    .children(&mut [
+                        Self::tool_button(&page, Tool::Arrow),
+                        Self::tool_button(&page, Tool::Circle),
+                        Self::tool_button(&page, Tool::Freehand),
+                        Self::tool_button(&page, Tool::Measure),
+                        Self::tool_button(&page, Tool::Text),
+                    ])
+                }))
+                .child(html!("select" => HtmlSelectElement, {
+                    .class(["form-select","form-select-sm","w-auto"])
+                    .children(COLORS.iter().map(|(value, text)| {
+                        html!("option", {
+                            .attr("value", value)
+                            .style("color", value)
+                            .text(text)
+                        })
+                    }))
+                    .with_node!(element => {
+                        .event(clone!(page => move |_: events::Change| {
+                            page.color.set(element.value());
+                        }))
+                    })
+                }))
+                .child(html!("div", {
+                    .class("btn-group")
+                    .children(&mut [
+                        html!("button" => HtmlButtonElement, {
+                            .attr("type","button")
+                            .attr("title","ย้อนกลับ")
+                            .class(["btn","btn-sm","btn-secondary"])
+                            .child(html!("i", {
+                                .class(["fas","fa-delete-left"])
+                            }))
+                            .apply(mixins::other_true_signal_disable(page.has_shapes_signal().map(|v| !v)))
+                            .event(clone!(page => move |_: events::Click| {
+                                if let Some(annotation) = page.annotation.lock_mut().as_mut() {
+                                    annotation.shapes.pop();
+                                }
+                            }))
+                        }),
+                        html!("button" => HtmlButtonElement, {
+                            .attr("type","button")
+                            .attr("title","ล้าง")
+                            .class(["btn","btn-sm","btn-secondary"])
+                            .child(html!("i", {
+                                .class(["fas","fa-eraser"])
+                            }))
+                            .apply(mixins::other_true_signal_disable(page.has_shapes_signal().map(|v| !v)))
+                            .event(clone!(page => move |_: events::Click| {
+                                if let Some(annotation) = page.annotation.lock_mut().as_mut() {
+                                    annotation.shapes.clear();
+                                    annotation.mm_per_px = None;
+                                }
+                            }))
+                        }),
+                    ])
+                }))
+            }))
+            .child_signal(page.has_measure_signal().map(clone!(page => move |has_measure| {
+                has_measure.then(|| {
+                    html!("div", {
+                        .class(["input-group","input-group-sm","mb-1","mx-auto"])
+                        .style("max-width","300px")
+                        .children(&mut [
+                            html!("span", {
+                                .class("input-group-text")
+                                .text("เส้นวัดแรกยาว")
+                            }),
+                            html!("input" => HtmlInputElement, {
+                                .class("form-control")
+                                .attr("type","number")
+                                .attr("min","0")
+                                .attr("step","0.1")
+                                .with_node!(element => {
+                                    .event(clone!(page => move |_: events::Change| {
+                                        let mm = element.value().parse::<f64>().ok().filter(|mm| *mm > 0.0);
+                                        if let Some(annotation) = page.annotation.lock_mut().as_mut() {
+                                            annotation.mm_per_px = mm.zip(first_measure_px(annotation)).map(|(mm, px)| mm / px);
+                                        }
+                                    }))
+                                })
+                            }),
+                            html!("span", {
+                                .class("input-group-text")
+                                .text("mm")
+                            }),
+                        ])
+                    })
+                })
+            })))
+            .child(html!("div", {
+                .class(["position-relative","d-inline-block"])
+                .child(html!("img" => HtmlImageElement, {
+                    .class(["mw-100","d-block"])
+                    .attr("draggable","false")
+                    .attr("src", &["images", &page.image_data.path].join("/"))
+                    .with_node!(element => {
+                        .event(clone!(page => move |_: events::Load| {
+                            let mut lock = page.annotation.lock_mut();
+                            if lock.is_none() {
+                                *lock = Some(ImageAnnotation::new(
+                                    page.image_data.image_id,
+                                    &page.image_data.path,
+                                    element.natural_width(),
+                                    element.natural_height(),
+                                ));
+                            }
+                        }))
+                    })
+                }))
+                .child_signal(page.annotation.signal_cloned().map(clone!(page => move |opt| {
+                    opt.map(|annotation| {
+                        let annotation = Rc::new(annotation);
+                        svg!("svg", {
+                            .class(["position-absolute","top-0","start-0","w-100","h-100"])
+                            .attr("viewBox", &["0 0 ", &annotation.width.to_string(), " ", &annotation.height.to_string()].concat())
+                            .attr("style","cursor:crosshair;touch-action:none")
+                            .children(annotation.shapes.iter().map(|shape| shape_svg(&annotation, shape)))
+                            .child_signal(page.draft.signal_cloned().map(clone!(annotation => move |draft| {
+                                draft.map(|shape| shape_svg(&annotation, &shape))
+                            })))
+                            .with_node!(element => {
+                                .event(clone!(page, element, annotation => move |e: events::PointerDown| {
+                                    let _ = element.set_pointer_capture(e.pointer_id());
+                                    let (x, y) = Self::image_point(&element, e.x(), e.y(), &annotation);
+                                    page.pointer_down(x, y);
+                                }))
+                                .event(clone!(page, element, annotation => move |e: events::PointerMove| {
+                                    let (x, y) = Self::image_point(&element, e.x(), e.y(), &annotation);
+                                    page.pointer_move(x, y);
+                                }))
+                                .event(clone!(page => move |_: events::PointerUp| {
+                                    page.pointer_up();
+                                }))
+                            })
+                        })
+                    })
+                })))
+            }))
+            .child(html!("div", {
+                .class("mt-1")
+                .children(&mut [
+                    html!("button" => HtmlButtonElement, {
+                        .attr("type","button")
+                        .class(["btn","btn-sm","btn-primary","me-1"])
+                        .text("บันทึก")
+                        .apply(mixins::other_true_signal_disable(map_ref! {
+                            let busy = app.loader.is_loading(),
+                            let loaded = page.annotation.signal_ref(|opt| opt.is_some()) =>
+                            *busy || !loaded
+                        }))
+                        .event(move |_: events::Click| on_save())
+                    }),
+                    html!("button" => HtmlButtonElement, {
+                        .attr("type","button")
+                        .class(["btn","btn-sm","btn-secondary","me-1"])
+                        .child(html!("i", {
+                            .class(["fas","fa-download","me-1"])
+                        }))
+                        .text("webp")
+                        .apply(mixins::other_true_signal_disable(map_ref! {
+                            let busy = app.loader.is_loading(),
+                            let has_shapes = page.has_shapes_signal() =>
+                            *busy || !has_shapes
+                        }))
+                        .event(clone!(app, page => move |_: events::Click| {
+                            app.loader.load(clone!(page => async move {
+                                if let Err(e) = page.export().await {
+                                    log::error!("cannot export annotated image {}: {:?}", page.image_id(), e);
+                                }
+                            }));
+                        }))
+                    }),
+                    html!("button", {
+                        .attr("type","button")
+                        .class(["btn","btn-sm","btn-secondary"])
+                        .text("ยกเลิก")
+                        .event(move |_: events::Click| on_cancel())
+                    }),
+                ])
+            }))
+        })
+    }
+}
+
+/// pixels length of first measurement
+fn first_measure_px(annotation: &ImageAnnotation) -> Option<f64> {
+    annotation.shapes.iter().find_map(|shape| {
+        if let ShapeKind::Measure { x1, y1, x2, y2 } = shape.kind {
+            Some((x2 - x1).hypot(y2 - y1))
+        } else {
+            None
+        }
+    })
+}
+
+/// svg overlay of annotation, `square` for center cropped thumbnail
+pub fn annotation_svg(annotation: &ImageAnnotation, square: bool) -> Dom {
+    let (w, h) = (annotation.width as f64, annotation.height as f64);
+    let view_box = if square {
+        let size = w.min(h);
+        [((w - size) / 2.0).to_string(), ((h - size) / 2.0).to_string(), size.to_string(), size.to_string()].join(" ")
+    } else {
+        ["0 0 ", &w.to_string(), " ", &h.to_string()].concat()
+    };
+    svg!("svg", {
+        .class(["position-absolute","top-0","start-0","w-100","h-100"])
+        .attr("viewBox", &view_box)
+        .attr("pointer-events","none")
+        .children(annotation.shapes.iter().map(|shape| shape_svg(annotation, shape)))
+    })
+}
+
+fn shape_svg(annotation: &ImageAnnotation, shape: &Shape) -> Dom {
+    let stroke_width = annotation.stroke_width().to_string();
+    let font_size = annotation.font_size().to_string();
+    let line = |x1: f64, y1: f64, x2: f64, y2: f64| {
+        svg!("line", {
+            .attr("x1", &x1.to_string())
+            .attr("y1", &y1.to_string())
+            .attr("x2", &x2.to_string())
+            .attr("y2", &y2.to_string())
+        })
+    };
+    let text = |x: f64, y: f64, text: &str| {
+        svg!("text", {
+            .attr("x", &x.to_string())
+            .attr("y", &y.to_string())
+            .attr("fill", &shape.color)
+            .attr("stroke","none")
+            .attr("font-size", &font_size)
+            .attr("font-family","sans-serif")
+            .text(text)
+        })
+    };
+    svg!("g", {
+        .attr("stroke", &shape.color)
+        .attr("stroke-width", &stroke_width)
+        .attr("stroke-linecap","round")
+        .attr("stroke-linejoin","round")
+        .attr("fill","none")
+        .apply(|dom| match &shape.kind {
+            ShapeKind::Arrow { x1, y1, x2, y2 } => {
+                let [a, b] = ShapeKind::arrow_head(*x1, *y1, *x2, *y2, annotation.font_size());
+                dom.children(&mut [
+                    line(*x1, *y1, *x2, *y2),
+                    svg!("polyline", {
+                        .attr("points", &points_text(&[a, (*x2, *y2), b]))
+                    }),
+                ])
+            }
+            ShapeKind::Circle { cx, cy, rx, ry } => {
+                dom.child(svg!("ellipse", {
+                    .attr("cx", &cx.to_string())
+                    .attr("cy", &cy.to_string())
+                    .attr("rx", &rx.to_string())
+                    .attr("ry", &ry.to_string())
+                }))
+            }
+            ShapeKind::Freehand { points } => {
+                dom.child(svg!("polyline", {
+                    .attr("points", &points_text(points))
+                }))
+            }
+            ShapeKind::Measure { x1, y1, x2, y2 } => {
+                let label = annotation.measure_text(*x1, *y1, *x2, *y2);
+                dom.children(&mut [
+                    line(*x1, *y1, *x2, *y2),
+                    text((x1 + x2) / 2.0, (y1 + y2) / 2.0 - annotation.stroke_width() * 2.0, &label),
+                ])
+            }
+            ShapeKind::Text { x, y, text: label } => dom.child(text(*x, *y, label)),
+        })
+    })
+}
+
+fn points_text(points: &[(f64, f64)]) -> String {
+    points.iter()
+        .map(|(x, y)| [x.to_string(), y.to_string()].join(","))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// draw image with annotation on canvas
+async fn flatten_canvas(annotation: &ImageAnnotation) -> Result<(HtmlCanvasElement, CanvasRenderingContext2d), JsValue> {
+    let document = window().and_then(|w| w.document()).ok_or("no document")?;
+    let image = HtmlImageElement::new()?;
+    image.set_src(&["images", &annotation.path].join("/"));
+    JsFuture::from(image.decode()).await?;
+
+    let canvas = document.create_element("canvas")?.unchecked_into::<HtmlCanvasElement>();
+    canvas.set_width(annotation.width);
+    canvas.set_height(annotation.height);
+    let ctx = canvas.get_context("2d")?
+        .ok_or("no canvas context")?
+        .unchecked_into::<CanvasRenderingContext2d>();
+    ctx.draw_image_with_html_image_element_and_dw_and_dh(&image, 0.0, 0.0, annotation.width as f64, annotation.height as f64)?;
+
+    ctx.set_line_width(annotation.stroke_width());
+    ctx.set_line_cap("round");
+    ctx.set_line_join("round");
+    ctx.set_font(&[annotation.font_size().to_string(), String::from("px sans-serif")].concat());
+    for shape in annotation.shapes.iter() {
+        ctx.set_stroke_style_str(&shape.color);
+        ctx.set_fill_style_str(&shape.color);
+        ctx.begin_path();
+        match &shape.kind {
+            ShapeKind::Arrow { x1, y1, x2, y2 } => {
+                let [a, b] = ShapeKind::arrow_head(*x1, *y1, *x2, *y2, annotation.font_size());
+                ctx.move_to(*x1, *y1);
+                ctx.line_to(*x2, *y2);
+                ctx.move_to(a.0, a.1);
+                ctx.line_to(*x2, *y2);
+                ctx.line_to(b.0, b.1);
+                ctx.stroke();
+            }
+            ShapeKind::Circle { cx, cy, rx, ry } => {
+                ctx.ellipse(*cx, *cy, *rx, *ry, 0.0, 0.0, 2.0 * PI)?;
+                ctx.stroke();
+            }
+            ShapeKind::Freehand { points } => {
+                if let Some((x, y)) = points.first() {
+                    ctx.move_to(*x, *y);
+                }
+                for (x, y) in points.iter().skip(1) {
+                    ctx.line_to(*x, *y);
+                }
+                ctx.stroke();
+            }
+            ShapeKind::Measure { x1, y1, x2, y2 } => {
+                ctx.move_to(*x1, *y1);
+                ctx.line_to(*x2, *y2);
+                ctx.stroke();
+                let label = annotation.measure_text(*x1, *y1, *x2, *y2);
+                ctx.fill_text(&label, (x1 + x2) / 2.0, (y1 + y2) / 2.0 - annotation.stroke_width() * 2.0)?;
+            }
+            ShapeKind::Text { x, y, text } => {
+                ctx.fill_text(text, *x, *y)?;
+            }
+        }
+    }
+    Ok((canvas, ctx))
+}
+
+/// webp bytes of image with annotation burned in
+pub async fn flatten_webp(annotation: &ImageAnnotation) -> Result<Vec<u8>, JsValue> {
+    let (_, ctx) = flatten_canvas(annotation).await?;
+    let pixels = ctx.get_image_data(0.0, 0.0, annotation.width as f64, annotation.height as f64)?;
+    rgba_to_webp(annotation.width, annotation.height, pixels.data().0)
+        .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
+/// data url of image with annotation burned in, for viewer
+pub async fn flatten_url(annotation: &ImageAnnotation) -> Result<String, JsValue> {
+    let (canvas, _) = flatten_canvas(annotation).await?;
+    canvas.to_data_url()
+}
+
+async fn download_bytes(bytes: &[u8], filename: &str) -> Result<(), JsValue> {
+    let document = window().and_then(|w| w.document()).ok_or("no document")?;
+    let blob = bytes_to_blob(bytes).await?;
+    let url = Url::create_object_url_with_blob(&blob)?;
+    let anchor = document.create_element("a")?.unchecked_into::<HtmlAnchorElement>();
+    anchor.set_href(&url);
+    anchor.set_download(filename);
+    anchor.click();
+    Url::revoke_object_url(&url)
+}
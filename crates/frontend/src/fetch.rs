@@ -1,15 +1,17 @@
+use image::{DynamicImage, RgbaImage};
+use imaging::{renditions, upload_decode, Profile};
 use js_sys::{Array, ArrayBuffer, Uint8Array};
 use ulid::Ulid;
 use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{Blob, File, FileList, FormData, Headers, RequestInit, Response, window};
-use model::ImageData;
-
-use crate::{
-    abort::Abort,
-    image_parser::image_bytes_parser,
+use web_sys::{
+    Blob, CanvasRenderingContext2d, File, FileList, FormData, Headers, HtmlCanvasElement, HtmlFormElement,
+    HtmlImageElement, HtmlInputElement, RequestInit, Response, Url, window,
 };
+use model::{ImageAnnotation, ImageData, ImageVersion, NearDuplicate};
+
+use crate::abort::Abort;
 
 pub async fn get_first_images() -> Result<Vec<ImageData>, String> {
 
@@ -49,6 +51,48 @@ pub async fn get_second_images() -> Result<Vec<ImageData>, String> {
     }
 }
 
+pub async fn get_first_similar(image_ids: &[u32]) -> Result<Vec<NearDuplicate>, String> {
+
+    let ids = image_ids.iter().map(u32::to_string).collect::<Vec<String>>().join(",");
+    let url = ["/api/first/1/similar?image_ids=", &ids].join("");
+    match fetch_json_api(&url, "GET", None).await {
+        Ok((response, true)) => {
+            let response: Vec<NearDuplicate> = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
+pub async fn get_second_similar(image_ids: &[u32]) -> Result<Vec<NearDuplicate>, String> {
+
+    let ids = image_ids.iter().map(u32::to_string).collect::<Vec<String>>().join(",");
+    let url = ["/api/second/1/similar?image_ids=", &ids].join("");
+    match fetch_json_api(&url, "GET", None).await {
+        Ok((response, true)) => {
+            let response: Vec<NearDuplicate> = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
 pub async fn post_first_images(
     images: &[Rc<ImageData>],
 ) -> Result<Vec<String>, String> {
@@ -121,6 +165,93 @@ pub async fn put_image(
     }
 }
 
+pub async fn put_first_order(ids: &[u32]) -> Result<Vec<String>, String> {
+
+    let body_json = serde_json::to_string(&ids).map_err(|e| e.to_string())?;
+    let body = serde_wasm_bindgen::to_value(&body_json).map_err(|e| e.to_string())?;
+
+    match fetch_json_api("/api/first/1", "PUT", Some(&body)).await {
+        Ok((response, true)) => {
+            let response: Vec<String> = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
+pub async fn get_image_versions(image_id: u32) -> Result<Vec<ImageVersion>, String> {
+
+    let url = ["/api/image/", &image_id.to_string(), "/versions"].join("");
+    match fetch_json_api(&url, "GET", None).await {
+        Ok((response, true)) => {
+            let response: Vec<ImageVersion> = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
+pub async fn post_image_revert(image_id: u32, version: u32) -> Result<ImageData, String> {
+
+    let url = ["/api/image/", &image_id.to_string(), "/versions/", &version.to_string()].join("");
+    match fetch_json_api(&url, "POST", None).await {
+        Ok((response, true)) => {
+            let response: ImageData = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
+pub async fn put_image_annotation(
+    annotation: &ImageAnnotation,
+) -> Result<Vec<String>, String> {
+
+    let body_json = serde_json::to_string(annotation).map_err(|e| e.to_string())?;
+    let body = serde_wasm_bindgen::to_value(&body_json).map_err(|e| e.to_string())?;
+
+    let url = ["/api/image/", &annotation.image_id.to_string(), "/annotation"].join("");
+    match fetch_json_api(&url, "PUT", Some(&body)).await {
+        Ok((response, true)) => {
+            let response: Vec<String> = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
 pub async fn delete_first_images(ids: &[u32]) -> Result<Vec<String>, String> {
 
     let body_json = serde_json::to_string(&ids).map_err(|e| e.to_string())?;
@@ -143,6 +274,28 @@ pub async fn delete_first_images(ids: &[u32]) -> Result<Vec<String>, String> {
     }
 }
 
+pub async fn put_second_order(ids: &[u32]) -> Result<Vec<String>, String> {
+
+    let body_json = serde_json::to_string(&ids).map_err(|e| e.to_string())?;
+    let body = serde_wasm_bindgen::to_value(&body_json).map_err(|e| e.to_string())?;
+
+    match fetch_json_api("/api/second/1", "PUT", Some(&body)).await {
+        Ok((response, true)) => {
+            let response: Vec<String> = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
 pub async fn delete_second_images(ids: &[u32]) -> Result<Vec<String>, String> {
 
     let body_json = serde_json::to_string(&ids).map_err(|e| e.to_string())?;
@@ -200,18 +353,33 @@ pub async fn fetch_json_api(
 pub async fn post_files(
     filelist: &FileList,
 ) -> Result<Vec<ImageData>, String> {
-    let form_data = FormData::new().unwrap();
+    let form_data = FormData::new().map_err(js_error)?;
     for i in 0..filelist.length() {
         if let Some(file) = filelist.item(i) {
-            let file_buf = file_to_bytes(&file).await.unwrap();
+            let file_buf = file_to_bytes(&file).await.map_err(js_error)?;
             // Parse image
-            let (image, thumb) = image_bytes_parser(&file_buf).unwrap();
-
-            let image_blob = bytes_to_blob(&image).await.unwrap();
-            let thumb_blob = bytes_to_blob(&thumb).await.unwrap();
+            let (raw_image, metadata) = match upload_decode(&file_buf) {
+                Ok(decoded) => decoded,
+                // HEIC/AVIF or other format browser can show
+                Err(e) => match browser_decode(&file).await {
+                    Ok(raw_image) => (raw_image, None),
+                    Err(_) => return Err([file.name(), String::from(": "), e.to_string()].concat()),
+                },
+            };
+            let (image, thumb) = renditions(raw_image, Profile::IMAGE, Profile::THUMB)
+                .map_err(|e| [file.name(), String::from(": "), e.to_string()].concat())?;
+
+            let image_blob = bytes_to_blob(&image).await.map_err(js_error)?;
+            let thumb_blob = bytes_to_blob(&thumb).await.map_err(js_error)?;
             let path_with_filename = new_ulid_to_path();
-            form_data.append_with_blob_and_filename("images", &image_blob, &path_with_filename).unwrap();
-            form_data.append_with_blob_and_filename("thumbs", &thumb_blob, &path_with_filename).unwrap();
+            if let Some(metadata) = metadata {
+                // must be sent before thumbs field of the same file
+                let json = serde_json::to_string(&metadata).map_err(|e| e.to_string())?;
+                let metadata_blob = bytes_to_blob(json.as_bytes()).await.map_err(js_error)?;
+                form_data.append_with_blob_and_filename("metadata", &metadata_blob, &path_with_filename).map_err(js_error)?;
+            }
+            form_data.append_with_blob_and_filename("images", &image_blob, &path_with_filename).map_err(js_error)?;
+            form_data.append_with_blob_and_filename("thumbs", &thumb_blob, &path_with_filename).map_err(js_error)?;
         }
     }
     match post_multipart("/api/image", &form_data).await {
@@ -231,6 +399,78 @@ pub async fn post_files(
     }
 }
 
+/// upload edited (image, thumbnail) as new files of `image_id`
+pub async fn post_image_edit(
+    image_id: u32,
+    image: &[u8],
+    thumb: &[u8],
+    redacted: bool,
+) -> Result<ImageData, String> {
+    let form_data = FormData::new().map_err(js_error)?;
+    let image_blob = bytes_to_blob(image).await.map_err(js_error)?;
+    let thumb_blob = bytes_to_blob(thumb).await.map_err(js_error)?;
+    let path_with_filename = new_ulid_to_path();
+    form_data.append_with_blob_and_filename("images", &image_blob, &path_with_filename).map_err(js_error)?;
+    form_data.append_with_blob_and_filename("thumbs", &thumb_blob, &path_with_filename).map_err(js_error)?;
+    if redacted {
+        // previous versions are moved to restricted storage
+        form_data.append_with_str("redacted", "true").map_err(js_error)?;
+    }
+
+    match post_multipart(&["/api/image/", &image_id.to_string()].join(""), &form_data).await {
+        Ok((response, true)) => {
+            let response: ImageData = serde_wasm_bindgen::from_value(response)
+                .map_err(|e| e.to_string())?;
+            Ok(response)
+        }
+        Ok((app_error, false)) => {
+            let error: String = serde_wasm_bindgen::from_value(app_error)
+                .map_err(|e| e.to_string())?;
+            Err(error)
+        }
+        Err(e) => {
+            Err(e.as_string().unwrap_or(String::from("fetch error")))
+        }
+    }
+}
+
+/// download zip of images by hidden form post, browser streams the archive to disk instead of memory
+pub fn download_images_zip(image_ids: &[u32]) -> Result<(), String> {
+    let document = window().and_then(|w| w.document()).ok_or(String::from("no document"))?;
+    let form = document.create_element("form").map_err(js_error)?.unchecked_into::<HtmlFormElement>();
+    form.set_method("POST");
+    form.set_action("/api/image/zip");
+    form.set_attribute("style", "display:none").map_err(js_error)?;
+    let input = document.create_element("input").map_err(js_error)?.unchecked_into::<HtmlInputElement>();
+    input.set_type("hidden");
+    input.set_name("ids");
+    input.set_value(&image_ids.iter().map(|id| id.to_string()).collect::<Vec<String>>().join(","));
+    form.append_child(&input).map_err(js_error)?;
+    let body = document.body().ok_or(String::from("no document body"))?;
+    body.append_child(&form).map_err(js_error)?;
+    form.submit().map_err(js_error)?;
+    form.remove();
+    Ok(())
+}
+
+/// get raw bytes, ex. original image for editing
+pub async fn fetch_bytes(url: &str) -> Result<Vec<u8>, JsValue> {
+    let abort = Abort::new()?;
+
+    let w = window().unwrap();
+    let init = RequestInit::new();
+    init.set_method("GET");
+    init.set_signal(Some(&abort.signal()));
+    let future = w.fetch_with_str_and_init(url, &init);
+
+    let response = JsFuture::from(future).await?.unchecked_into::<Response>();
+    if !response.ok() {
+        return Err(JsValue::from_str(&["fetch ", url, " failed with status ", &response.status().to_string()].join("")));
+    }
+    let buffer = JsFuture::from(response.array_buffer()?).await?.unchecked_into::<ArrayBuffer>();
+    Ok(Uint8Array::new(&buffer).to_vec())
+}
+
 async fn post_multipart(
     url: &str,
     body: &FormData,
@@ -277,7 +517,36 @@ async fn file_to_bytes(file: &File) -> Result<Vec<u8>, JsValue> {
     Ok(file_buf)
 }
 
-async fn bytes_to_blob(bytes: &[u8]) -> Result<Blob, JsValue> {
+/// decode with browser, for formats `imaging` cannot decode in wasm, ex. HEIC in Safari and AVIF
+async fn browser_decode(file: &File) -> Result<DynamicImage, JsValue> {
+    let document = window().and_then(|w| w.document()).ok_or("no document")?;
+    let url = Url::create_object_url_with_blob(file)?;
+    let image = HtmlImageElement::new()?;
+    image.set_src(&url);
+    let decoded = JsFuture::from(image.decode()).await;
+    Url::revoke_object_url(&url)?;
+    decoded?;
+
+    // EXIF orientation is applied to natural size
+    let (width, height) = (image.natural_width(), image.natural_height());
+    let canvas = document.create_element("canvas")?.unchecked_into::<HtmlCanvasElement>();
+    canvas.set_width(width);
+    canvas.set_height(height);
+    let ctx = canvas.get_context("2d")?
+        .ok_or("no canvas context")?
+        .unchecked_into::<CanvasRenderingContext2d>();
+    ctx.draw_image_with_html_image_element(&image, 0.0, 0.0)?;
+    let pixels = ctx.get_image_data(0.0, 0.0, width as f64, height as f64)?;
+    RgbaImage::from_raw(width, height, pixels.data().0)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| JsValue::from_str("canvas size mismatch"))
+}
+
+fn js_error(e: JsValue) -> String {
+    e.as_string().unwrap_or_else(|| format!("{:?}", e))
+}
+
+pub async fn bytes_to_blob(bytes: &[u8]) -> Result<Blob, JsValue> {
     // [u8] to Uint8Array
     let img_u8a = Uint8Array::new_with_length(bytes.len() as u32);
     img_u8a.copy_from(bytes);
@@ -1,4 +1,4 @@
-use dominator::{clone, Dom, events, html, with_node};
+use dominator::{clone, Dom, EventOptions, events, html, with_node};
 use futures_signals::{
     map_ref,
     signal::{not, Mutable, Signal, SignalExt},
@@ -9,17 +9,21 @@ use std::{
     sync::atomic::{AtomicUsize, Ordering},
 };
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
 use web_sys::{HtmlButtonElement, HtmlInputElement};
-use model::ImageData;
+use model::{ImageData, NearDuplicate};
 
 use crate::{
     App,
+    annotation::{annotation_svg, flatten_url, AnnotationEditorCpn},
     binding::{Viewer, ViewerOption},
     fetch::{
-        post_files, put_image,
-        get_first_images, post_first_images, delete_first_images,
-        get_second_images, post_second_images, delete_second_images,
+        download_images_zip, post_files, post_image_edit, post_image_revert, put_image,
+        get_first_images, get_first_similar, post_first_images, put_first_order, delete_first_images,
+        get_second_images, get_second_similar, post_second_images, put_second_order, delete_second_images,
     },
+    image_editor::ImageEditorCpn,
+    image_history::ImageHistoryCpn,
     mixins, str_some,
 };
 
@@ -45,6 +49,9 @@ const ANIMATE_ROTATION_STYLE: &str = r#"
   100% {rotate:-15deg}
 }"#;
 
+// uploads waiting for confirmation, with their possible duplicates in gallery
+type PendingUploads = (Vec<Rc<ImageData>>, Vec<NearDuplicate>);
+
 #[derive(Clone)]
 pub enum ImageOf {
     First,
@@ -64,9 +71,16 @@ pub struct ImageCpn {
     image_datas: MutableVec<Rc<ImageData>>,
 
     selected: Mutable<Vec<Rc<ImageData>>>,
+    dragged: Mutable<Option<Rc<ImageData>>>,
     edited: Mutable<Option<ImageData>>,
     old_title: Mutable<String>,
     edited_title: Mutable<String>,
+    editor: Mutable<Option<Rc<ImageEditorCpn>>>,
+    annotator: Mutable<Option<Rc<AnnotationEditorCpn>>>,
+    history: Mutable<Option<Rc<ImageHistoryCpn>>>,
+    similar: Mutable<Option<PendingUploads>>,
+    // file which cannot be read, nothing of the batch is uploaded
+    upload_error: Mutable<Option<String>>,
 }
 
 impl ImageCpn {
@@ -84,9 +98,15 @@ impl ImageCpn {
             viewer: Mutable::new(None),
             image_datas: MutableVec::new(),
             selected: Mutable::new(Vec::new()),
+            dragged: Mutable::new(None),
             edited: Mutable::new(None),
             old_title: Mutable::new(String::new()),
             edited_title: Mutable::new(String::new()),
+            editor: Mutable::new(None),
+            annotator: Mutable::new(None),
+            history: Mutable::new(None),
+            similar: Mutable::new(None),
+            upload_error: Mutable::new(None),
         })
     }
 
@@ -94,6 +114,16 @@ impl ImageCpn {
         self.selected.signal_cloned().map(|v| !v.is_empty())
     }
 
+    /// editor, annotator or history is shown instead of images
+    fn has_panel_signal(&self) -> impl Signal<Item = bool> {
+        map_ref! {
+            let editor = self.editor.signal_ref(|editor| editor.is_some()),
+            let annotator = self.annotator.signal_ref(|annotator| annotator.is_some()),
+            let history = self.history.signal_ref(|history| history.is_some()) =>
+            *editor || *annotator || *history
+        }
+    }
+
     fn viewer_id(&self) -> String {
         ["images-list-", &self.id.to_string()].join("")
     }
@@ -124,6 +154,14 @@ impl ImageCpn {
         lock.extend(image_datas.into_iter().map(Rc::new));
     }
 
+    /// `dicom` is zip of DICOM Secondary Capture files for PACS, `pdf` is printable report
+    fn export_url(&self, format: &str) -> String {
+        match self.use_at {
+            ImageOf::First => ["api/first/1/", format].concat(),
+            ImageOf::Second => ["api/second/1/", format].concat(),
+        }
+    }
+
     async fn post_images(&self, images: &[Rc<ImageData>]) {
         match self.use_at {
             ImageOf::First => post_first_images(images).await.unwrap(),
@@ -131,6 +169,36 @@ impl ImageCpn {
         };
     }
 
+    /// possible duplicates of uploads, upload goes on when the check fails
+    async fn get_similar(&self, images: &[Rc<ImageData>]) -> Vec<NearDuplicate> {
+        let ids = images.iter().map(|image| image.image_id).collect::<Vec<u32>>();
+        let result = match self.use_at {
+            ImageOf::First => get_first_similar(&ids).await,
+            ImageOf::Second => get_second_similar(&ids).await,
+        };
+        result.unwrap_or_else(|e| {
+            log::error!("cannot check similar images: {}", e);
+            Vec::new()
+        })
+    }
+
+    /// save uploads after "possible duplicate" warning
+    fn save_similar(page: Rc<Self>, app: Rc<App>) {
+        if let Some((images, _)) = page.similar.replace(None) {
+            app.loader.load(clone!(page => async move {
+                page.post_images(&images).await;
+                page.loaded.set(false);
+            }));
+        }
+    }
+
+    async fn put_order(&self, ids: &[u32]) {
+        match self.use_at {
+            ImageOf::First => put_first_order(ids).await.unwrap(),
+            ImageOf::Second => put_second_order(ids).await.unwrap(),
+        };
+    }
+
     async fn delete_images(&self, ids: &[u32]) {
         match self.use_at {
             ImageOf::First => delete_first_images(ids).await.unwrap(),
@@ -148,6 +216,215 @@ impl ImageCpn {
         }
     }
 
+    fn open_editor(page: Rc<Self>, app: Rc<App>) {
+        if let Some(image) = page.edited.get_cloned() {
+            app.loader.load(clone!(page => async move {
+                match ImageEditorCpn::load(&image).await {
+                    Ok(editor) => page.editor.set(Some(editor)),
+                    Err(e) => log::error!("cannot open image {} for editing: {}", image.image_id, e),
+                }
+            }));
+        }
+    }
+
+    fn save_editor(page: Rc<Self>, app: Rc<App>) {
+        if let Some(editor) = page.editor.get_cloned() {
+            app.loader.load(clone!(app, page => async move {
+                match editor.renditions() {
+                    Ok((image, thumb)) => {
+                        if let Err(e) = post_image_edit(editor.image_id, &image, &thumb, editor.is_redacted()).await {
+                            log::error!("cannot save edited image {}: {}", editor.image_id, e);
+                        }
+                    }
+                    Err(e) => log::error!("cannot edit image {}: {}", editor.image_id, e),
+                }
+                page.close_panel();
+                page.get_images().await;
+                page.viewer_destroy();
+                Self::viewer_render(page, app);
+            }));
+        }
+    }
+
+    fn open_history(page: Rc<Self>, app: Rc<App>) {
+        if let Some(image) = page.edited.get_cloned() {
+            app.loader.load(clone!(page => async move {
+                match ImageHistoryCpn::load(&image).await {
+                    Ok(history) => page.history.set(Some(history)),
+                    Err(e) => log::error!("cannot load history of image {}: {}", image.image_id, e),
+                }
+            }));
+        }
+    }
+
+    fn revert_history(page: Rc<Self>, app: Rc<App>, version: u32) {
+        if let Some(history) = page.history.get_cloned() {
+            app.loader.load(clone!(app, page => async move {
+                if let Err(e) = post_image_revert(history.image_id, version).await {
+                    log::error!("cannot revert image {} to version {}: {}", history.image_id, version, e);
+                }
+                page.close_panel();
+                page.get_images().await;
+                page.viewer_destroy();
+                Self::viewer_render(page, app);
+            }));
+        }
+    }
+
+    fn close_panel(&self) {
+        self.editor.set(None);
+        self.annotator.set(None);
+        self.history.set(None);
+        self.edited.set_neq(None);
+        self.select_mode.set(false);
+        self.selected.lock_mut().clear();
+    }
+
+    fn open_annotator(page: Rc<Self>, _app: Rc<App>) {
+        if let Some(image) = page.edited.get_cloned() {
+            page.annotator.set(Some(AnnotationEditorCpn::new(&image)));
+        }
+    }
+
+    fn save_annotator(page: Rc<Self>, app: Rc<App>) {
+        if let Some(annotator) = page.annotator.get_cloned() {
+            app.loader.load(clone!(app, page => async move {
+                if let Err(e) = annotator.save().await {
+                    log::error!("cannot save annotation of image {}: {}", annotator.image_id(), e);
+                }
+                page.close_panel();
+                page.get_images().await;
+                page.viewer_destroy();
+                Self::viewer_render(page, app);
+            }));
+        }
+    }
+
+    /// button for action on a single selected image
+    fn single_button(text: &str, page: Rc<Self>, app: Rc<App>, on_click: fn(Rc<Self>, Rc<App>)) -> Dom {
+        html!("button" => HtmlButtonElement, {
+            .attr("type","button")
+            .class(["btn","btn-sm","btn-primary","me-1"])
+            .text(text)
+            .apply(mixins::other_true_signal_disable(map_ref! {
+                let busy = app.loader.is_loading(),
+                let has_panel = page.has_panel_signal() =>
+                *busy || *has_panel
+            }))
+            .event(clone!(app, page => move |_: events::Click| {
+                on_click(page.clone(), app.clone());
+            }))
+        })
+    }
+
+    /// move image to `to` index then save the new order
+    fn move_image(page: Rc<Self>, app: Rc<App>, image_id: u32, to: usize) {
+        let ids;
+        {
+            let mut lock = page.image_datas.lock_mut();
+            let Some(from) = lock.iter().position(|data| data.image_id == image_id) else {
+                return;
+            };
+            if from == to || to >= lock.len() {
+                return;
+            }
+            lock.move_from_to(from, to);
+            ids = lock.iter().map(|data| data.image_id).collect::<Vec<u32>>();
+        }
+        app.loader.load(clone!(app, page => async move {
+            page.put_order(&ids).await;
+            // viewer navigation follows DOM order
+            if !page.select_mode.get() {
+                Self::viewer_render(page, app);
+            }
+        }));
+    }
+
+    /// move image by `step` from current index
+    fn step_image(page: Rc<Self>, app: Rc<App>, image_id: u32, step: isize) {
+        let to = page.image_datas.lock_ref().iter()
+            .position(|data| data.image_id == image_id)
+            .and_then(|from| from.checked_add_signed(step));
+        if let Some(to) = to {
+            Self::move_image(page, app, image_id, to);
+        }
+    }
+
+    /// file of upload which neither `imaging` nor browser can decode, ex. HEIC outside Safari
+    fn render_upload_error(e: &str, page: Rc<Self>) -> Dom {
+        html!("div", {
+            .class(["alert","alert-danger","rounded-0","m-0","p-2","d-flex","align-items-center"])
+            .child(html!("span", {
+                .class("me-auto")
+                .text(&["อ่านรูปไม่ได้ ", e].concat())
+            }))
+            .child(html!("button", {
+                .attr("type","button")
+                .class(["btn","btn-sm","btn-secondary"])
+                .text("ปิด")
+                .event(clone!(page => move |_: events::Click| {
+                    page.upload_error.set(None);
+                }))
+            }))
+        })
+    }
+
+    /// "possible duplicate" warning, thumbnails of upload and similar gallery image side by side
+    fn render_similar(uploads: &[Rc<ImageData>], similar: &[NearDuplicate], page: Rc<Self>, app: Rc<App>) -> Dom {
+        html!("div", {
+            .class(["alert","alert-warning","rounded-0","m-0","p-2"])
+            .child(html!("div", {
+                .class("mb-2")
+                .text("รูปที่เพิ่มอาจซ้ำกับรูปที่มีอยู่แล้ว")
+            }))
+            .children(similar.iter().map(|duplicate| {
+                let upload = uploads.iter().find(|image| image.image_id == duplicate.image_id)
+                    .map(|image| image.path.clone())
+                    .unwrap_or_default();
+                html!("div", {
+                    .class(["d-flex","align-items-center","mb-1"])
+                    .children(&mut [
+                        html!("img", {
+                            .attr("src", &["thumbs", &upload].join("/"))
+                            .style("width","64px")
+                        }),
+                        html!("i", {
+                            .class(["fas","fa-arrows-left-right","mx-2"])
+                        }),
+                        html!("img", {
+                            .attr("src", &["thumbs", &duplicate.similar.path].join("/"))
+                            .attr("alt", &duplicate.similar.title.clone().unwrap_or(String::from("ไม่มีคำบรรยาย")))
+                            .style("width","64px")
+                        }),
+                        html!("span", {
+                            .class(["ms-2","small"])
+                            .text(&["ต่างกัน ", &duplicate.distance.to_string(), "/64"].concat())
+                        }),
+                    ])
+                })
+            }))
+            .children(&mut [
+                html!("button" => HtmlButtonElement, {
+                    .attr("type","button")
+                    .class(["btn","btn-sm","btn-primary","me-1"])
+                    .text("บันทึกต่อ")
+                    .apply(mixins::other_true_signal_disable(app.loader.is_loading()))
+                    .event(clone!(app, page => move |_: events::Click| {
+                        Self::save_similar(page.clone(), app.clone());
+                    }))
+                }),
+                html!("button", {
+                    .attr("type","button")
+                    .class(["btn","btn-sm","btn-secondary"])
+                    .text("ยกเลิก")
+                    .event(clone!(page => move |_: events::Click| {
+                        page.similar.set(None);
+                    }))
+                }),
+            ])
+        })
+    }
+
     /// header height=48px, title input height=36px, the rest is images_max_height
     pub fn render(images_max_height: &'static str, page: Rc<Self>, app: Rc<App>) -> Dom {
 
@@ -200,7 +477,7 @@ impl ImageCpn {
                                         .text("เพิ่มรูป")
                                         .child(html!("input" => HtmlInputElement, {
                                             .attr("type","file")
-                                            .attr("accept","image/*")
+                                            .attr("accept","image/*,.heic,.heif,.avif,.dcm,application/dicom")
                                             .attr("capture","environment")
                                             .attr("multiple","")
                                             .class("d-none")
@@ -211,11 +488,25 @@ impl ImageCpn {
                                                     if let Some(files) = file_input.files() {
                                                         if files.length() > 0 {
                                                             app.loader.load(clone!(page => async move {
-                                                                let images = post_files(&files).await.unwrap()
-                                                                    .into_iter().map(Rc::new).collect::<Vec<Rc<ImageData>>>();
-                                                                page.post_images(&images).await;
+                                                                page.upload_error.set(None);
+                                                                let images = match post_files(&files).await {
+                                                                    Ok(images) => images.into_iter().map(Rc::new).collect::<Vec<Rc<ImageData>>>(),
+                                                                    Err(e) => {
+                                                                        log::error!("cannot upload images: {}", e);
+                                                                        file_input.set_value("");
+                                                                        page.upload_error.set(Some(e));
+                                                                        return;
+                                                                    }
+                                                                };
+                                                                let similar = page.get_similar(&images).await;
                                                                 file_input.set_value("");
-                                                                page.loaded.set(false);
+                                                                if similar.is_empty() {
+                                                                    page.post_images(&images).await;
+                                                                    page.loaded.set(false);
+                                                                } else {
+                                                                    // unsaved uploads are removed by gc
+                                                                    page.similar.set(Some((images, similar)));
+                                                                }
                                                             }));
                                                         }
                                                     }
@@ -237,6 +528,28 @@ impl ImageCpn {
                                             })
                                         })
                                     })))
+                                    .child_signal(page.image_datas.signal_vec_cloned().to_signal_cloned().map(clone!(page => move |datas| {
+                                        (!datas.is_empty()).then(|| {
+                                            html!("span", {
+                                                .children(&mut [
+                                                    html!("a", {
+                                                        .attr("href", &page.export_url("pdf"))
+                                                        .attr("target","_blank")
+                                                        .attr("title","พิมพ์รายงาน PDF")
+                                                        .class(["btn","btn-sm","btn-secondary","me-1"])
+                                                        .text("PDF")
+                                                    }),
+                                                    html!("a", {
+                                                        .attr("href", &page.export_url("dicom"))
+                                                        .attr("download","")
+                                                        .attr("title","ส่งออกเป็นไฟล์ DICOM สำหรับ PACS")
+                                                        .class(["btn","btn-sm","btn-secondary","me-1"])
+                                                        .text("DICOM")
+                                                    }),
+                                                ])
+                                            })
+                                        })
+                                    })))
                                     .child_signal(app.clipboard_images.signal_vec_cloned().to_signal_cloned().map(clone!(app, page => move |datas| {
                                         (!datas.is_empty()).then(|| {
                                             html!("button" => HtmlButtonElement, {
@@ -287,6 +600,30 @@ impl ImageCpn {
                                                             Self::viewer_render(page.clone(), app.clone());
                                                         }))
                                                     }),
+                                                    html!("button", {
+                                                        .attr("type","button")
+                                                        .class(["btn","btn-sm","btn-primary","me-1"])
+                                                        .text("ดาวน์โหลด")
+                                                        .event(clone!(page => move |_: events::Click| {
+                                                            let ids = page.selected.lock_ref().iter().map(|image| image.image_id).collect::<Vec<u32>>();
+                                                            if let Err(e) = download_images_zip(&ids) {
+                                                                log::error!("cannot download images: {}", e);
+                                                            }
+                                                        }))
+                                                    }),
+                                                    html!("span", {
+                                                        .child_signal(page.edited.signal_ref(|edited| edited.is_some()).map(clone!(app, page => move |is_single| {
+                                                            is_single.then(|| {
+                                                                html!("span", {
+                                                                    .children(&mut [
+                                                                        Self::single_button("แก้ไขรูป", page.clone(), app.clone(), Self::open_editor),
+                                                                        Self::single_button("วาดเขียน", page.clone(), app.clone(), Self::open_annotator),
+                                                                        Self::single_button("ประวัติ", page.clone(), app.clone(), Self::open_history),
+                                                                    ])
+                                                                })
+                                                            })
+                                                        })))
+                                                    }),
                                                     html!("button" => HtmlButtonElement, {
                                                         .attr("type","button")
                                                         .class(["btn","btn-sm","btn-danger","me-1"])
@@ -341,9 +678,7 @@ impl ImageCpn {
                                         .class(["btn","btn-sm","btn-secondary","me-1"])
                                         .text("ยกเลิก")
                                         .event(clone!(app, page => move |_: events::Click| {
-                                            page.edited.set_neq(None);
-                                            page.select_mode.set(false);
-                                            page.selected.lock_mut().clear();
+                                            page.close_panel();
                                             Self::viewer_render(page.clone(), app.clone());
                                         }))
                                     }))
@@ -353,11 +688,71 @@ impl ImageCpn {
                     }),
                     html!("div", {
                         .class(["card-body","p-0"])
+                        .child_signal(page.upload_error.signal_cloned().map(clone!(page => move |opt| {
+                            opt.map(|e| Self::render_upload_error(&e, page.clone()))
+                        })))
+                        .child_signal(page.similar.signal_cloned().map(clone!(app, page => move |opt| {
+                            opt.map(|(uploads, similar)| Self::render_similar(&uploads, &similar, page.clone(), app.clone()))
+                        })))
+                        .child_signal(page.editor.signal_cloned().map(clone!(app, page => move |opt| {
+                            opt.map(|editor| {
+                                ImageEditorCpn::render(editor, app.clone(), clone!(app, page => move || {
+                                    Self::save_editor(page.clone(), app.clone());
+                                }), clone!(app, page => move || {
+                                    page.close_panel();
+                                    Self::viewer_render(page.clone(), app.clone());
+                                }))
+                            })
+                        })))
+                        .child_signal(page.annotator.signal_cloned().map(clone!(app, page => move |opt| {
+                            opt.map(|annotator| {
+                                AnnotationEditorCpn::render(annotator, app.clone(), clone!(app, page => move || {
+                                    Self::save_annotator(page.clone(), app.clone());
+                                }), clone!(app, page => move || {
+                                    page.close_panel();
+                                    Self::viewer_render(page.clone(), app.clone());
+                                }))
+                            })
+                        })))
+                        .child_signal(page.history.signal_cloned().map(clone!(app, page => move |opt| {
+                            opt.map(|history| {
+                                ImageHistoryCpn::render(history, app.clone(), clone!(app, page => move |version| {
+                                    Self::revert_history(page.clone(), app.clone(), version);
+                                }), clone!(app, page => move || {
+                                    page.close_panel();
+                                    Self::viewer_render(page.clone(), app.clone());
+                                }))
+                            })
+                        })))
                         .child_signal(page.edited.signal_cloned().map(clone!(app, page => move |opt| {
                             opt.map(|image| {
                                 html!("div", {
                                     .class(["input-group","w-100"])
                                     .children(&mut [
+                                        html!("button" => HtmlButtonElement, {
+                                            .attr("type","button")
+                                            .attr("title","เลื่อนไปก่อนหน้า")
+                                            .class(["btn","btn-sm","btn-secondary","rounded-0"])
+                                            .child(html!("i", {
+                                                .class(["fas","fa-arrow-left"])
+                                            }))
+                                            .apply(mixins::other_true_signal_disable(app.loader.is_loading()))
+                                            .event(clone!(app, page, image => move |_:events::Click| {
+                                                Self::step_image(page.clone(), app.clone(), image.image_id, -1);
+                                            }))
+                                        }),
+                                        html!("button" => HtmlButtonElement, {
+                                            .attr("type","button")
+                                            .attr("title","เลื่อนไปถัดไป")
+                                            .class(["btn","btn-sm","btn-secondary"])
+                                            .child(html!("i", {
+                                                .class(["fas","fa-arrow-right"])
+                                            }))
+                                            .apply(mixins::other_true_signal_disable(app.loader.is_loading()))
+                                            .event(clone!(app, page, image => move |_:events::Click| {
+                                                Self::step_image(page.clone(), app.clone(), image.image_id, 1);
+                                            }))
+                                        }),
                                         html!("input" => HtmlInputElement, {
                                             .class(["form-control","form-control-sm","rounded-0"])
                                             .attr("value", &image.title.clone().unwrap_or_default())
@@ -413,6 +808,7 @@ impl ImageCpn {
                             })
                         })))
                         .child(html!("div", {
+                            .visible_signal(not(page.has_panel_signal()))
                             .child(html!("div", {
                                 .style("overflow-y","auto")
                                 .style("max-height", images_max_height)
@@ -420,13 +816,66 @@ impl ImageCpn {
                                     .class(["d-flex","flex-wrap","bg-secondary","m-0","p-0"])
                                     .attr("id", &page.viewer_id())
                                     .style("list-style-type","none")
-                                    .children_signal_vec(page.image_datas.signal_vec_cloned().map(clone!(page => move |image_data| {
+                                    .children_signal_vec(page.image_datas.signal_vec_cloned().map(clone!(app, page => move |image_data| {
                                         html!("li", {
                                             .class("position-relative")
                                             .style("margin","1px")
                                             .style("flex-grow","1")
+                                            // drag or Alt+Arrow to reorder, only in view mode
+                                            .attr("tabindex","0")
+                                            .attr_signal("draggable", map_ref! {
+                                                let busy = app.loader.is_loading(),
+                                                let select_mode = page.select_mode.signal() =>
+                                                if *busy || *select_mode {"false"} else {"true"}
+                                            })
+                                            .style_signal("opacity", page.dragged.signal_cloned().map(clone!(image_data => move |dragged| {
+                                                if dragged.as_ref() == Some(&image_data) {"0.5"} else {"1"}
+                                            })))
+                                            .event(clone!(page, image_data => move |e: events::DragStart| {
+                                                if let Some(data_transfer) = e.data_transfer() {
+                                                    data_transfer.set_effect_allowed("move");
+                                                    // firefox will not start dragging without data
+                                                    let _ = data_transfer.set_data("text/plain", &image_data.image_id.to_string());
+                                                }
+                                                page.dragged.set(Some(image_data.clone()));
+                                            }))
+                                            .event_with_options(&EventOptions::preventable(), clone!(page => move |e: events::DragOver| {
+                                                if page.dragged.lock_ref().is_some() {
+                                                    // allow dropping here
+                                                    e.prevent_default();
+                                                }
+                                            }))
+                                            .event_with_options(&EventOptions::preventable(), clone!(app, page, image_data => move |e: events::Drop| {
+                                                if let Some(dragged) = page.dragged.replace(None) {
+                                                    e.prevent_default();
+                                                    let to = page.image_datas.lock_ref().iter().position(|data| *data == image_data);
+                                                    if let Some(to) = to {
+                                                        Self::move_image(page.clone(), app.clone(), dragged.image_id, to);
+                                                    }
+                                                }
+                                            }))
+                                            .event(clone!(page => move |_: events::DragEnd| {
+                                                page.dragged.set(None);
+                                            }))
+                                            .with_node!(element => {
+                                                .event(clone!(app, page, image_data => move |e: events::KeyDown| {
+                                                    if e.alt_key() && !page.select_mode.get() && !app.loader.is_loading_now() {
+                                                        let step = match e.key().as_str() {
+                                                            "ArrowLeft" | "ArrowUp" => -1,
+                                                            "ArrowRight" | "ArrowDown" => 1,
+                                                            _ => return,
+                                                        };
+                                                        Self::step_image(page.clone(), app.clone(), image_data.image_id, step);
+                                                        // moved node lose focus, refocus after DOM was updated
+                                                        spawn_local(clone!(element => async move {
+                                                            let _ = element.focus();
+                                                        }));
+                                                    }
+                                                }))
+                                            })
                                             .child(html!("img", {
                                                 .class("w-100")
+                                                .attr("draggable","false")
                                                 .style_signal("cursor", page.select_mode.signal().map(|is_select| {
                                                     if is_select {
                                                         "pointer"
@@ -436,6 +885,26 @@ impl ImageCpn {
                                                 }))
                                                 .attr("data-original", &["images", &image_data.path].join("/"))
                                                 .attr("src", &["thumbs", &image_data.path].join("/"))
+                                                .apply(|dom| {
+                                                    // viewer show annotated image
+                                                    if let Some(annotation) = image_data.annotation.clone() {
+                                                        with_node!(dom, element => {
+                                                            .future(clone!(page => async move {
+                                                                match flatten_url(&annotation).await {
+                                                                    Ok(url) => {
+                                                                        let _ = element.set_attribute("data-original", &url);
+                                                                        if let Some(viewer) = page.viewer.get_cloned() {
+                                                                            viewer.update();
+                                                                        }
+                                                                    }
+                                                                    Err(e) => log::error!("cannot flatten annotation of image {}: {:?}", annotation.image_id, e),
+                                                                }
+                                                            }))
+                                                        })
+                                                    } else {
+                                                        dom
+                                                    }
+                                                })
                                                 .attr("alt", &image_data.title.clone().unwrap_or(String::from("ไม่มีคำบรรยาย")))
                                                 .event(clone!(page, image_data => move |_:events::Click| {
                                                     if page.select_mode.get() {
@@ -457,6 +926,25 @@ impl ImageCpn {
                                                     }
                                                 }))
                                             }))
+                                            .apply(|dom| {
+                                                if let Some(annotation) = &image_data.annotation {
+                                                    dom.child(annotation_svg(annotation, true))
+                                                } else {
+                                                    dom
+                                                }
+                                            })
+                                            .apply(|dom| {
+                                                if let Some(metadata) = &image_data.metadata {
+                                                    dom.child(html!("span", {
+                                                        .class(["badge","text-bg-info","position-absolute","start-0","top-0","m-1"])
+                                                        .attr("title", &metadata.summary())
+                                                        .style("font-size","8px")
+                                                        .text(metadata.modality.as_deref().unwrap_or("DICOM"))
+                                                    }))
+                                                } else {
+                                                    dom
+                                                }
+                                            })
                                             .child_signal(page.selected.signal_cloned().map(clone!(image_data => move |selected| {
                                                 selected.contains(&image_data).then(|| {
                                                     html!("i", {
@@ -0,0 +1,290 @@
+use dominator::{clone, Dom, events, html, with_node};
+use futures_signals::{
+    map_ref,
+    signal::{Mutable, Signal, SignalExt},
+};
+use image::{DynamicImage, ImageResult};
+use imaging::{
+    edit::{image_preview, image_transform, CropRect, ImageEdit, RedactMode, Redaction},
+    image_decode, renditions, Profile,
+};
+use std::{cell::Cell, rc::Rc};
+use web_sys::{HtmlButtonElement, Url};
+use model::ImageData;
+
+use crate::{
+    App,
+    fetch::{bytes_to_blob, fetch_bytes},
+    mixins,
+};
+
+/// what dragging on preview does
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DragTool {
+    Crop,
+    Redact(RedactMode),
+}
+
+/// rotate, flip, redact and crop an image, result is a new version of image
+pub struct ImageEditorCpn {
+    pub image_id: u32,
+    source: DynamicImage,
+    edit: Mutable<ImageEdit>,
+    preview_url: Mutable<Option<String>>,
+    tool: Mutable<Option<DragTool>>,
+    // pointer down point while dragging, as fraction of preview
+    drag_start: Cell<Option<(f64, f64)>>,
+    // redaction area being dragged
+    drag_rect: Mutable<Option<CropRect>>,
+}
+
+impl ImageEditorCpn {
+    /// load original image of `image_data`
+    pub async fn load(image_data: &ImageData) -> Result<Rc<Self>, String> {
+        let raw_data = fetch_bytes(&["images", &image_data.path].join("/")).await
+            .map_err(|e| e.as_string().unwrap_or(String::from("fetch error")))?;
+        let source = image_decode(&raw_data).map_err(|e| e.to_string())?;
+        Ok(Rc::new(Self {
+            image_id: image_data.image_id,
+            source,
+            edit: Mutable::new(ImageEdit::default()),
+            preview_url: Mutable::new(None),
+            tool: Mutable::new(None),
+            drag_start: Cell::new(None),
+            drag_rect: Mutable::new(None),
+        }))
+    }
+
+    /// saving redacted image will restrict previous versions
+    pub fn is_redacted(&self) -> bool {
+        self.edit.lock_ref().is_redacted()
+    }
+
+    /// (image, thumbnail) webp bytes of edited image
+    pub fn renditions(&self) -> ImageResult<(Vec<u8>, Vec<u8>)> {
+        renditions(image_transform(&self.source, &self.edit.lock_ref()), Profile::IMAGE, Profile::THUMB)
+    }
+
+    fn is_empty_signal(&self) -> impl Signal<Item = bool> {
+        self.edit.signal_ref(|edit| edit.is_empty())
+    }
+
+    async fn update_preview(&self) {
+        let edit = self.edit.get_cloned();
+        match image_preview(&self.source, &edit) {
+            Ok(bytes) => {
+                if let Ok(url) = bytes_to_blob(&bytes).await.and_then(|blob| Url::create_object_url_with_blob(&blob)) {
+                    if let Some(old) = self.preview_url.replace(Some(url)) {
+                        let _ = Url::revoke_object_url(&old);
+                    }
+                }
+            }
+            Err(e) => log::error!("preview error: {}", e),
+        }
+    }
+
+    /// fraction of element size, clamp to 0.0 - 1.0
+    fn point_fraction(offset_x: i32, offset_y: i32, width: i32, height: i32) -> (f64, f64) {
+        let x = offset_x as f64 / width.max(1) as f64;
+        let y = offset_y as f64 / height.max(1) as f64;
+        (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0))
+    }
+
+    fn drag_rect((x1, y1): (f64, f64), (x2, y2): (f64, f64)) -> CropRect {
+        CropRect {
+            x: x1.min(x2),
+            y: y1.min(y2),
+            width: (x1 - x2).abs(),
+            height: (y1 - y2).abs(),
+        }
+    }
+
+    /// ignore click without dragging
+    fn is_too_small(rect: &CropRect) -> bool {
+        rect.width < 0.01 || rect.height < 0.01
+    }
+
+    fn percent(fraction: f64) -> String {
+        [(fraction * 100.0).to_string(), String::from("%")].concat()
+    }
+
+    fn drag_tool_button(page: Rc<Self>, app: &App, icon: &str, title: &str, tool: DragTool) -> Dom {
+        html!("button" => HtmlButtonElement, {
+            .attr("type","button")
+            .attr("title", title)
+            .class(["btn","btn-sm"])
+            .class_signal("btn-secondary", page.tool.signal().map(move |v| v != Some(tool)))
+            .class_signal("btn-warning", page.tool.signal().map(move |v| v == Some(tool)))
+            .child(html!("i", {
+                .class(["fas", icon])
+            }))
+            .apply(mixins::other_true_signal_disable(app.loader.is_loading()))
+            .event(clone!(page => move |_: events::Click| {
+                let current = page.tool.get();
+                page.tool.set(if current == Some(tool) { None } else { Some(tool) });
+            }))
+        })
+    }
+
+    fn tool_button(icon: &str, title: &str, disabled: impl Signal<Item = bool> + 'static, on_click: impl FnMut(events::Click) + 'static) -> Dom {
+        html!("button" => HtmlButtonElement, {
+            .attr("type","button")
+            .attr("title", title)
+            .class(["btn","btn-sm","btn-secondary"])
+            .child(html!("i", {
+                .class(["fas", icon])
+            }))
+            .apply(mixins::other_true_signal_disable(disabled))
+            .event(on_click)
+        })
+    }
+
+    pub fn render<S, C>(page: Rc<Self>, app: Rc<App>, on_save: S, on_cancel: C) -> Dom
+    where
+        S: Fn() + 'static,
+        C: Fn() + 'static,
+    {
+        html!("div", {
+            .class(["p-1","text-center"])
+            .future(page.edit.signal_ref(|edit| (edit.rotate, edit.flip_horizontal, edit.flip_vertical, edit.redactions.clone()))
+                .dedupe_cloned()
+                .for_each(clone!(page => move |_| {
+                    clone!(page => async move {
+                        page.update_preview().await;
+                    })
+                }))
+            )
+            .child(html!("div", {
+                .class(["btn-group","mb-1"])
+                .children(&mut [
+                    Self::tool_button("fa-rotate-left", "หมุนซ้าย", app.loader.is_loading(), clone!(page => move |_| {
+                        page.edit.lock_mut().rotate_left();
+                    })),
+                    Self::tool_button("fa-rotate-right", "หมุนขวา", app.loader.is_loading(), clone!(page => move |_| {
+                        page.edit.lock_mut().rotate_right();
+                    })),
+                    Self::tool_button("fa-arrows-left-right", "กลับซ้ายขวา", app.loader.is_loading(), clone!(page => move |_| {
+                        page.edit.lock_mut().flip_horizontal();
+                    })),
+                    Self::tool_button("fa-arrows-up-down", "กลับบนล่าง", app.loader.is_loading(), clone!(page => move |_| {
+                        page.edit.lock_mut().flip_vertical();
+                    })),
+                    Self::drag_tool_button(page.clone(), &app, "fa-crop-simple", "ตัดขอบ", DragTool::Crop),
+                    Self::drag_tool_button(page.clone(), &app, "fa-border-none", "ปิดบังแบบโมเสก", DragTool::Redact(RedactMode::Pixelate)),
+                    Self::drag_tool_button(page.clone(), &app, "fa-square", "ปิดบังแบบทึบ", DragTool::Redact(RedactMode::BlackOut)),
+                    Self::tool_button("fa-delete-left", "ลบการปิดบังล่าสุด", map_ref! {
+                        let busy = app.loader.is_loading(),
+                        let is_redacted = page.edit.signal_ref(|edit| edit.is_redacted()) =>
+                        *busy || !*is_redacted
+                    }, clone!(page => move |_| {
+                        page.edit.lock_mut().redactions.pop();
+                    })),
+                    Self::tool_button("fa-eraser", "ล้าง", app.loader.is_loading(), clone!(page => move |_| {
+                        page.edit.set(ImageEdit::default());
+                        page.tool.set(None);
+                    })),
+                ])
+            }))
+            .child(html!("div", {
+                .class(["position-relative","d-inline-block"])
+                .child(html!("img", {
+                    .class(["mw-100","d-block"])
+                    .attr("draggable","false")
+                    .attr_signal("src", page.preview_url.signal_cloned().map(|url| url.unwrap_or_default()))
+                    .style_signal("cursor", page.tool.signal().map(|v| if v.is_some() {"crosshair"} else {"default"}))
+                    .style_signal("touch-action", page.tool.signal().map(|v| if v.is_some() {"none"} else {"auto"}))
+                    .with_node!(element => {
+                        .event(clone!(page, element => move |e: events::PointerDown| {
+                            if let Some(tool) = page.tool.get() {
+                                let _ = element.set_pointer_capture(e.pointer_id());
+                                let start = Self::point_fraction(e.offset_x(), e.offset_y(), element.client_width(), element.client_height());
+                                page.drag_start.set(Some(start));
+                                if tool == DragTool::Crop {
+                                    page.edit.lock_mut().crop = None;
+                                }
+                            }
+                        }))
+                        .event(clone!(page, element => move |e: events::PointerMove| {
+                            if let (Some(start), Some(tool)) = (page.drag_start.get(), page.tool.get()) {
+                                let end = Self::point_fraction(e.offset_x(), e.offset_y(), element.client_width(), element.client_height());
+                                let rect = Self::drag_rect(start, end);
+                                match tool {
+                                    DragTool::Crop => page.edit.lock_mut().crop = Some(rect),
+                                    DragTool::Redact(_) => page.drag_rect.set(Some(rect)),
+                                }
+                            }
+                        }))
+                        .event(clone!(page => move |_: events::PointerUp| {
+                            page.drag_start.set(None);
+                            let mut lock = page.edit.lock_mut();
+                            if lock.crop.as_ref().is_some_and(Self::is_too_small) {
+                                lock.crop = None;
+                            }
+                            if let (Some(rect), Some(DragTool::Redact(mode))) = (page.drag_rect.replace(None), page.tool.get()) {
+                                if !Self::is_too_small(&rect) {
+                                    lock.redactions.push(Redaction { rect, mode });
+                                }
+                            }
+                        }))
+                    })
+                }))
+                .child_signal(page.edit.signal_ref(|edit| edit.crop).map(|opt| {
+                    opt.map(|crop| {
+                        html!("div", {
+                            .class(["position-absolute","border","border-2","border-warning"])
+                            .style("left", Self::percent(crop.x))
+                            .style("top", Self::percent(crop.y))
+                            .style("width", Self::percent(crop.width))
+                            .style("height", Self::percent(crop.height))
+                            .style("box-shadow","0 0 0 9999px rgba(0,0,0,0.5)")
+                            .style("pointer-events","none")
+                        })
+                    })
+                }))
+                .child_signal(page.drag_rect.signal().map(|opt| {
+                    opt.map(|rect| {
+                        html!("div", {
+                            .class(["position-absolute","border","border-2","border-danger"])
+                            .style("border-style","dashed")
+                            .style("left", Self::percent(rect.x))
+                            .style("top", Self::percent(rect.y))
+                            .style("width", Self::percent(rect.width))
+                            .style("height", Self::percent(rect.height))
+                            .style("pointer-events","none")
+                        })
+                    })
+                }))
+            }))
+            .child(html!("div", {
+                .class("mt-1")
+                .children(&mut [
+                    html!("button" => HtmlButtonElement, {
+                        .attr("type","button")
+                        .class(["btn","btn-sm","btn-primary","me-1"])
+                        .text("บันทึก")
+                        .apply(mixins::other_true_signal_disable(map_ref! {
+                            let busy = app.loader.is_loading(),
+                            let is_empty = page.is_empty_signal() =>
+                            *busy || *is_empty
+                        }))
+                        .event(move |_: events::Click| on_save())
+                    }),
+                    html!("button", {
+                        .attr("type","button")
+                        .class(["btn","btn-sm","btn-secondary"])
+                        .text("ยกเลิก")
+                        .event(move |_: events::Click| on_cancel())
+                    }),
+                ])
+            }))
+        })
+    }
+}
+
+impl Drop for ImageEditorCpn {
+    fn drop(&mut self) {
+        if let Some(url) = self.preview_url.get_cloned() {
+            let _ = Url::revoke_object_url(&url);
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use dominator::{clone, Dom, events, html};
+use std::rc::Rc;
+use wasm_bindgen::JsValue;
+use web_sys::HtmlButtonElement;
+use model::{ImageData, ImageVersion, VersionKind};
+
+use crate::{
+    App,
+    fetch::get_image_versions,
+    mixins,
+};
+
+/// versions of an image, newest first
+pub struct ImageHistoryCpn {
+    pub image_id: u32,
+    versions: Vec<ImageVersion>,
+}
+
+impl ImageHistoryCpn {
+    pub async fn load(image_data: &ImageData) -> Result<Rc<Self>, String> {
+        let versions = get_image_versions(image_data.image_id).await?;
+        Ok(Rc::new(Self {
+            image_id: image_data.image_id,
+            versions,
+        }))
+    }
+
+    fn kind_text(kind: &VersionKind) -> String {
+        match kind {
+            VersionKind::Upload => String::from("อัปโหลด"),
+            VersionKind::Title => String::from("แก้คำบรรยาย"),
+            VersionKind::Edit => String::from("แก้ไขรูป"),
+            VersionKind::Redact => String::from("ปิดบังข้อมูล"),
+            VersionKind::Revert(version) => ["ย้อนกลับไปฉบับที่ ", &version.to_string()].concat(),
+        }
+    }
+
+    /// unredacted files are served by api for privileged roles only
+    fn file_url(prefix: &str, version: &ImageVersion) -> String {
+        if version.restricted {
+            ["api/restricted", prefix, &version.path].join("/")
+        } else {
+            [prefix, &version.path].join("/")
+        }
+    }
+
+    fn time_text(version: &ImageVersion) -> String {
+        let ms = (version.created_at.unix_timestamp_nanos() / 1_000_000) as f64;
+        let date = js_sys::Date::new(&JsValue::from_f64(ms));
+        date.to_locale_string("th-TH", &JsValue::UNDEFINED).into()
+    }
+
+    pub fn render<R, C>(page: Rc<Self>, app: Rc<App>, on_revert: R, on_close: C) -> Dom
+    where
+        R: Fn(u32) + Clone + 'static,
+        C: Fn() + 'static,
+    {
+        let current = page.versions.first().map(|version| version.version);
+        html!("div", {
+            .class("p-1")
+            .child(html!("ul", {
+                .class(["list-group","list-group-flush","mb-1"])
+                .children(page.versions.iter().map(|version| {
+                    html!("li", {
+                        .class(["list-group-item","d-flex","align-items-center","p-1"])
+                        .child(html!("a", {
+                            .attr("href", &Self::file_url("images", version))
+                            .attr("target","_blank")
+                            .child(html!("img", {
+                                .attr("src", &Self::file_url("thumbs", version))
+                                .attr("alt", &version.title.clone().unwrap_or_default())
+                                .style("width","64px")
+                                .style("height","64px")
+                            }))
+                        }))
+                        .child(html!("div", {
+                            .class(["flex-grow-1","ms-2","small"])
+                            .children(&mut [
+                                html!("div", {
+                                    .class("fw-bold")
+                                    .text(&["ฉบับที่ ", &version.version.to_string(), " ", &Self::kind_text(&version.kind)].concat())
+                                }),
+                                html!("div", {
+                                    .text(&version.title.clone().unwrap_or(String::from("ไม่มีคำบรรยาย")))
+                                }),
+                                html!("div", {
+                                    .class("text-body-secondary")
+                                    .text(&[version.user.as_str(), " ", &Self::time_text(version)].concat())
+                                }),
+                            ])
+                        }))
+                        .apply(|dom| {
+                            if Some(version.version) == current {
+                                dom.child(html!("span", {
+                                    .class(["badge","text-bg-primary"])
+                                    .text("ปัจจุบัน")
+                                }))
+                            } else if version.restricted {
+                                // reverting would bring back unredacted image
+                                dom.child(html!("span", {
+                                    .class(["badge","text-bg-danger"])
+                                    .text("จำกัดสิทธิ์")
+                                }))
+                            } else {
+                                let number = version.version;
+                                dom.child(html!("button" => HtmlButtonElement, {
+                                    .attr("type","button")
+                                    .class(["btn","btn-sm","btn-warning"])
+                                    .text("ย้อนกลับ")
+                                    .apply(mixins::other_true_signal_disable(app.loader.is_loading()))
+                                    .event(clone!(on_revert => move |_: events::Click| {
+                                        on_revert(number);
+                                    }))
+                                }))
+                            }
+                        })
+                    })
+                }))
+            }))
+            .child(html!("button", {
+                .attr("type","button")
+                .class(["btn","btn-sm","btn-secondary"])
+                .text("ปิด")
+                .event(move |_: events::Click| on_close())
+            }))
+        })
+    }
+}
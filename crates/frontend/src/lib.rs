@@ -2,10 +2,12 @@
 // extern crate concat_string;
 
 mod abort;
+mod annotation;
 mod binding;
 mod fetch;
 mod image;
-mod image_parser;
+mod image_editor;
+mod image_history;
 mod mixins;
 mod loader;
 
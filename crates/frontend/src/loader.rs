@@ -79,6 +79,10 @@ impl AsyncLoader {
         });
     }
 
+    pub fn is_loading_now(&self) -> bool {
+        self.loading.lock_ref().is_some()
+    }
+
     pub fn is_loading(&self) -> impl Signal<Item = bool> {
         self.loading.signal_ref(|x| x.is_some())
     }
@@ -0,0 +1,36 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use std::{hint::black_box, io::Cursor};
+
+use imaging::{edit::{image_preview, ImageEdit}, image_decode, Profile};
+
+/// 12MP phone photo with detail, so encoders have work to do
+fn photo() -> DynamicImage {
+    DynamicImage::ImageRgb8(RgbImage::from_fn(4000, 3000, |x, y| {
+        Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 256) as u8])
+    }))
+}
+
+fn bench_profiles(c: &mut Criterion) {
+    let photo = photo();
+    let stored = Profile::IMAGE.resize(&photo);
+    let mut jpeg = Vec::new();
+    photo.write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg).unwrap();
+
+    c.bench_function("decode jpeg 4000x3000", |b| b.iter(|| image_decode(black_box(&jpeg)).unwrap()));
+    c.bench_function("image from 4000x3000", |b| b.iter(|| Profile::IMAGE.render(black_box(&photo)).unwrap()));
+    c.bench_function("thumb from 1024x768", |b| b.iter(|| Profile::THUMB.render(black_box(&stored)).unwrap()));
+    c.bench_function("preview from 1024x768", |b| b.iter(|| Profile::PREVIEW.render(black_box(&stored)).unwrap()));
+    c.bench_function("editor preview from 1024x768", |b| {
+        let mut edit = ImageEdit::default();
+        edit.rotate_right();
+        b.iter(|| image_preview(black_box(&stored), &edit).unwrap())
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = bench_profiles
+}
+criterion_main!(benches);
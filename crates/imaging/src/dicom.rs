@@ -0,0 +1,572 @@
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, GrayImage, ImageError, ImageFormat, ImageResult, Limits, RgbImage,
+};
+use std::collections::HashMap;
+use model::ImageMetadata;
+
+// transfer syntax uid
+const IMPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2";
+const EXPLICIT_VR_LITTLE_ENDIAN: &str = "1.2.840.10008.1.2.1";
+const EXPLICIT_VR_BIG_ENDIAN: &str = "1.2.840.10008.1.2.2";
+const JPEG_BASELINE: &str = "1.2.840.10008.1.2.4.50";
+const RLE_LOSSLESS: &str = "1.2.840.10008.1.2.5";
+
+// (group << 16) | element
+const TRANSFER_SYNTAX_UID: u32 = 0x0002_0010;
+const STUDY_DATE: u32 = 0x0008_0020;
+const MODALITY: u32 = 0x0008_0060;
+const PATIENT_NAME: u32 = 0x0010_0010;
+const SAMPLES_PER_PIXEL: u32 = 0x0028_0002;
+const PHOTOMETRIC_INTERPRETATION: u32 = 0x0028_0004;
+const PLANAR_CONFIGURATION: u32 = 0x0028_0006;
+const NUMBER_OF_FRAMES: u32 = 0x0028_0008;
+const ROWS: u32 = 0x0028_0010;
+const COLUMNS: u32 = 0x0028_0011;
+const BITS_ALLOCATED: u32 = 0x0028_0100;
+const BITS_STORED: u32 = 0x0028_0101;
+const PIXEL_REPRESENTATION: u32 = 0x0028_0103;
+const WINDOW_CENTER: u32 = 0x0028_1050;
+const WINDOW_WIDTH: u32 = 0x0028_1051;
+const RESCALE_INTERCEPT: u32 = 0x0028_1052;
+const RESCALE_SLOPE: u32 = 0x0028_1053;
+const PIXEL_DATA: u32 = 0x7FE0_0010;
+
+const ITEM: u32 = 0xFFFE_E000;
+const ITEM_DELIMITATION: u32 = 0xFFFE_E00D;
+const SEQUENCE_DELIMITATION: u32 = 0xFFFE_E0DD;
+const UNDEFINED_LENGTH: u32 = 0xFFFF_FFFF;
+
+/// DICOM Part 10 file has "DICM" after 128 bytes preamble
+pub fn is_dicom(raw_data: &[u8]) -> bool {
+    raw_data.get(128..132) == Some(b"DICM")
+}
+
+/// first frame of DICOM file with window/level applied, and metadata
+pub fn dicom_decode(raw_data: &[u8]) -> ImageResult<(DynamicImage, ImageMetadata)> {
+    if !is_dicom(raw_data) {
+        return Err(dicom_error("missing DICM prefix"));
+    }
+    // file meta information is always explicit VR little endian
+    let mut meta_reader = Reader::new(raw_data, 132, true, false);
+    let file_meta = meta_reader.read_dataset(|tag| tag >> 16 != 0x0002)?;
+    let transfer_syntax = file_meta.string(TRANSFER_SYNTAX_UID).unwrap_or_default();
+    let (explicit, big_endian) = match transfer_syntax.as_str() {
+        IMPLICIT_VR_LITTLE_ENDIAN => (false, false),
+        EXPLICIT_VR_LITTLE_ENDIAN | JPEG_BASELINE | RLE_LOSSLESS => (true, false),
+        EXPLICIT_VR_BIG_ENDIAN => (true, true),
+        other => return Err(dicom_error(&["unsupported transfer syntax ", other].concat())),
+    };
+    let mut reader = Reader::new(raw_data, meta_reader.pos, explicit, big_endian);
+    let dataset = reader.read_dataset(|_| false)?;
+
+    let metadata = ImageMetadata {
+        patient_name: dataset.string(PATIENT_NAME).map(|name| name.replace('^', " ").trim().to_owned()),
+        study_date: dataset.string(STUDY_DATE).map(|date| format_date(&date)),
+        modality: dataset.string(MODALITY),
+    };
+    let pixel = PixelInfo::from_dataset(&dataset)?;
+    let image = match transfer_syntax.as_str() {
+        JPEG_BASELINE => {
+            let jpeg = image::load_from_memory_with_format(&first_frame(&dataset.fragments, pixel.frames)?, ImageFormat::Jpeg)?;
+            if pixel.photometric == "MONOCHROME1" {
+                let mut gray = jpeg.to_luma8();
+                image::imageops::invert(&mut gray);
+                DynamicImage::ImageLuma8(gray)
+            } else {
+                jpeg
+            }
+        }
+        RLE_LOSSLESS => {
+            let native = rle_decode(&first_frame(&dataset.fragments, pixel.frames)?, &pixel)?;
+            pixel.to_image(&native, false)?
+        }
+        _ => {
+            let data = dataset.values.get(&PIXEL_DATA).ok_or_else(|| dicom_error("missing pixel data"))?;
+            pixel.to_image(data, big_endian)?
+        }
+    };
+    Ok((image, metadata))
+}
+
+fn dicom_error(message: &str) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Name(String::from("DICOM")), message.to_owned()))
+}
+
+/// DA value `20240115` to `2024-01-15`
+fn format_date(date: &str) -> String {
+    if date.len() == 8 && date.bytes().all(|b| b.is_ascii_digit()) {
+        [&date[0..4], &date[4..6], &date[6..8]].join("-")
+    } else {
+        date.to_owned()
+    }
+}
+
+/// top level elements, values of sequences are skipped
+#[derive(Default)]
+struct DataSet {
+    values: HashMap<u32, Vec<u8>>,
+    // encapsulated pixel data, first fragment is basic offset table
+    fragments: Vec<Vec<u8>>,
+    big_endian: bool,
+}
+
+impl DataSet {
+    fn string(&self, tag: u32) -> Option<String> {
+        let value = self.values.get(&tag)?;
+        let text = String::from_utf8_lossy(value).trim_matches(|c: char| c == ' ' || c == '\0').to_owned();
+        (!text.is_empty()).then_some(text)
+    }
+
+    fn u16(&self, tag: u32) -> Option<u16> {
+        let bytes: [u8; 2] = self.values.get(&tag)?.get(0..2)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    /// first value of multi-valued decimal or integer string
+    fn number(&self, tag: u32) -> Option<f64> {
+        self.string(tag)?.split('\\').next()?.trim().parse().ok()
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    explicit: bool,
+    big_endian: bool,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], pos: usize, explicit: bool, big_endian: bool) -> Self {
+        Self { data, pos, explicit, big_endian }
+    }
+
+    fn take(&mut self, len: usize) -> ImageResult<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len())
+            .ok_or_else(|| dicom_error("unexpected end of file"))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u16(&mut self) -> ImageResult<u16> {
+        let bytes = self.take(2)?.try_into().unwrap();
+        Ok(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&mut self) -> ImageResult<u32> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    fn tag(&mut self) -> ImageResult<u32> {
+        let group = self.u16()? as u32;
+        let element = self.u16()? as u32;
+        Ok((group << 16) | element)
+    }
+
+    /// (tag, is sequence, length) of next element
+    fn header(&mut self) -> ImageResult<(u32, bool, u32)> {
+        let tag = self.tag()?;
+        // item and delimitation tags never have VR
+        if tag >> 16 == 0xFFFE || !self.explicit {
+            let length = self.u32()?;
+            return Ok((tag, false, length));
+        }
+        let vr = self.take(2)?;
+        if [b"OB", b"OD", b"OF", b"OL", b"OV", b"OW", b"SQ", b"UC", b"UN", b"UR", b"UT"].contains(&vr.try_into().unwrap()) {
+            self.take(2)?;
+            let length = self.u32()?;
+            Ok((tag, vr == b"SQ", length))
+        } else {
+            let length = self.u16()? as u32;
+            Ok((tag, false, length))
+        }
+    }
+
+    /// read elements until end of data or `stop` returns true for next tag
+    fn read_dataset(&mut self, stop: impl Fn(u32) -> bool) -> ImageResult<DataSet> {
+        let mut dataset = DataSet { big_endian: self.big_endian, ..Default::default() };
+        while self.pos < self.data.len() {
+            let start = self.pos;
+            let (tag, is_sequence, length) = self.header()?;
+            if stop(tag) {
+                self.pos = start;
+                break;
+            }
+            if length == UNDEFINED_LENGTH {
+                if tag == PIXEL_DATA {
+                    dataset.fragments = self.read_fragments()?;
+                } else {
+                    self.skip_sequence()?;
+                }
+            } else {
+                let value = self.take(length as usize)?;
+                if !is_sequence {
+                    dataset.values.insert(tag, value.to_vec());
+                }
+            }
+        }
+        Ok(dataset)
+    }
+
+    /// skip items of undefined length sequence
+    fn skip_sequence(&mut self) -> ImageResult<()> {
+        loop {
+            let (tag, _, length) = self.header()?;
+            match tag {
+                SEQUENCE_DELIMITATION => return Ok(()),
+                ITEM if length == UNDEFINED_LENGTH => self.skip_item()?,
+                ITEM => {
+                    self.take(length as usize)?;
+                }
+                _ => return Err(dicom_error("invalid sequence item")),
+            }
+        }
+    }
+
+    /// skip elements of undefined length item
+    fn skip_item(&mut self) -> ImageResult<()> {
+        loop {
+            let (tag, _, length) = self.header()?;
+            if tag == ITEM_DELIMITATION {
+                return Ok(());
+            }
+            if length == UNDEFINED_LENGTH {
+                self.skip_sequence()?;
+            } else {
+                self.take(length as usize)?;
+            }
+        }
+    }
+
+    fn read_fragments(&mut self) -> ImageResult<Vec<Vec<u8>>> {
+        let mut fragments = Vec::new();
+        loop {
+            let (tag, _, length) = self.header()?;
+            match tag {
+                SEQUENCE_DELIMITATION => return Ok(fragments),
+                ITEM => fragments.push(self.take(length as usize)?.to_vec()),
+                _ => return Err(dicom_error("invalid pixel data fragment")),
+            }
+        }
+    }
+}
+
+/// encapsulated bytes of first frame, fragments after basic offset table
+fn first_frame(fragments: &[Vec<u8>], frames: u32) -> ImageResult<Vec<u8>> {
+    let fragments = fragments.get(1..).filter(|rest| !rest.is_empty())
+        .ok_or_else(|| dicom_error("missing pixel data fragment"))?;
+    if frames > 1 {
+        // assume one fragment per frame
+        Ok(fragments[0].clone())
+    } else {
+        Ok(fragments.concat())
+    }
+}
+
+struct PixelInfo {
+    rows: u32,
+    columns: u32,
+    frames: u32,
+    samples: u16,
+    planar: bool,
+    bits_allocated: u16,
+    bits_stored: u16,
+    signed: bool,
+    photometric: String,
+    slope: f64,
+    intercept: f64,
+    // (center, width)
+    window: Option<(f64, f64)>,
+}
+
+impl PixelInfo {
+    fn from_dataset(dataset: &DataSet) -> ImageResult<Self> {
+        let rows = dataset.u16(ROWS).ok_or_else(|| dicom_error("missing rows"))? as u32;
+        let columns = dataset.u16(COLUMNS).ok_or_else(|| dicom_error("missing columns"))? as u32;
+        let bits_allocated = dataset.u16(BITS_ALLOCATED).unwrap_or(8);
+        let window = dataset.number(WINDOW_CENTER).zip(dataset.number(WINDOW_WIDTH))
+            .filter(|(_, width)| *width >= 1.0);
+        Ok(Self {
+            rows,
+            columns,
+            frames: dataset.number(NUMBER_OF_FRAMES).map(|frames| frames as u32).unwrap_or(1),
+            samples: dataset.u16(SAMPLES_PER_PIXEL).unwrap_or(1),
+            planar: dataset.u16(PLANAR_CONFIGURATION) == Some(1),
+            bits_allocated,
+            bits_stored: dataset.u16(BITS_STORED).unwrap_or(bits_allocated),
+            signed: dataset.u16(PIXEL_REPRESENTATION) == Some(1),
+            photometric: dataset.string(PHOTOMETRIC_INTERPRETATION).unwrap_or(String::from("MONOCHROME2")),
+            slope: dataset.number(RESCALE_SLOPE).unwrap_or(1.0),
+            intercept: dataset.number(RESCALE_INTERCEPT).unwrap_or(0.0),
+            window,
+        })
+    }
+
+    fn pixel_count(&self) -> usize {
+        self.rows as usize * self.columns as usize
+    }
+
+    /// native (uncompressed) first frame to 8 bit image
+    fn to_image(&self, data: &[u8], big_endian: bool) -> ImageResult<DynamicImage> {
+        let bytes = match self.bits_allocated {
+            8 => 1,
+            16 => 2,
+            _ => return Err(dicom_error("unsupported bits allocated")),
+        };
+        let frame_len = self.pixel_count() * self.samples as usize * bytes;
+        let frame = data.get(0..frame_len).ok_or_else(|| dicom_error("pixel data too short"))?;
+        match (self.samples, self.photometric.as_str()) {
+            (1, "MONOCHROME1" | "MONOCHROME2") => {
+                let values = frame.chunks_exact(bytes).map(|chunk| {
+                    let raw = match (bytes, big_endian) {
+                        (1, _) => chunk[0] as u32,
+                        (_, true) => u16::from_be_bytes([chunk[0], chunk[1]]) as u32,
+                        (_, false) => u16::from_le_bytes([chunk[0], chunk[1]]) as u32,
+                    };
+                    self.stored_value(raw) * self.slope + self.intercept
+                }).collect::<Vec<f64>>();
+                let (center, width) = self.window.unwrap_or_else(|| {
+                    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+                    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+                    ((min + max) / 2.0, (max - min).max(1.0))
+                });
+                let invert = self.photometric == "MONOCHROME1";
+                let pixels = values.into_iter().map(|value| {
+                    let level = window_level(value, center, width);
+                    if invert { 255 - level } else { level }
+                }).collect();
+                GrayImage::from_raw(self.columns, self.rows, pixels)
+                    .map(DynamicImage::ImageLuma8)
+                    .ok_or_else(|| dicom_error("invalid image size"))
+            }
+            (3, "RGB" | "YBR_FULL") if bytes == 1 => {
+                let count = self.pixel_count();
+                let mut pixels = if self.planar {
+                    (0..count).flat_map(|i| [frame[i], frame[count + i], frame[2 * count + i]]).collect::<Vec<u8>>()
+                } else {
+                    frame.to_vec()
+                };
+                if self.photometric == "YBR_FULL" {
+                    pixels.chunks_exact_mut(3).for_each(ybr_to_rgb);
+                }
+                RgbImage::from_raw(self.columns, self.rows, pixels)
+                    .map(DynamicImage::ImageRgb8)
+                    .ok_or_else(|| dicom_error("invalid image size"))
+            }
+            (samples, photometric) => Err(dicom_error(&format!("unsupported {} samples {}", samples, photometric))),
+        }
+    }
+
+    /// mask high bits and sign extend by bits stored
+    fn stored_value(&self, raw: u32) -> f64 {
+        let bits = self.bits_stored.clamp(1, 16) as u32;
+        let masked = raw & ((1 << bits) - 1);
+        if self.signed && masked & (1 << (bits - 1)) != 0 {
+            masked as f64 - (1u32 << bits) as f64
+        } else {
+            masked as f64
+        }
+    }
+}
+
+/// linear VOI LUT function of DICOM PS3.3 C.11.2.1.2
+fn window_level(value: f64, center: f64, width: f64) -> u8 {
+    let low = center - 0.5 - (width - 1.0) / 2.0;
+    let high = center - 0.5 + (width - 1.0) / 2.0;
+    if value <= low {
+        0
+    } else if value > high {
+        255
+    } else {
+        (((value - (center - 0.5)) / (width - 1.0).max(1.0) + 0.5) * 255.0).round().clamp(0.0, 255.0) as u8
+    }
+}
+
+fn ybr_to_rgb(pixel: &mut [u8]) {
+    let y = pixel[0] as f64;
+    let cb = pixel[1] as f64 - 128.0;
+    let cr = pixel[2] as f64 - 128.0;
+    pixel[0] = (y + 1.402 * cr).round().clamp(0.0, 255.0) as u8;
+    pixel[1] = (y - 0.344136 * cb - 0.714136 * cr).round().clamp(0.0, 255.0) as u8;
+    pixel[2] = (y + 1.772 * cb).round().clamp(0.0, 255.0) as u8;
+}
+
+/// RLE lossless frame to native little endian interleaved pixels
+fn rle_decode(frame: &[u8], pixel: &PixelInfo) -> ImageResult<Vec<u8>> {
+    let header = frame.get(0..64).ok_or_else(|| dicom_error("missing RLE header"))?;
+    let offsets = header.chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()) as usize)
+        .collect::<Vec<usize>>();
+    let bytes = pixel.bits_allocated as usize / 8;
+    let segments = offsets[0];
+    if segments != pixel.samples as usize * bytes || segments == 0 || segments > 15 {
+        return Err(dicom_error("unexpected number of RLE segments"));
+    }
+    let count = pixel.pixel_count();
+    let len = count.checked_mul(segments)
+        .filter(|len| Limits::default().max_alloc.is_none_or(|max| *len as u64 <= max))
+        .ok_or_else(|| dicom_error("RLE frame too large"))?;
+    let segment_data = (0..segments).map(|segment| {
+        let start = offsets[segment + 1];
+        let end = if segment + 1 < segments { offsets[segment + 2] } else { frame.len() };
+        let data = frame.get(start..end).ok_or_else(|| dicom_error("invalid RLE segment offset"))?;
+        // a replicate run of 2 bytes decodes to at most 128 bytes
+        if data.len().saturating_mul(64) < count {
+            return Err(dicom_error("RLE segment too short"));
+        }
+        Ok(data)
+    }).collect::<ImageResult<Vec<&[u8]>>>()?;
+    let mut native = vec![0; len];
+    for (segment, data) in segment_data.into_iter().enumerate() {
+        let decoded = packbits_decode(data, count);
+        // segments are ordered by sample, then most significant byte first
+        let sample = segment / bytes;
+        let byte = bytes - 1 - segment % bytes;
+        for (i, value) in decoded.into_iter().enumerate() {
+            native[(i * pixel.samples as usize + sample) * bytes + byte] = value;
+        }
+    }
+    Ok(native)
+}
+
+fn packbits_decode(data: &[u8], len: usize) -> Vec<u8> {
+    let mut result = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() && result.len() < len {
+        let n = data[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let end = (i + n as usize + 1).min(data.len());
+            result.extend_from_slice(&data[i..end]);
+            i = end;
+        } else if n != -128 {
+            if let Some(value) = data.get(i) {
+                // -1 to -127 repeats next byte 2 to 128 times
+                result.extend(std::iter::repeat_n(*value, (1 - n as isize) as usize));
+            }
+            i += 1;
+        }
+    }
+    result.resize(len, 0);
+    result
+}
+
+#[cfg(test)]
+pub mod tests {
+    use image::GenericImageView;
+
+    use super::{dicom_decode, is_dicom, packbits_decode};
+
+    fn element(tag: (u16, u16), vr: &[u8; 2], value: &[u8]) -> Vec<u8> {
+        let mut bytes = [tag.0.to_le_bytes(), tag.1.to_le_bytes()].concat();
+        bytes.extend_from_slice(vr);
+        if vr == b"OW" {
+            bytes.extend_from_slice(&[0, 0]);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        } else {
+            bytes.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+        bytes.extend_from_slice(value);
+        bytes
+    }
+
+    #[test]
+    pub fn test_dicom_decode() {
+        let pixels = [0u16, 100, 200, 4095].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        let data = [
+            vec![0; 128],
+            b"DICM".to_vec(),
+            element((0x0002, 0x0010), b"UI", b"1.2.840.10008.1.2.1\0"),
+            element((0x0008, 0x0020), b"DA", b"20240115"),
+            element((0x0008, 0x0060), b"CS", b"CR"),
+            element((0x0010, 0x0010), b"PN", b"Doe^John"),
+            element((0x0028, 0x0002), b"US", &1u16.to_le_bytes()),
+            element((0x0028, 0x0004), b"CS", b"MONOCHROME2 "),
+            element((0x0028, 0x0010), b"US", &2u16.to_le_bytes()),
+            element((0x0028, 0x0011), b"US", &2u16.to_le_bytes()),
+            element((0x0028, 0x0100), b"US", &16u16.to_le_bytes()),
+            element((0x0028, 0x0101), b"US", &12u16.to_le_bytes()),
+            element((0x0028, 0x1050), b"DS", b"100 "),
+            element((0x0028, 0x1051), b"DS", b"200 "),
+            element((0x7FE0, 0x0010), b"OW", &pixels),
+        ].concat();
+        assert!(is_dicom(&data));
+        let (image, metadata) = dicom_decode(&data).unwrap();
+        assert_eq!(metadata.patient_name.as_deref(), Some("Doe John"));
+        assert_eq!(metadata.study_date.as_deref(), Some("2024-01-15"));
+        assert_eq!(metadata.modality.as_deref(), Some("CR"));
+        assert_eq!(image.dimensions(), (2, 2));
+        let levels = image.to_luma8().pixels().map(|p| p.0[0]).collect::<Vec<u8>>();
+        assert_eq!(levels, vec![0, 128, 255, 255]);
+    }
+
+    /// 16 bit monochrome RLE Lossless file of a single fragment
+    fn rle_dicom(rows: u16, columns: u16, frame: &[u8]) -> Vec<u8> {
+        let item = |value: &[u8]| [&[0xFE, 0xFF, 0x00, 0xE0], (value.len() as u32).to_le_bytes().as_slice(), value].concat();
+        [
+            vec![0; 128],
+            b"DICM".to_vec(),
+            element((0x0002, 0x0010), b"UI", b"1.2.840.10008.1.2.5\0"),
+            element((0x0028, 0x0002), b"US", &1u16.to_le_bytes()),
+            element((0x0028, 0x0004), b"CS", b"MONOCHROME2 "),
+            element((0x0028, 0x0010), b"US", &rows.to_le_bytes()),
+            element((0x0028, 0x0011), b"US", &columns.to_le_bytes()),
+            element((0x0028, 0x0100), b"US", &16u16.to_le_bytes()),
+            element((0x0028, 0x0101), b"US", &12u16.to_le_bytes()),
+            element((0x0028, 0x1050), b"DS", b"100 "),
+            element((0x0028, 0x1051), b"DS", b"200 "),
+            // encapsulated pixel data of undefined length, empty offset table then fragment
+            vec![0xE0, 0x7F, 0x10, 0x00, b'O', b'B', 0, 0, 0xFF, 0xFF, 0xFF, 0xFF],
+            item(&[]),
+            item(frame),
+            vec![0xFE, 0xFF, 0xDD, 0xE0, 0, 0, 0, 0],
+        ].concat()
+    }
+
+    /// RLE header of 2 segments then segments
+    fn rle_frame(msb: &[u8], lsb: &[u8]) -> Vec<u8> {
+        let mut frame = [2u32, 64, 64 + msb.len() as u32].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<u8>>();
+        frame.resize(64, 0);
+        frame.extend_from_slice(msb);
+        frame.extend_from_slice(lsb);
+        frame
+    }
+
+    #[test]
+    pub fn test_dicom_decode_rle() {
+        // 16 bit pixels 0, 100, 200, 4095 as most and least significant byte segments,
+        // replicate run in first segment
+        let mut frame = rle_frame(&[-2i8 as u8, 0, 0, 0x0F], &[3, 0, 100, 200, 255]);
+        frame.push(0);
+        let (image, _) = dicom_decode(&rle_dicom(2, 2, &frame)).unwrap();
+        assert_eq!(image.dimensions(), (2, 2));
+        let levels = image.to_luma8().pixels().map(|p| p.0[0]).collect::<Vec<u8>>();
+        assert_eq!(levels, vec![0, 128, 255, 255]);
+    }
+
+    #[test]
+    pub fn test_dicom_decode_rle_hostile() {
+        // segments cannot cover 256x256 pixels
+        let frame = rle_frame(&[-127i8 as u8, 0], &[-127i8 as u8, 0]);
+        let error = dicom_decode(&rle_dicom(256, 256, &frame)).unwrap_err();
+        assert!(error.to_string().contains("RLE segment too short"));
+        // 65535x65535 would need 8 GB before decoding any segment
+        let frame = rle_frame(&[0; 70_000], &[0; 70_000]);
+        let error = dicom_decode(&rle_dicom(u16::MAX, u16::MAX, &frame)).unwrap_err();
+        assert!(error.to_string().contains("RLE frame too large"));
+    }
+
+    #[test]
+    pub fn test_packbits_decode() {
+        // literal run of 3, replicate run of 4, no-op, replicate run of 128
+        let data = [2, 1, 2, 3, -3i8 as u8, 9, -128i8 as u8, -127i8 as u8, 7];
+        let decoded = packbits_decode(&data, 135);
+        assert_eq!(decoded[..7], [1, 2, 3, 9, 9, 9, 9]);
+        assert!(decoded[7..].iter().all(|&v| v == 7));
+        // truncated or padded to pixel count
+        assert_eq!(packbits_decode(&data, 5), vec![1, 2, 3, 9, 9]);
+        assert_eq!(packbits_decode(&[0, 5], 3), vec![5, 0, 0]);
+    }
+}
@@ -0,0 +1,191 @@
+use image::{DynamicImage, ImageResult, Rgba};
+
+use crate::Profile;
+
+/// edit operations, applied in order: rotate, flip, redact, crop
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImageEdit {
+    /// clockwise degree, 0, 90, 180 or 270
+    pub rotate: u16,
+    pub flip_horizontal: bool,
+    pub flip_vertical: bool,
+    pub redactions: Vec<Redaction>,
+    pub crop: Option<CropRect>,
+}
+
+/// fraction (0.0 - 1.0) of rotated and flipped image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CropRect {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl CropRect {
+    /// pixels (x, y, width, height) in image of `w` x `h`, at least 1x1
+    fn to_pixels(self, w: u32, h: u32) -> (u32, u32, u32, u32) {
+        let (w, h) = (w as f64, h as f64);
+        let x = (self.x.clamp(0.0, 1.0) * w).round() as u32;
+        let y = (self.y.clamp(0.0, 1.0) * h).round() as u32;
+        let width = (self.width.clamp(0.0, 1.0) * w).round().max(1.0) as u32;
+        let height = (self.height.clamp(0.0, 1.0) * h).round().max(1.0) as u32;
+        (x, y, width, height)
+    }
+}
+
+/// hide faces, tattoos or name bands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Redaction {
+    pub rect: CropRect,
+    pub mode: RedactMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedactMode {
+    Pixelate,
+    BlackOut,
+}
+
+impl ImageEdit {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn is_redacted(&self) -> bool {
+        !self.redactions.is_empty()
+    }
+
+    /// rotate and flip will clear crop and redactions, those are fraction of rotated and flipped image
+    fn clear_areas(&mut self) {
+        self.crop = None;
+        self.redactions.clear();
+    }
+
+    pub fn rotate_right(&mut self) {
+        self.rotate = (self.rotate + 90) % 360;
+        self.clear_areas();
+    }
+
+    pub fn rotate_left(&mut self) {
+        self.rotate = (self.rotate + 270) % 360;
+        self.clear_areas();
+    }
+
+    pub fn flip_horizontal(&mut self) {
+        self.flip_horizontal = !self.flip_horizontal;
+        self.clear_areas();
+    }
+
+    pub fn flip_vertical(&mut self) {
+        self.flip_vertical = !self.flip_vertical;
+        self.clear_areas();
+    }
+}
+
+pub fn image_transform(image: &DynamicImage, edit: &ImageEdit) -> DynamicImage {
+    let rotated = match edit.rotate {
+        90 => image.rotate90(),
+        180 => image.rotate180(),
+        270 => image.rotate270(),
+        _ => image.clone(),
+    };
+    let flipped_h = if edit.flip_horizontal { rotated.fliph() } else { rotated };
+    let flipped = if edit.flip_vertical { flipped_h.flipv() } else { flipped_h };
+    let redacted = image_redact(flipped, &edit.redactions);
+    if let Some(crop) = edit.crop {
+        let (x, y, crop_w, crop_h) = crop.to_pixels(redacted.width(), redacted.height());
+        // crop_imm clamps to image bounds
+        redacted.crop_imm(x, y, crop_w, crop_h)
+    } else {
+        redacted
+    }
+}
+
+/// pixelate or black out areas, pixelate block size scales with image size
+pub fn image_redact(image: DynamicImage, redactions: &[Redaction]) -> DynamicImage {
+    if redactions.is_empty() {
+        return image;
+    }
+    let mut rgba = image.to_rgba8();
+    let (w, h) = rgba.dimensions();
+    let block = (w.max(h) / 40).max(8);
+    for redaction in redactions {
+        let (x, y, width, height) = redaction.rect.to_pixels(w, h);
+        let x_end = (x + width).min(w);
+        let y_end = (y + height).min(h);
+        match redaction.mode {
+            RedactMode::BlackOut => {
+                for py in y..y_end {
+                    for px in x..x_end {
+                        rgba.put_pixel(px, py, Rgba([0, 0, 0, 255]));
+                    }
+                }
+            }
+            RedactMode::Pixelate => {
+                for by in (y..y_end).step_by(block as usize) {
+                    for bx in (x..x_end).step_by(block as usize) {
+                        let bx_end = (bx + block).min(x_end);
+                        let by_end = (by + block).min(y_end);
+                        let mut sum = [0u64; 4];
+                        for py in by..by_end {
+                            for px in bx..bx_end {
+                                for (total, channel) in sum.iter_mut().zip(rgba.get_pixel(px, py).0) {
+                                    *total += channel as u64;
+                                }
+                            }
+                        }
+                        let count = ((bx_end - bx) * (by_end - by)) as u64;
+                        let average = Rgba(sum.map(|total| (total / count) as u8));
+                        for py in by..by_end {
+                            for px in bx..bx_end {
+                                rgba.put_pixel(px, py, average);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+    DynamicImage::ImageRgba8(rgba)
+}
+
+/// fast-to-encode preview for editor, no crop applied
+pub fn image_preview(image: &DynamicImage, edit: &ImageEdit) -> ImageResult<Vec<u8>> {
+    let edit = ImageEdit { crop: None, ..edit.clone() };
+    let preview = image_transform(&Profile::PREVIEW.resize(image), &edit);
+    Profile::PREVIEW.encode(&preview)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use image::{DynamicImage, GenericImageView};
+
+    use super::{image_redact, image_transform, CropRect, ImageEdit, Redaction, RedactMode};
+
+    #[test]
+    pub fn test_image_transform() {
+        let image = DynamicImage::new_rgb8(40, 20);
+        let mut edit = ImageEdit::default();
+        edit.rotate_left();
+        edit.flip_horizontal = true;
+        edit.crop = Some(CropRect { x: 0.5, y: 0.25, width: 0.5, height: 0.5 });
+        let edited = image_transform(&image, &edit);
+        assert_eq!(edit.rotate, 270);
+        assert_eq!((edited.width(), edited.height()), (10, 20));
+    }
+
+    #[test]
+    pub fn test_image_redact() {
+        let image = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(20, 20, image::Rgba([200, 100, 50, 255])));
+        let redactions = [
+            Redaction { rect: CropRect { x: 0.0, y: 0.0, width: 0.5, height: 0.5 }, mode: RedactMode::BlackOut },
+            Redaction { rect: CropRect { x: 0.5, y: 0.5, width: 0.5, height: 0.5 }, mode: RedactMode::Pixelate },
+        ];
+        let redacted = image_redact(image, &redactions);
+        assert_eq!(redacted.get_pixel(5, 5).0, [0, 0, 0, 255]);
+        // uniform color stays the same after pixelate
+        assert_eq!(redacted.get_pixel(15, 15).0, [200, 100, 50, 255]);
+        assert_eq!(redacted.get_pixel(15, 5).0, [200, 100, 50, 255]);
+    }
+}
@@ -0,0 +1,96 @@
+use image::{error::ImageFormatHint, DynamicImage, ImageError, ImageResult};
+
+// ISO BMFF brands of HEIC/HEIF (HEVC) and AVIF (AV1) still images and sequences
+const HEIF_BRANDS: [&[u8; 4]; 10] = [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"mif1", b"msf1", b"avif", b"avis"];
+
+/// HEIC/HEIF or AVIF file, default format of iPhone camera, `ftyp` box with a known major or compatible brand
+pub fn is_heif(raw_data: &[u8]) -> bool {
+    if raw_data.len() < 16 || &raw_data[4..8] != b"ftyp" {
+        return false;
+    }
+    let box_size = u32::from_be_bytes([raw_data[0], raw_data[1], raw_data[2], raw_data[3]]) as usize;
+    let ftyp = &raw_data[..box_size.clamp(16, raw_data.len())];
+    // major brand, then compatible brands after minor version
+    std::iter::once(&ftyp[8..12])
+        .chain(ftyp[16..].chunks_exact(4))
+        .any(|brand| HEIF_BRANDS.iter().any(|known| brand == known.as_slice()))
+}
+
+fn heif_hint() -> ImageFormatHint {
+    ImageFormatHint::Name(String::from("HEIF"))
+}
+
+/// decode primary image with libheif, rotation and crop of file applied
+#[cfg(feature = "heif")]
+pub fn heif_decode(raw_data: &[u8]) -> ImageResult<DynamicImage> {
+    use image::{error::DecodingError, RgbaImage};
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    let to_error = |e: String| ImageError::Decoding(DecodingError::new(heif_hint(), e));
+    let context = HeifContext::read_from_bytes(raw_data).map_err(|e| to_error(e.to_string()))?;
+    let handle = context.primary_image_handle().map_err(|e| to_error(e.to_string()))?;
+    let image = LibHeif::new().decode(&handle, ColorSpace::Rgb(RgbChroma::Rgba), None)
+        .map_err(|e| to_error(e.to_string()))?;
+    let plane = image.planes().interleaved.ok_or_else(|| to_error(String::from("no interleaved plane")))?;
+    // rows may be padded to stride
+    let row_len = plane.width as usize * 4;
+    let rgba = plane.data.chunks(plane.stride)
+        .take(plane.height as usize)
+        .flat_map(|row| row.get(..row_len).unwrap_or(row))
+        .copied()
+        .collect::<Vec<u8>>();
+    RgbaImage::from_raw(plane.width, plane.height, rgba)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| to_error(String::from("truncated plane")))
+}
+
+/// without libheif, frontend decodes with browser instead
+#[cfg(not(feature = "heif"))]
+pub fn heif_decode(_raw_data: &[u8]) -> ImageResult<DynamicImage> {
+    use image::error::{UnsupportedError, UnsupportedErrorKind};
+
+    Err(ImageError::Unsupported(UnsupportedError::from_format_and_kind(
+        heif_hint(),
+        UnsupportedErrorKind::Format(heif_hint()),
+    )))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::is_heif;
+
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len() as u32;
+        let mut data = [size.to_be_bytes().as_slice(), b"ftyp", major, &[0; 4]].concat();
+        for brand in compatible {
+            data.extend_from_slice(brand.as_slice());
+        }
+        // start of next box
+        data.extend_from_slice(&[0, 0, 0, 8, b'm', b'e', b't', b'a']);
+        data
+    }
+
+    #[test]
+    pub fn test_is_heif() {
+        assert!(is_heif(&ftyp(b"heic", &[b"mif1", b"heic"])));
+        assert!(is_heif(&ftyp(b"avif", &[])));
+        assert!(is_heif(&ftyp(b"MA1B", &[b"avif"])));
+        // mp4 video
+        assert!(!is_heif(&ftyp(b"isom", &[b"iso2", b"mp41"])));
+        // brand out of ftyp box
+        let mut data = ftyp(b"isom", &[]);
+        data.extend_from_slice(b"heic");
+        assert!(!is_heif(&data));
+        assert!(!is_heif(b"\x89PNG\r\n\x1a\n"));
+    }
+
+    #[cfg(not(feature = "heif"))]
+    #[test]
+    pub fn test_heif_unsupported() {
+        use image::ImageError;
+
+        use crate::image_decode;
+
+        assert!(matches!(image_decode(&ftyp(b"heic", &[b"mif1"])), Err(ImageError::Unsupported(_))));
+    }
+}
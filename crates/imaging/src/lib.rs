@@ -0,0 +1,227 @@
+use image::{
+    error::{ParameterError, ParameterErrorKind},
+    imageops::FilterType, DynamicImage, ImageError, ImageFormat, ImageReader, ImageResult, RgbaImage,
+};
+use std::io::Cursor;
+use model::ImageMetadata;
+
+pub mod dicom;
+pub mod edit;
+pub mod heif;
+
+use dicom::{dicom_decode, is_dicom};
+use heif::{heif_decode, is_heif};
+
+// const MAX_WIDTH: u32 = 720; // 9x64=576, 9x80=720, 9x96=864
+// const MAX_HEIGHT: u32 = 1280; // 16x64=1024, 16x80=1280, 16*96=1536
+// const THUMB_WIDTH: u32 = 144; // 9x16
+// const THUMB_HEIGHT: u32 = 256; // 16x16
+
+/// longest side of stored image
+pub const IMAGE_SIZE: u32 = 1024;
+/// side of square thumbnail
+pub const THUMB_SIZE: u32 = 128;
+/// longest side of editor preview
+pub const PREVIEW_SIZE: u32 = 512;
+// 1 slowest and smallest to 10 fastest
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 70;
+
+/// how a rendition is resized and encoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Profile {
+    /// longest side, or side of square
+    pub size: u32,
+    /// center square, small image is enlarged so thumbnails of a gallery have the same size,
+    /// otherwise small image is kept as is
+    pub square: bool,
+    pub format: ImageFormat,
+}
+
+impl Profile {
+    pub const IMAGE: Self = Self { size: IMAGE_SIZE, square: false, format: ImageFormat::WebP };
+    pub const THUMB: Self = Self { size: THUMB_SIZE, square: true, format: ImageFormat::WebP };
+    /// bmp is fast to encode
+    pub const PREVIEW: Self = Self { size: PREVIEW_SIZE, square: false, format: ImageFormat::Bmp };
+
+    pub fn with_size(self, size: u32) -> Self {
+        Self { size, ..self }
+    }
+
+    /// `ImageFormat::Avif` needs `avif` feature
+    pub fn with_format(self, format: ImageFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    pub fn resize(&self, image: &DynamicImage) -> DynamicImage {
+        let (img_w, img_h) = (image.width(), image.height());
+        if self.square {
+            let side = img_w.min(img_h);
+            let cubic = image.crop_imm((img_w - side) / 2, (img_h - side) / 2, side, side);
+            cubic.thumbnail(self.size, self.size)
+        } else if img_w > self.size || img_h > self.size {
+            // see filters detail at https://docs.rs/image/latest/image/imageops/enum.FilterType.html
+            image.resize(self.size, self.size, FilterType::Triangle)
+        } else {
+            image.clone()
+        }
+    }
+
+    pub fn encode(&self, image: &DynamicImage) -> ImageResult<Vec<u8>> {
+        let mut res = Vec::new();
+        match self.format {
+            // bmp encoder takes 8-bit color only
+            ImageFormat::Bmp => image.to_rgba8().write_to(&mut Cursor::new(&mut res), self.format)?,
+            // lossy, unlike webp encoder of `image`, and much slower
+            #[cfg(feature = "avif")]
+            ImageFormat::Avif => {
+                let image = if image.color().has_alpha() {
+                    DynamicImage::ImageRgba8(image.to_rgba8())
+                } else {
+                    DynamicImage::ImageRgb8(image.to_rgb8())
+                };
+                let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut res, AVIF_SPEED, AVIF_QUALITY);
+                image.write_with_encoder(encoder)?;
+            }
+            _ => image.write_to(&mut Cursor::new(&mut res), self.format)?,
+        }
+        Ok(res)
+    }
+
+    pub fn render(&self, image: &DynamicImage) -> ImageResult<Vec<u8>> {
+        self.encode(&self.resize(image))
+    }
+}
+
+/// decode any supported format, guessed from content,
+/// HEIC/HEIF and AVIF need `heif` feature
+pub fn image_decode(raw_data: &[u8]) -> ImageResult<DynamicImage> {
+    if is_heif(raw_data) {
+        return heif_decode(raw_data);
+    }
+    ImageReader::new(Cursor::new(raw_data)).with_guessed_format()?.decode()
+}
+
+/// decode uploaded file, with metadata if it is DICOM file
+pub fn upload_decode(raw_data: &[u8]) -> ImageResult<(DynamicImage, Option<ImageMetadata>)> {
+    if is_dicom(raw_data) {
+        let (raw_image, metadata) = dicom_decode(raw_data)?;
+        Ok((raw_image, Some(metadata)))
+    } else {
+        Ok((image_decode(raw_data)?, None))
+    }
+}
+
+/// create (image, thumbnail) bytes, thumbnail is made from resized image
+pub fn renditions(raw_image: DynamicImage, image: Profile, thumb: Profile) -> ImageResult<(Vec<u8>, Vec<u8>)> {
+    let resized = image.resize(&raw_image);
+    let res_image = image.encode(&resized)?;
+    let res_thumb = thumb.render(&resized)?;
+    Ok((res_image, res_thumb))
+}
+
+/// encode canvas pixels to webp
+pub fn rgba_to_webp(width: u32, height: u32, rgba: Vec<u8>) -> ImageResult<Vec<u8>> {
+    let image = RgbaImage::from_raw(width, height, rgba)
+        .ok_or_else(|| ImageError::Parameter(ParameterError::from_kind(ParameterErrorKind::DimensionMismatch)))?;
+    Profile::IMAGE.encode(&DynamicImage::ImageRgba8(image))
+}
+
+/// 64-bit difference hash as hex, brightness gradient of 9x8 grayscale,
+/// survives resize and re-encode, compare with `dhash_distance`
+pub fn dhash(image: &DynamicImage) -> String {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash = (hash << 1) | u64::from(small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0]);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
+/// `dhash` of encoded image, `None` when it cannot be decoded
+pub fn dhash_bytes(data: &[u8]) -> Option<String> {
+    image::load_from_memory(data).ok().map(|image| dhash(&image))
+}
+
+/// number of differing bits, `None` when a hash is invalid
+pub fn dhash_distance(a: &str, b: &str) -> Option<u32> {
+    let a = u64::from_str_radix(a, 16).ok()?;
+    let b = u64::from_str_radix(b, 16).ok()?;
+    Some((a ^ b).count_ones())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use image::{DynamicImage, GenericImageView, ImageFormat, RgbImage};
+    use std::io::Cursor;
+
+    use super::{dhash, dhash_bytes, dhash_distance, renditions, rgba_to_webp, upload_decode, Profile};
+
+    #[test]
+    pub fn test_renditions() {
+        let image = Profile::IMAGE.resize(&DynamicImage::new_rgb8(3000, 1500));
+        assert_eq!(image.dimensions(), (1024, 512));
+        let small = Profile::IMAGE.resize(&DynamicImage::new_rgb8(300, 150));
+        assert_eq!(small.dimensions(), (300, 150));
+        assert_eq!(Profile::THUMB.resize(&image).dimensions(), (128, 128));
+        // small image is enlarged, thumbnails of a gallery have the same size
+        assert_eq!(Profile::THUMB.resize(&DynamicImage::new_rgb8(90, 400)).dimensions(), (128, 128));
+
+        let (image, thumb) = renditions(
+            DynamicImage::new_rgb8(200, 100),
+            Profile::IMAGE.with_size(64),
+            Profile::THUMB.with_size(32),
+        ).unwrap();
+        let image = image::load_from_memory(&image).unwrap();
+        let thumb = image::load_from_memory(&thumb).unwrap();
+        assert_eq!(image.dimensions(), (64, 32));
+        assert_eq!(thumb.dimensions(), (32, 32));
+
+        let preview = Profile::PREVIEW.render(&DynamicImage::new_luma16(600, 300)).unwrap();
+        let preview = image::load_from_memory_with_format(&preview, ImageFormat::Bmp).unwrap();
+        assert_eq!(preview.dimensions(), (512, 256));
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    pub fn test_avif() {
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 16, |x, y| image::Rgb([(x * 8) as u8, (y * 16) as u8, 0])));
+        let avif = Profile::IMAGE.with_format(ImageFormat::Avif).render(&image).unwrap();
+        assert_eq!(&avif[4..12], b"ftypavif");
+        assert!(crate::heif::is_heif(&avif));
+    }
+
+    #[test]
+    pub fn test_upload_decode() {
+        let mut png = Vec::new();
+        DynamicImage::new_rgb8(4, 3).write_to(&mut Cursor::new(&mut png), ImageFormat::Png).unwrap();
+        let (image, metadata) = upload_decode(&png).unwrap();
+        assert_eq!(image.dimensions(), (4, 3));
+        assert_eq!(metadata, None);
+        assert!(upload_decode(&png[..png.len() / 2]).is_err());
+        assert!(rgba_to_webp(2, 2, vec![0; 16]).is_ok());
+        assert!(rgba_to_webp(2, 2, vec![0; 15]).is_err());
+    }
+
+    #[test]
+    pub fn test_dhash() {
+        let gradient = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 200, |x, y| {
+            let v = ((x * 255 / 300 + y * 255 / 200) / 2) as u8;
+            image::Rgb([v, v / 2, 255 - v])
+        }));
+        // smaller re-encoded copy
+        let mut png = Vec::new();
+        gradient.resize(150, 100, image::imageops::FilterType::Triangle)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let hash = dhash(&gradient);
+        assert!(dhash_distance(&hash, &dhash_bytes(&png).unwrap()).unwrap() <= 4);
+        let flipped = dhash(&gradient.fliph());
+        assert!(dhash_distance(&hash, &flipped).unwrap() > 20);
+        assert_eq!(dhash_distance(&hash, "not hex"), None);
+    }
+}
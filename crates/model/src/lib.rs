@@ -1,5 +1,6 @@
 use serde_derive::{Deserialize, Serialize};
 use std::rc::Rc;
+use time::OffsetDateTime;
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ImageData {
@@ -9,6 +10,24 @@ pub struct ImageData {
     pub path: String,
     pub title: Option<String>,
     pub user: String,
+    // order within a gallery, ascending
+    #[serde(default)]
+    pub position: u32,
+    // LEFT JOIN from annotations table
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub annotation: Option<ImageAnnotation>,
+    // from DICOM file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<ImageMetadata>,
+    // hex SHA-256 of image file, identical uploads share the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    // hex SHA-256 of thumbnail file, verified by scrubber
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb_sha256: Option<String>,
+    // hex 64-bit difference hash, re-shot or re-encoded photos differ by few bits
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhash: Option<String>,
 }
 
 impl ImageData {
@@ -19,6 +38,12 @@ impl ImageData {
             path: rc_ref.path.clone(),
             title: rc_ref.title.clone(),
             user: rc_ref.user.clone(),
+            position: rc_ref.position,
+            annotation: rc_ref.annotation.clone(),
+            metadata: rc_ref.metadata.clone(),
+            sha256: rc_ref.sha256.clone(),
+            thumb_sha256: rc_ref.thumb_sha256.clone(),
+            dhash: rc_ref.dhash.clone(),
         }
     }
 }
@@ -27,4 +52,149 @@ impl PartialEq for ImageData {
     fn eq(&self, other: &Self) -> bool {
         self.image_id == other.image_id
     }
-}
\ No newline at end of file
+}
+
+/// clinical metadata of imported DICOM image
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct ImageMetadata {
+    pub patient_name: Option<String>,
+    // yyyy-mm-dd
+    pub study_date: Option<String>,
+    // ex. CR, CT, MR, US, XC
+    pub modality: Option<String>,
+}
+
+impl ImageMetadata {
+    /// `modality patient_name study_date`, skip missing values
+    pub fn summary(&self) -> String {
+        [&self.modality, &self.patient_name, &self.study_date].into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<&str>>()
+            .join(" ")
+    }
+}
+
+/// history of image, every change of `images` row create a new version
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ImageVersion {
+    pub image_id: u32,
+    // start from 1
+    pub version: u32,
+    pub kind: VersionKind,
+    pub path: String,
+    pub title: Option<String>,
+    pub user: String,
+    #[serde(with = "time::serde::rfc3339")]
+    pub created_at: OffsetDateTime,
+    // unredacted files moved to restricted storage, privileged roles only
+    #[serde(default)]
+    pub restricted: bool,
+    // of image and thumbnail files, restored by revert and verified by scrubber
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumb_sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dhash: Option<String>,
+}
+
+/// gallery image which looks like `image_id`, a possible duplicate
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct NearDuplicate {
+    pub image_id: u32,
+    pub similar: ImageData,
+    // differing bits of perceptual hash, 0 is visually identical
+    pub distance: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VersionKind {
+    Upload,
+    Title,
+    Edit,
+    // edit with pixelated or blacked out areas
+    Redact,
+    // from version number
+    Revert(u32),
+}
+
+/// vector annotations of image, not burned into pixels
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ImageAnnotation {
+    pub image_id: u32,
+    // image path which coordinates refer to, stale when image was edited
+    pub path: String,
+    // image size in pixels, coordinate space of shapes
+    pub width: u32,
+    pub height: u32,
+    // from real length of first measurement
+    #[serde(default)]
+    pub mm_per_px: Option<f64>,
+    pub shapes: Vec<Shape>,
+}
+
+impl ImageAnnotation {
+    pub fn new(image_id: u32, path: &str, width: u32, height: u32) -> Self {
+        Self {
+            image_id,
+            path: path.to_owned(),
+            width,
+            height,
+            mm_per_px: None,
+            shapes: Vec::new(),
+        }
+    }
+
+    /// stroke width in pixels, scale with image size
+    pub fn stroke_width(&self) -> f64 {
+        self.width.max(self.height) as f64 / 200.0
+    }
+
+    /// font size in pixels, scale with image size
+    pub fn font_size(&self) -> f64 {
+        self.width.max(self.height) as f64 / 30.0
+    }
+
+    /// length text of measurement, in mm if calibrated
+    pub fn measure_text(&self, x1: f64, y1: f64, x2: f64, y2: f64) -> String {
+        let px = (x2 - x1).hypot(y2 - y1);
+        if let Some(mm_per_px) = self.mm_per_px {
+            format!("{:.1} mm", px * mm_per_px)
+        } else {
+            format!("{:.0} px", px)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct Shape {
+    // css color
+    pub color: String,
+    pub kind: ShapeKind,
+}
+
+/// coordinates are pixels of image
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ShapeKind {
+    Arrow { x1: f64, y1: f64, x2: f64, y2: f64 },
+    // ellipse in bounding box
+    Circle { cx: f64, cy: f64, rx: f64, ry: f64 },
+    Freehand { points: Vec<(f64, f64)> },
+    Measure { x1: f64, y1: f64, x2: f64, y2: f64 },
+    Text { x: f64, y: f64, text: String },
+}
+
+impl ShapeKind {
+    /// two wings of arrow head at (x2, y2)
+    pub fn arrow_head(x1: f64, y1: f64, x2: f64, y2: f64, size: f64) -> [(f64, f64); 2] {
+        let angle = (y2 - y1).atan2(x2 - x1);
+        let spread = std::f64::consts::PI / 7.0;
+        [
+            (x2 - size * (angle - spread).cos(), y2 - size * (angle - spread).sin()),
+            (x2 - size * (angle + spread).cos(), y2 - size * (angle + spread).sin()),
+        ]
+    }
+}